@@ -0,0 +1,1643 @@
+// The HTTP API independent of the server it runs on. The firmware registers
+// every entry of `ROUTES` with the esp-idf server and forwards the requests
+// to `Api::handle`.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+use serde_json::{json, Value};
+
+use crate::auth::{self, AuthError, NewToken, Scope, Token};
+use crate::calibration::{self, ServoCalibration};
+use crate::discovery;
+use crate::hal::SystemInfo;
+use crate::hooks::{self, Adapter, HookError, Hooks};
+use crate::ip::{self, IpConfig};
+use crate::jobs::{CancelError, JobId};
+use crate::link::LinkStatus;
+use crate::metrics::{self, Exposition, MetricType};
+use crate::midi;
+use crate::mqtt::{self, MqttSettings};
+use crate::patterns;
+use crate::player::PlayerHandle;
+use crate::policy::{self, LocalClock, Policy, PolicySettings, Verdict};
+use crate::rhythm::{self, Timeline};
+use crate::schedule::{self, Schedule};
+use crate::sequence::{self, StrikeSequence};
+use crate::signing::{self, NonceCache, Signed, SigningSettings};
+use crate::storage::{self, Store};
+use crate::strike::{Strike, StrikeError, DEFAULT_VELOCITY};
+use crate::tls::{self, TlsSettings};
+use crate::tz::{self, TimeSettings, TimeZone};
+use crate::wifi::{self, WifiSettings};
+
+// Raised on incompatible changes, clients find it in the mDNS TXT records
+pub const API_VERSION: u32 = 2;
+
+// Servos the firmware drives, the routes all address the single one
+pub const ACTUATORS: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+// Paths ending in `/*` match any single trailing segment
+pub const ROUTES: &[(&str, Method)] = &[
+    ("/status", Method::Get),
+    ("/metrics", Method::Get),
+    ("/servo", Method::Post),
+    ("/strike", Method::Post),
+    ("/pattern", Method::Post),
+    ("/midi", Method::Post),
+    ("/patterns", Method::Get),
+    ("/patterns/*", Method::Get),
+    ("/patterns/*", Method::Put),
+    ("/patterns/*", Method::Post),
+    ("/patterns/*", Method::Delete),
+    ("/hooks/*", Method::Post),
+    ("/schedules", Method::Get),
+    ("/schedules", Method::Post),
+    ("/schedules/*", Method::Delete),
+    ("/jobs", Method::Get),
+    ("/jobs", Method::Delete),
+    ("/jobs/*", Method::Get),
+    ("/jobs/*", Method::Delete),
+    ("/config/servo", Method::Get),
+    ("/config/servo", Method::Put),
+    ("/config/network", Method::Get),
+    ("/config/network", Method::Put),
+    ("/config/wifi", Method::Get),
+    ("/config/wifi", Method::Put),
+    ("/config/mdns", Method::Get),
+    ("/config/mdns", Method::Put),
+    ("/config/mqtt", Method::Get),
+    ("/config/mqtt", Method::Put),
+    ("/config/hooks", Method::Get),
+    ("/config/hooks", Method::Put),
+    ("/config/time", Method::Get),
+    ("/config/time", Method::Put),
+    ("/config/policy", Method::Get),
+    ("/config/policy", Method::Put),
+    ("/config/auth", Method::Get),
+    ("/config/auth", Method::Put),
+    ("/tokens", Method::Get),
+    ("/tokens", Method::Post),
+    ("/tokens/*", Method::Delete),
+    ("/config/signing", Method::Get),
+    ("/config/signing", Method::Put),
+    ("/config/tls", Method::Get),
+    ("/config/tls", Method::Put),
+];
+
+// Most routes a server takes, each one is a URI handler the esp-idf server
+// makes room for on the heap when it starts
+pub const MAX_ROUTES: usize = 64;
+
+// Request headers the routes look at. Servers pass on only these, see
+// `Request::header`.
+pub const HEADERS: &[&str] = &[
+    auth::AUTHORIZATION,
+    hooks::GITHUB_EVENT,
+    hooks::GITHUB_SIGNATURE,
+    signing::TIMESTAMP,
+    signing::NONCE,
+    signing::SIGNATURE,
+];
+
+// Largest request body of most routes, see `body_limit`
+pub const MAX_BODY: usize = 1024;
+
+// Largest request body the route at `path` accepts. Servers read the body in
+// chunks up to this limit and answer 413 beyond it.
+pub fn body_limit(path: &str) -> usize {
+    match path {
+        "/midi" => midi::MAX_FILE,
+        // A certificate and its key in PEM, escaped in JSON
+        "/config/tls" => 2 * tls::MAX_PEM_LEN + MAX_BODY,
+        _ if path.starts_with("/hooks/") => hooks::MAX_BODY,
+        _ => MAX_BODY,
+    }
+}
+
+pub struct Request<'a> {
+    pub method: Method,
+    // Path and query string as sent by the client
+    pub uri: &'a str,
+    // Names and values of the `HEADERS` that were sent
+    pub headers: &'a [(&'a str, &'a str)],
+    // Address of the sender, `None` for the gong's own requests
+    pub client: Option<IpAddr>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn path(&self) -> &'a str {
+        self.uri.split('?').next().unwrap_or_default()
+    }
+
+    // Header names are compared without regard to case
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, value)| value)
+    }
+
+    // Value of a query string parameter, without any percent-decoding
+    pub fn query(&self, name: &str) -> Option<&'a str> {
+        let (_, query) = self.uri.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    // Sent besides `Content-Type`
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: impl ToString) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn text(status: u16, content_type: &'static str, body: impl ToString) -> Self {
+        Self {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn html(status: u16, body: impl ToString) -> Self {
+        Self {
+            status,
+            content_type: "text/html; charset=utf-8",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self::json(status, json!({ "error": message.to_string() }))
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            301 => "Moved Permanently",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+pub struct Api {
+    player: Arc<PlayerHandle>,
+    calibration: Arc<Mutex<ServoCalibration>>,
+    store: Arc<dyn Store>,
+    system: Arc<dyn SystemInfo>,
+    link: Arc<LinkStatus>,
+    policy: Policy,
+    nonces: Mutex<NonceCache>,
+    counters: Counters,
+}
+
+// Counted since boot for `/metrics`
+#[derive(Default)]
+struct Counters {
+    sequences_accepted: AtomicU32,
+    sequences_rejected: AtomicU32,
+    // By route pattern and status, which keeps the number of entries bounded
+    requests: Mutex<BTreeMap<(&'static str, u16), u32>>,
+}
+
+impl Api {
+    pub fn new(
+        player: Arc<PlayerHandle>,
+        calibration: Arc<Mutex<ServoCalibration>>,
+        store: Arc<dyn Store>,
+        system: Arc<dyn SystemInfo>,
+        link: Arc<LinkStatus>,
+    ) -> Self {
+        Self {
+            player,
+            calibration,
+            store,
+            system,
+            link,
+            policy: Policy::new(),
+            nonces: Mutex::new(NonceCache::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn player(&self) -> &PlayerHandle {
+        &self.player
+    }
+
+    pub fn store(&self) -> &dyn Store {
+        &*self.store
+    }
+
+    pub fn system(&self) -> &dyn SystemInfo {
+        &*self.system
+    }
+
+    pub fn link(&self) -> &LinkStatus {
+        &self.link
+    }
+
+    pub fn handle(&self, req: &Request) -> Response {
+        // The gong's own requests, from MQTT and the schedules, need no token
+        let authorized = match req.client {
+            Some(client) => self.authorize(req).map_err(|e| {
+                warn!(
+                    "{} {} from {}: {}",
+                    req.method.as_str(),
+                    req.path(),
+                    client,
+                    e
+                );
+                Response::error(e.status(), e)
+            }),
+            None => Ok(()),
+        };
+        let response = match authorized {
+            Ok(()) => self.route(req),
+            Err(response) => response,
+        };
+        let route = route_pattern(req.path());
+        *self
+            .counters
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route, response.status))
+            .or_default() += 1;
+        // Everything that can queue a sequence, dry runs are not counted
+        if req.method == Method::Post
+            && matches!(
+                route,
+                "/servo" | "/strike" | "/pattern" | "/midi" | "/patterns/*" | "/hooks/*"
+            )
+        {
+            match response.status {
+                202 => &self.counters.sequences_accepted,
+                200 => return response,
+                _ => &self.counters.sequences_rejected,
+            }
+            .fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+
+    fn authorize(&self, req: &Request) -> Result<(), AuthError> {
+        let settings = auth::load(&*self.store);
+        let path = req.path();
+        // GitHub cannot send tokens, its signature is checked instead
+        if req.method == Method::Post && path.starts_with("/hooks/") {
+            let hooks = hooks::load(&*self.store);
+            if let Some(Adapter::Github { .. }) = hooks
+                .get(&path["/hooks/".len()..])
+                .map(|hook| &hook.adapter)
+            {
+                return Ok(());
+            }
+        }
+        let now = schedule::now();
+        let now = schedule::synced(now).then_some(now);
+        let scope = required_scope(req.method, path);
+        // Signed requests may do what a token with the strike scope may
+        if let Some(signature) = req.header(signing::SIGNATURE) {
+            let signed = Signed {
+                timestamp: req.header(signing::TIMESTAMP),
+                nonce: req.header(signing::NONCE),
+                signature: Some(signature),
+            };
+            self.nonces
+                .lock()
+                .unwrap()
+                .verify(
+                    &signing::load(&*self.store),
+                    req.method.as_str(),
+                    req.uri,
+                    &signed,
+                    req.body,
+                    now,
+                )
+                .map_err(AuthError::Signature)?;
+            return match scope {
+                None | Some(Scope::Strike) => Ok(()),
+                Some(scope) => Err(AuthError::Scope(scope)),
+            };
+        }
+        settings.authorize(req.header(auth::AUTHORIZATION), scope, now)
+    }
+
+    fn route(&self, req: &Request) -> Response {
+        let path = req.path();
+        match (req.method, path) {
+            (Method::Get, "/status") => self.get_status(),
+            (Method::Get, "/metrics") => self.get_metrics(),
+            (Method::Post, "/servo") => self.post_servo(req),
+            (Method::Post, "/strike") => self.post_strike(req),
+            (Method::Post, "/pattern") => self.post_pattern(req),
+            (Method::Post, "/midi") => self.post_midi(req),
+            (Method::Get, "/patterns") => self.list_patterns(),
+            (method, _) if path.starts_with("/patterns/") => {
+                let name = &path["/patterns/".len()..];
+                match method {
+                    Method::Get => self.get_pattern(name),
+                    Method::Put => self.put_pattern(name, req.body),
+                    Method::Post => self.play_pattern(req, name),
+                    Method::Delete => self.delete_pattern(name),
+                }
+            }
+            (Method::Post, _) if path.starts_with("/hooks/") => {
+                self.post_hook(req, &path["/hooks/".len()..])
+            }
+            (Method::Get, "/schedules") => self.list_schedules(),
+            (Method::Post, "/schedules") => self.post_schedule(req.body),
+            (Method::Delete, _) if path.starts_with("/schedules/") => {
+                match path["/schedules/".len()..].parse() {
+                    Ok(id) => self.delete_schedule(id),
+                    Err(_) => Response::error(404, "no such schedule"),
+                }
+            }
+            (Method::Get, "/jobs") => self.list_jobs(),
+            (Method::Delete, "/jobs") => self.cancel_jobs(),
+            (Method::Get, "/config/servo") => self.get_servo_config(),
+            (Method::Put, "/config/servo") => self.put_servo_config(req.body),
+            (Method::Get, "/config/network") => self.get_network_config(),
+            (Method::Put, "/config/network") => self.put_network_config(req.body),
+            (Method::Get, "/config/wifi") => self.get_wifi_config(),
+            (Method::Put, "/config/wifi") => self.put_wifi_config(req.body),
+            (Method::Get, "/config/mdns") => self.get_mdns_config(),
+            (Method::Put, "/config/mdns") => self.put_mdns_config(req.body),
+            (Method::Get, "/config/mqtt") => self.get_mqtt_config(),
+            (Method::Put, "/config/mqtt") => self.put_mqtt_config(req.body),
+            (Method::Get, "/config/time") => self.get_time_config(),
+            (Method::Put, "/config/time") => self.put_time_config(req.body),
+            (Method::Get, "/config/policy") => self.get_policy_config(),
+            (Method::Put, "/config/policy") => self.put_policy_config(req.body),
+            (Method::Get, "/config/auth") => self.get_auth_config(),
+            (Method::Put, "/config/auth") => self.put_auth_config(req.body),
+            (Method::Get, "/config/signing") => self.get_signing_config(),
+            (Method::Put, "/config/signing") => self.put_signing_config(req.body),
+            (Method::Get, "/config/tls") => self.get_tls_config(),
+            (Method::Put, "/config/tls") => self.put_tls_config(req.body),
+            (Method::Get, "/tokens") => self.list_tokens(),
+            (Method::Post, "/tokens") => self.post_token(req.body),
+            (Method::Delete, _) if path.starts_with("/tokens/") => {
+                match path["/tokens/".len()..].parse() {
+                    Ok(id) => self.delete_token(id),
+                    Err(_) => Response::error(404, "no such token"),
+                }
+            }
+            (Method::Get, "/config/hooks") => self.get_hooks_config(),
+            (Method::Put, "/config/hooks") => self.put_hooks_config(req.body),
+            (method, _) if path.starts_with("/jobs/") => match job_id(path) {
+                None => Response::error(404, "no such job"),
+                Some(id) => match method {
+                    Method::Get => self.get_job(id),
+                    Method::Delete => self.cancel_job(id),
+                    _ => Response::error(405, "method not allowed"),
+                },
+            },
+            _ => Response::error(404, "no such route"),
+        }
+    }
+
+    fn post_servo(&self, req: &Request) -> Response {
+        // Parse the request of the form ({angle},{pause},)*{angle}
+        let sequence = match sequence::parse_bytes(req.body) {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                warn!("Rejected sequence: {}", e);
+                return Response::json(400, e.to_json());
+            }
+        };
+
+        self.submit(req, sequence)
+    }
+
+    // `POST /strike?velocity=0.7&damping_hold=200`, the hold is in milliseconds
+    fn post_strike(&self, req: &Request) -> Response {
+        let velocity = match req.query("velocity").map(str::parse::<f32>) {
+            None => DEFAULT_VELOCITY,
+            Some(Ok(velocity)) => velocity,
+            Some(Err(_)) => return Response::error(400, StrikeError::Velocity),
+        };
+        let damping_hold = match req.query("damping_hold").map(str::parse::<u64>) {
+            None => 0,
+            Some(Ok(hold)) => hold,
+            Some(Err(_)) => return Response::error(400, StrikeError::DampingHold),
+        };
+        let strike = match Strike::new(velocity, Duration::from_millis(damping_hold)) {
+            Ok(strike) => strike,
+            Err(e) => return Response::error(400, e),
+        };
+
+        let sequence = strike.compile(&self.calibration.lock().unwrap());
+        info!("Strike with velocity {} compiled to {}", velocity, sequence);
+        self.submit(req, sequence)
+    }
+
+    // `POST /pattern` with the rhythm notation as the body. With `?dry_run=1`
+    // the compiled timeline is returned instead of queued.
+    fn post_pattern(&self, req: &Request) -> Response {
+        let source = match std::str::from_utf8(req.body) {
+            Ok(source) => source,
+            Err(_) => return Response::error(400, "the pattern is not valid UTF-8"),
+        };
+        match rhythm::parse(source) {
+            Ok(timeline) => self.play_timeline(req, timeline),
+            Err(e) => {
+                warn!("Rejected pattern: {}", e);
+                Response::json(400, e.to_json())
+            }
+        }
+    }
+
+    // `POST /midi?channel=10&note=38&damping_hold=0` with a Standard MIDI File
+    // as the body. The channel goes from 1 to 16, without a channel or note
+    // all notes are played. Takes `dry_run` like `/pattern`.
+    fn post_midi(&self, req: &Request) -> Response {
+        let channel = match req.query("channel").map(str::parse::<u8>) {
+            None => None,
+            Some(Ok(channel @ 1..=16)) => Some(channel - 1),
+            Some(_) => return Response::error(400, "channel must be a number from 1 to 16"),
+        };
+        let note = match req.query("note").map(str::parse::<u8>) {
+            None => None,
+            Some(Ok(note @ 0..=127)) => Some(note),
+            Some(_) => return Response::error(400, "note must be a number from 0 to 127"),
+        };
+        let damping_hold = match req.query("damping_hold").map(str::parse::<u64>) {
+            None => 0,
+            Some(Ok(hold)) if hold <= sequence::MAX_PAUSE_MS as u64 => hold,
+            Some(_) => return Response::error(400, StrikeError::DampingHold),
+        };
+
+        let filter = midi::Filter { channel, note };
+        match midi::parse(req.body).and_then(|song| song.timeline(&filter, damping_hold)) {
+            Ok(timeline) => self.play_timeline(req, timeline),
+            Err(e) => {
+                warn!("Rejected MIDI file: {}", e);
+                Response::error(400, e)
+            }
+        }
+    }
+
+    fn list_patterns(&self) -> Response {
+        Response::json(200, json!({ "patterns": patterns::load(&*self.store) }))
+    }
+
+    fn get_pattern(&self, name: &str) -> Response {
+        match patterns::load(&*self.store).remove(name) {
+            Some(source) => Response::json(200, json!({ "name": name, "pattern": source })),
+            None => Response::error(404, "no such pattern"),
+        }
+    }
+
+    // `PUT /patterns/{name}` with the rhythm notation as the body
+    fn put_pattern(&self, name: &str, body: &[u8]) -> Response {
+        let source = match std::str::from_utf8(body) {
+            Ok(source) => source,
+            Err(_) => return Response::error(400, "the pattern is not valid UTF-8"),
+        };
+        let mut saved = patterns::load(&*self.store);
+        if let Err(e) = patterns::insert(&mut saved, name, source) {
+            return Response::error(400, e);
+        }
+        if let Err(e) = patterns::save(&*self.store, &saved) {
+            error!("Could not save the patterns: {:?}", e);
+            return Response::error(500, "could not save the patterns");
+        }
+        info!("Pattern {} saved", name);
+        Response::json(200, json!({ "name": name, "pattern": source }))
+    }
+
+    fn delete_pattern(&self, name: &str) -> Response {
+        let mut saved = patterns::load(&*self.store);
+        if saved.remove(name).is_none() {
+            return Response::error(404, "no such pattern");
+        }
+        if let Err(e) = patterns::save(&*self.store, &saved) {
+            error!("Could not save the patterns: {:?}", e);
+            return Response::error(500, "could not save the patterns");
+        }
+        info!("Pattern {} deleted", name);
+        Response::json(200, json!({ "name": name }))
+    }
+
+    // `POST /patterns/{name}` plays a saved pattern, with `dry_run` like
+    // `/pattern`
+    fn play_pattern(&self, req: &Request, name: &str) -> Response {
+        let source = match patterns::load(&*self.store).remove(name) {
+            Some(source) => source,
+            None => return Response::error(404, "no such pattern"),
+        };
+        match rhythm::parse(&source) {
+            Ok(timeline) => self.play_timeline(req, timeline),
+            Err(e) => Response::json(400, e.to_json()),
+        }
+    }
+
+    // `POST /hooks/{name}` plays the pattern the hook's rules pick for the
+    // payload. Deliveries no rule matches are answered 200 so senders do
+    // not retry them.
+    fn post_hook(&self, req: &Request, name: &str) -> Response {
+        let hooks = hooks::load(&*self.store);
+        let hook = match hooks.get(name) {
+            Some(hook) => hook,
+            None => return Response::error(404, "no such hook"),
+        };
+        match hook.select(|header| req.header(header), req.body) {
+            Ok(Some(pattern)) => {
+                info!("Hook {} plays {}", name, pattern);
+                self.play_pattern(req, pattern)
+            }
+            Ok(None) => Response::json(200, json!({ "pattern": null })),
+            Err(e @ HookError::Signature) => {
+                warn!("Hook {}: {}", name, e);
+                Response::error(401, e)
+            }
+            Err(e) => {
+                warn!("Hook {}: {}", name, e);
+                Response::error(400, e)
+            }
+        }
+    }
+
+    // The schedules with the local time each fires next, once the clock is
+    // synchronised
+    fn list_schedules(&self) -> Response {
+        let now = schedule::now();
+        let zone = self.time_zone();
+        let schedules: Vec<Value> = schedule::load(&*self.store)
+            .iter()
+            .map(|schedule| {
+                let next = schedule
+                    .validate()
+                    .ok()
+                    .filter(|_| schedule::synced(now))
+                    .and_then(|cron| cron.next_after(now, &zone))
+                    .map(|at| tz::format(zone.to_local(at)));
+                json!({
+                    "id": schedule.id,
+                    "cron": schedule.cron,
+                    "pattern": schedule.pattern,
+                    "next": next,
+                })
+            })
+            .collect();
+        Response::json(200, json!({ "schedules": schedules }))
+    }
+
+    // `POST /schedules` with `{"cron": "0 17 * * fri", "pattern": "triple"}`
+    fn post_schedule(&self, body: &[u8]) -> Response {
+        let new: Schedule = match serde_json::from_slice(body) {
+            Ok(schedule) => schedule,
+            Err(e) => return Response::error(400, e),
+        };
+        let mut schedules = schedule::load(&*self.store);
+        let id = match schedule::add(&mut schedules, new) {
+            Ok(id) => id,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = schedule::save(&*self.store, &schedules) {
+            error!("Could not save the schedules: {:?}", e);
+            return Response::error(500, "could not save the schedules");
+        }
+        let added = schedules.last().unwrap();
+        info!(
+            "Schedule {} added: {} plays {}",
+            id, added.cron, added.pattern
+        );
+        Response::json(201, json!(added))
+    }
+
+    fn delete_schedule(&self, id: u32) -> Response {
+        let mut schedules = schedule::load(&*self.store);
+        let count = schedules.len();
+        schedules.retain(|schedule| schedule.id != id);
+        if schedules.len() == count {
+            return Response::error(404, "no such schedule");
+        }
+        if let Err(e) = schedule::save(&*self.store, &schedules) {
+            error!("Could not save the schedules: {:?}", e);
+            return Response::error(500, "could not save the schedules");
+        }
+        info!("Schedule {} deleted", id);
+        Response::json(200, json!({ "id": id }))
+    }
+
+    // Queues the compiled timeline, or with `?dry_run=1` returns it instead
+    fn play_timeline(&self, req: &Request, timeline: Timeline) -> Response {
+        let sequence = match timeline.compile(&self.calibration.lock().unwrap()) {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                warn!("Rejected timeline: {}", e);
+                return Response::json(400, e.to_json());
+            }
+        };
+
+        if matches!(req.query("dry_run"), Some("" | "1" | "true")) {
+            return Response::json(
+                200,
+                json!({
+                    "hits": timeline.hits,
+                    "length_ms": timeline.length_ms,
+                    "duration_ms": sequence.duration().as_millis() as u64,
+                    "steps": sequence.to_string(),
+                }),
+            );
+        }
+        info!(
+            "Timeline with {} strikes compiled to {}",
+            timeline.hits.len(),
+            sequence
+        );
+        self.submit(req, sequence)
+    }
+
+    // Queues the sequence if the policy allows it
+    fn submit(&self, req: &Request, sequence: StrikeSequence) -> Response {
+        let settings = policy::load(&*self.store);
+        let (sequence, charge) =
+            match self
+                .policy
+                .check(&settings, req.client, sequence, self.clock())
+            {
+                Ok(Verdict::Play(sequence, charge)) => (sequence, charge),
+                Ok(Verdict::Deferred(until)) => {
+                    let until = tz::format(self.time_zone().to_local(until));
+                    info!("Quiet hours, sequence deferred until {}", until);
+                    return Response::json(202, json!({ "job": null, "deferred_until": until }));
+                }
+                Err(denied) => {
+                    warn!("Sequence denied: {}", denied.reason);
+                    let response = Response::json(denied.status, denied.to_json());
+                    return match denied.retry_after_s() {
+                        Some(seconds) => response.with_header("Retry-After", seconds),
+                        None => response,
+                    };
+                }
+            };
+        let response = self.enqueue(sequence);
+        if response.status != 202 {
+            self.policy.refund(charge);
+        }
+        response
+    }
+
+    // Queues the sequences held back by the quiet hours once they are over.
+    // Called every minute by the schedules, which try again with the ones
+    // the player has no room for.
+    pub fn release_deferred(&self) {
+        let settings = policy::load(&*self.store);
+        let mut released = self.policy.release(&settings, self.clock()).into_iter();
+        while let Some(sequence) = released.next() {
+            match self.player.submit(sequence.clone()) {
+                Ok(id) => info!("Queued deferred job {}", id),
+                Err(e) => {
+                    warn!(
+                        "Could not queue a deferred sequence, trying again in a minute: {}",
+                        e
+                    );
+                    self.policy
+                        .requeue(std::iter::once(sequence).chain(released).collect());
+                    break;
+                }
+            }
+        }
+    }
+
+    fn time_zone(&self) -> TimeZone {
+        tz::load(&*self.store).validate().unwrap_or(TimeZone::UTC)
+    }
+
+    // The local time for the quiet hours, once the clock is synchronised
+    fn clock(&self) -> Option<LocalClock> {
+        let now = schedule::now();
+        schedule::synced(now).then(|| LocalClock {
+            utc: now,
+            zone: self.time_zone(),
+        })
+    }
+
+    fn enqueue(&self, sequence: StrikeSequence) -> Response {
+        match self.player.submit(sequence) {
+            Ok(id) => {
+                info!("Queued job {}", id);
+                Response::json(202, json!({ "job": id }))
+            }
+            Err(e) => {
+                warn!("Could not queue sequence: {}", e);
+                Response::error(503, e)
+            }
+        }
+    }
+
+    fn get_status(&self) -> Response {
+        let heap = self.system.heap();
+        let connection = self.link.connection();
+        let jobs = self.player.jobs();
+        let running = jobs.running();
+        let stats = self.player.stats();
+        Response::json(
+            200,
+            json!({
+                "firmware_version": env!("CARGO_PKG_VERSION"),
+                "api_version": API_VERSION,
+                "uptime_s": self.system.uptime().as_secs(),
+                "free_heap": heap.map(|heap| heap.free_bytes),
+                "min_free_heap": heap.map(|heap| heap.min_free_bytes),
+                "reset_reason": self.system.reset_reason(),
+                "wifi": {
+                    "state": self.link.get(),
+                    "ssid": connection.as_ref().map(|c| &c.ssid),
+                    "ip": connection.as_ref().map(|c| c.ip.to_string()),
+                    "rssi": self.system.rssi(),
+                },
+                "servo_angle": stats.angle(),
+                "player": {
+                    "state": if running.is_some() { "playing" } else { "idle" },
+                    "job": running,
+                    "queue_depth": jobs.queued(),
+                },
+                "total_strikes": stats.strikes(),
+            }),
+        )
+    }
+
+    fn get_metrics(&self) -> Response {
+        use MetricType::{Counter, Gauge};
+
+        let stats = self.player.stats();
+        let mut out = Exposition::new();
+        out.single(
+            "gong_strikes_total",
+            Counter,
+            "Strikes since boot",
+            stats.strikes() as f64,
+        )
+        .single(
+            "gong_sequences_accepted_total",
+            Counter,
+            "Sequences queued for the player",
+            self.counters.sequences_accepted.load(Ordering::Relaxed) as f64,
+        )
+        .single(
+            "gong_sequences_rejected_total",
+            Counter,
+            "Sequences refused as invalid or because the queue was full",
+            self.counters.sequences_rejected.load(Ordering::Relaxed) as f64,
+        )
+        .family(
+            "gong_http_requests_total",
+            Counter,
+            "HTTP requests by route and status",
+        );
+        for (&(route, status), &count) in self.counters.requests.lock().unwrap().iter() {
+            out.sample(
+                "gong_http_requests_total",
+                &[("route", route), ("status", &status.to_string())],
+                count as f64,
+            );
+        }
+        out.single(
+            "gong_wifi_reconnects_total",
+            Counter,
+            "Times the WiFi connection was lost and established again",
+            self.link.reconnects() as f64,
+        )
+        .single(
+            "gong_uptime_seconds",
+            Gauge,
+            "Time since boot",
+            self.system.uptime().as_secs_f64(),
+        )
+        .single(
+            "gong_queue_depth",
+            Gauge,
+            "Jobs waiting for the player",
+            self.player.jobs().queued() as f64,
+        );
+        if let Some(heap) = self.system.heap() {
+            out.single(
+                "gong_heap_free_bytes",
+                Gauge,
+                "Free heap",
+                heap.free_bytes as f64,
+            )
+            .single(
+                "gong_heap_min_free_bytes",
+                Gauge,
+                "Lowest free heap since boot",
+                heap.min_free_bytes as f64,
+            );
+        }
+        if let Some(rssi) = self.system.rssi() {
+            out.single(
+                "gong_wifi_rssi_dbm",
+                Gauge,
+                "Signal strength of the access point",
+                rssi as f64,
+            );
+        }
+        Response::text(200, metrics::CONTENT_TYPE, out.finish())
+    }
+
+    fn list_jobs(&self) -> Response {
+        Response::json(200, json!(self.player.jobs().list()))
+    }
+
+    fn cancel_jobs(&self) -> Response {
+        let cancelled = self.player.jobs().cancel_all();
+        info!("Cancelled jobs {:?}", cancelled);
+        Response::json(200, json!({ "cancelled": cancelled }))
+    }
+
+    fn get_job(&self, id: JobId) -> Response {
+        match self.player.jobs().get(id) {
+            Some(job) => Response::json(200, json!(job)),
+            None => Response::error(404, "no such job"),
+        }
+    }
+
+    fn cancel_job(&self, id: JobId) -> Response {
+        match self.player.jobs().cancel(id) {
+            Ok(job) => {
+                info!("Cancelled job {}", job.id);
+                Response::json(200, json!(job))
+            }
+            Err(e @ CancelError::NotFound) => Response::error(404, e),
+            Err(e @ CancelError::AlreadyDone(state)) => {
+                Response::json(409, json!({ "error": e.to_string(), "state": state }))
+            }
+        }
+    }
+
+    fn get_servo_config(&self) -> Response {
+        Response::json(200, json!(*self.calibration.lock().unwrap()))
+    }
+
+    // Fields missing from the body keep their current value
+    fn put_servo_config(&self, body: &[u8]) -> Response {
+        let current = self.calibration.lock().unwrap().clone();
+        let calibration = match merge(&current, body) {
+            Ok(calibration) => calibration,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = calibration.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, calibration::STORE_KEY, &calibration) {
+            error!("Could not save servo calibration: {:?}", e);
+            return Response::error(500, "could not save the calibration");
+        }
+        info!("Servo calibration changed to {:?}", calibration);
+        *self.calibration.lock().unwrap() = calibration.clone();
+        Response::json(200, json!(calibration))
+    }
+
+    fn get_network_config(&self) -> Response {
+        Response::json(200, json!(ip::load(&*self.store).unwrap_or_default()))
+    }
+
+    // The whole configuration is replaced, as the fields depend on the mode.
+    // It takes effect after the next restart.
+    fn put_network_config(&self, body: &[u8]) -> Response {
+        let config: IpConfig = match serde_json::from_slice(body) {
+            Ok(config) => config,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = config.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, ip::STORE_KEY, &config) {
+            error!("Could not save the IP configuration: {:?}", e);
+            return Response::error(500, "could not save the IP configuration");
+        }
+        info!("IP configuration changed to {}", config);
+        Response::json(200, json!(config))
+    }
+
+    fn get_wifi_config(&self) -> Response {
+        Response::json(200, wifi_json(&wifi::load(&*self.store)))
+    }
+
+    // Replaces the list of known networks. Entries without a password keep
+    // the saved password of the network with the same SSID, so a list read
+    // with GET can be sent back changed. Used from the next reconnect on.
+    fn put_wifi_config(&self, body: &[u8]) -> Response {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Body {
+            networks: Vec<Value>,
+        }
+
+        let saved = wifi::load(&*self.store);
+        let parsed = serde_json::from_slice(body).and_then(|body: Body| {
+            body.networks
+                .into_iter()
+                .map(|mut network| {
+                    if let Some(fields) = network.as_object_mut() {
+                        fields.remove("password_set");
+                        let known = fields
+                            .get("ssid")
+                            .and_then(|ssid| saved.iter().find(|n| n.ssid == *ssid));
+                        if let Some(known) = known {
+                            fields
+                                .entry("password")
+                                .or_insert_with(|| json!(known.password));
+                        }
+                    }
+                    serde_json::from_value(network)
+                })
+                .collect::<Result<Vec<WifiSettings>, _>>()
+        });
+        let networks = match parsed {
+            Ok(networks) => networks,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = wifi::validate(&networks) {
+            return Response::error(400, e);
+        }
+        if let Err(e) = wifi::save(&*self.store, &networks) {
+            error!("Could not save the WiFi networks: {:?}", e);
+            return Response::error(500, "could not save the WiFi networks");
+        }
+        info!("{} WiFi networks saved", networks.len());
+        Response::json(200, wifi_json(&networks))
+    }
+
+    fn get_mdns_config(&self) -> Response {
+        Response::json(200, json!(discovery::load(&*self.store)))
+    }
+
+    // Takes effect after the next restart
+    fn put_mdns_config(&self, body: &[u8]) -> Response {
+        let settings = match merge(&discovery::load(&*self.store), body) {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, discovery::STORE_KEY, &settings) {
+            error!("Could not save the mDNS settings: {:?}", e);
+            return Response::error(500, "could not save the mDNS settings");
+        }
+        info!("mDNS hostname changed to {}", settings.hostname);
+        Response::json(200, json!(settings))
+    }
+
+    // The settings with the current time, `synced` tells whether SNTP has
+    // set the clock yet
+    fn get_time_config(&self) -> Response {
+        Response::json(200, time_json(&tz::load(&*self.store)))
+    }
+
+    // The time zone is used straight away, the NTP server after the next
+    // restart
+    fn put_time_config(&self, body: &[u8]) -> Response {
+        let settings = match merge(&tz::load(&*self.store), body) {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, tz::STORE_KEY, &settings) {
+            error!("Could not save the time settings: {:?}", e);
+            return Response::error(500, "could not save the time settings");
+        }
+        info!("Time zone changed to {}", settings.timezone);
+        Response::json(200, time_json(&settings))
+    }
+
+    // The limits with the number of sequences waiting for the end of the
+    // quiet hours
+    fn get_policy_config(&self) -> Response {
+        Response::json(200, self.policy_json(&policy::load(&*self.store)))
+    }
+
+    // Fields left out keep their value, `null` switches a limit off. Applies
+    // to the next sequence.
+    fn put_policy_config(&self, body: &[u8]) -> Response {
+        let settings: PolicySettings = match merge(&policy::load(&*self.store), body) {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, policy::STORE_KEY, &settings) {
+            error!("Could not save the policy: {:?}", e);
+            return Response::error(500, "could not save the policy");
+        }
+        info!("Policy changed: {:?}", settings);
+        Response::json(200, self.policy_json(&settings))
+    }
+
+    fn policy_json(&self, settings: &PolicySettings) -> Value {
+        let mut value = json!(settings);
+        if let Some(fields) = value.as_object_mut() {
+            fields.insert("deferred".into(), json!(self.policy.deferred()));
+        }
+        value
+    }
+
+    fn get_auth_config(&self) -> Response {
+        let settings = auth::load(&*self.store);
+        Response::json(200, json!({ "anonymous": settings.anonymous }))
+    }
+
+    // `{"anonymous": true}` lets requests without a token do everything but
+    // administration
+    fn put_auth_config(&self, body: &[u8]) -> Response {
+        let mut settings = auth::load(&*self.store);
+        let update: Value = match serde_json::from_slice(body) {
+            Ok(update) => update,
+            Err(e) => return Response::error(400, e),
+        };
+        match update.get("anonymous").map(Value::as_bool) {
+            Some(Some(anonymous)) => settings.anonymous = anonymous,
+            Some(None) => return Response::error(400, "anonymous must be true or false"),
+            None => {}
+        }
+        if let Err(e) = auth::save(&*self.store, &settings) {
+            error!("Could not save the tokens: {:?}", e);
+            return Response::error(500, "could not save the tokens");
+        }
+        info!(
+            "Anonymous access {}",
+            if settings.anonymous { "on" } else { "off" }
+        );
+        Response::json(200, json!({ "anonymous": settings.anonymous }))
+    }
+
+    fn get_signing_config(&self) -> Response {
+        Response::json(200, signing_json(&signing::load(&*self.store)))
+    }
+
+    // The secret is kept when left out and an empty one switches signed
+    // requests off
+    fn put_signing_config(&self, body: &[u8]) -> Response {
+        let settings = serde_json::from_slice(body).and_then(|mut update: Value| {
+            if let Some(fields) = update.as_object_mut() {
+                fields.remove("secret_set");
+            }
+            merge(&signing::load(&*self.store), update.to_string().as_bytes())
+        });
+        let settings: SigningSettings = match settings {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, signing::STORE_KEY, &settings) {
+            error!("Could not save the signing settings: {:?}", e);
+            return Response::error(500, "could not save the signing settings");
+        }
+        if settings.secret.is_empty() {
+            info!("Signed requests disabled");
+        } else {
+            info!("Signed requests enabled");
+        }
+        Response::json(200, signing_json(&settings))
+    }
+
+    fn get_tls_config(&self) -> Response {
+        Response::json(200, tls_json(&tls::load(&*self.store)))
+    }
+
+    // The private key is kept when left out. Without a certificate and key a
+    // self-signed pair is generated, the settings apply after a restart.
+    fn put_tls_config(&self, body: &[u8]) -> Response {
+        let settings = serde_json::from_slice(body).and_then(|mut update: Value| {
+            if let Some(fields) = update.as_object_mut() {
+                fields.remove("private_key_set");
+            }
+            merge(&tls::load(&*self.store), update.to_string().as_bytes())
+        });
+        let mut settings: TlsSettings = match settings {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        // Clearing the certificate asks for a new self-signed one, which
+        // needs a new key too
+        if settings.certificate.is_empty() {
+            settings.private_key.clear();
+        }
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = tls::save(&*self.store, &settings) {
+            error!("Could not save the TLS settings: {:?}", e);
+            return Response::error(500, "could not save the TLS settings");
+        }
+        info!(
+            "HTTPS {} after a restart",
+            if settings.enabled { "on" } else { "off" }
+        );
+        Response::json(200, tls_json(&settings))
+    }
+
+    fn list_tokens(&self) -> Response {
+        let tokens: Vec<Value> = auth::load(&*self.store)
+            .tokens
+            .iter()
+            .map(token_json)
+            .collect();
+        Response::json(200, json!({ "tokens": tokens }))
+    }
+
+    // `POST /tokens` with `{"name": "ci", "scopes": ["strike"],
+    // "expires_in_s": 86400}`. The answer holds the only copy of the token.
+    fn post_token(&self, body: &[u8]) -> Response {
+        let new: NewToken = match serde_json::from_slice(body) {
+            Ok(new) => new,
+            Err(e) => return Response::error(400, e),
+        };
+        let mut settings = auth::load(&*self.store);
+        let now = schedule::now();
+        let (token, secret) =
+            match settings.create(new, &*self.system, schedule::synced(now).then_some(now)) {
+                Ok(created) => created,
+                Err(e) => return Response::error(400, e),
+            };
+        if let Err(e) = auth::save(&*self.store, &settings) {
+            error!("Could not save the tokens: {:?}", e);
+            return Response::error(500, "could not save the tokens");
+        }
+        info!(
+            "Token {} ({}) created with {:?}",
+            token.id, token.name, token.scopes
+        );
+        let mut value = token_json(&token);
+        if let Some(fields) = value.as_object_mut() {
+            fields.insert("token".into(), json!(secret));
+        }
+        Response::json(201, value)
+    }
+
+    fn delete_token(&self, id: u32) -> Response {
+        let mut settings = auth::load(&*self.store);
+        if !settings.revoke(id) {
+            return Response::error(404, "no such token");
+        }
+        if let Err(e) = auth::save(&*self.store, &settings) {
+            error!("Could not save the tokens: {:?}", e);
+            return Response::error(500, "could not save the tokens");
+        }
+        info!("Token {} revoked", id);
+        Response::json(200, json!({ "id": id }))
+    }
+
+    fn get_hooks_config(&self) -> Response {
+        Response::json(200, hooks_json(&hooks::load(&*self.store)))
+    }
+
+    // Replaces all hooks. GitHub hooks sent without a secret keep the saved
+    // secret of the hook with the same name, like the WiFi passwords.
+    fn put_hooks_config(&self, body: &[u8]) -> Response {
+        let saved = hooks::load(&*self.store);
+        let parsed = serde_json::from_slice(body).and_then(|hooks: BTreeMap<String, Value>| {
+            hooks
+                .into_iter()
+                .map(|(name, mut hook)| {
+                    if let Some(fields) = hook.as_object_mut() {
+                        fields.remove("secret_set");
+                        if let Some(Adapter::Github { secret }) =
+                            saved.get(&name).map(|saved| &saved.adapter)
+                        {
+                            fields.entry("secret").or_insert_with(|| json!(secret));
+                        }
+                    }
+                    Ok((name, serde_json::from_value(hook)?))
+                })
+                .collect::<Result<Hooks, _>>()
+        });
+        let hooks = match parsed {
+            Ok(hooks) => hooks,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = hooks::validate(&hooks) {
+            return Response::error(400, e);
+        }
+        if let Err(e) = hooks::save(&*self.store, &hooks) {
+            error!("Could not save the hooks: {:?}", e);
+            return Response::error(500, "could not save the hooks");
+        }
+        info!("{} hooks saved", hooks.len());
+        Response::json(200, hooks_json(&hooks))
+    }
+
+    fn get_mqtt_config(&self) -> Response {
+        Response::json(200, mqtt_json(&mqtt::load(&*self.store)))
+    }
+
+    // Fields left out keep their saved values, the password too. Takes
+    // effect after the next restart.
+    fn put_mqtt_config(&self, body: &[u8]) -> Response {
+        let settings = serde_json::from_slice(body).and_then(|mut update: Value| {
+            if let Some(fields) = update.as_object_mut() {
+                fields.remove("password_set");
+            }
+            merge(&mqtt::load(&*self.store), update.to_string().as_bytes())
+        });
+        let settings = match settings {
+            Ok(settings) => settings,
+            Err(e) => return Response::error(400, e),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::error(400, e);
+        }
+        if let Err(e) = storage::save(&*self.store, mqtt::STORE_KEY, &settings) {
+            error!("Could not save the MQTT settings: {:?}", e);
+            return Response::error(500, "could not save the MQTT settings");
+        }
+        if settings.enabled {
+            info!("MQTT enabled with broker {}", settings.url);
+        } else {
+            info!("MQTT disabled");
+        }
+        Response::json(200, mqtt_json(&settings))
+    }
+}
+
+// The known networks without their passwords
+fn wifi_json(networks: &[WifiSettings]) -> Value {
+    let networks: Vec<Value> = networks
+        .iter()
+        .map(|network| {
+            json!({
+                "ssid": network.ssid,
+                "password_set": !network.password.is_empty(),
+                "auth_method": network.auth_method,
+                "priority": network.priority,
+            })
+        })
+        .collect();
+    json!({ "networks": networks })
+}
+
+fn time_json(settings: &TimeSettings) -> Value {
+    let now = schedule::now();
+    let zone = settings.validate().unwrap_or(TimeZone::UTC);
+    let mut value = json!(settings);
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("synced".into(), json!(schedule::synced(now)));
+        fields.insert("local_time".into(), json!(tz::format(zone.to_local(now))));
+    }
+    value
+}
+
+// The hooks without the GitHub secrets
+fn hooks_json(hooks: &Hooks) -> Value {
+    let mut value = json!(hooks);
+    if let Some(hooks) = value.as_object_mut() {
+        for hook in hooks.values_mut().filter_map(Value::as_object_mut) {
+            if let Some(secret) = hook.remove("secret") {
+                hook.insert("secret_set".into(), json!(secret != ""));
+            }
+        }
+    }
+    value
+}
+
+// The MQTT settings without the password
+fn mqtt_json(settings: &MqttSettings) -> Value {
+    let mut value = json!(settings);
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("password");
+        fields.insert("password_set".into(), json!(!settings.password.is_empty()));
+    }
+    value
+}
+
+// Applies the fields of a JSON object onto a settings record, nested objects
+// are merged the same way
+fn merge<T>(current: &T, body: &[u8]) -> Result<T, serde_json::Error>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn merge_value(value: &mut Value, update: Value) {
+        match (value, update) {
+            (Value::Object(fields), Value::Object(update)) => {
+                for (key, new) in update {
+                    match fields.get_mut(&key) {
+                        Some(old) => merge_value(old, new),
+                        None => {
+                            fields.insert(key, new);
+                        }
+                    }
+                }
+            }
+            (value, update) => *value = update,
+        }
+    }
+
+    let mut value = serde_json::to_value(current)?;
+    let update: serde_json::Map<String, Value> = serde_json::from_slice(body)?;
+    merge_value(&mut value, Value::Object(update));
+    serde_json::from_value(value)
+}
+
+fn signing_json(settings: &SigningSettings) -> Value {
+    json!({
+        "secret_set": !settings.secret.is_empty(),
+        "max_skew_s": settings.max_skew_s,
+    })
+}
+
+fn tls_json(settings: &TlsSettings) -> Value {
+    json!({
+        "enabled": settings.enabled,
+        "redirect": settings.redirect,
+        "certificate": settings.certificate,
+        "private_key_set": !settings.private_key.is_empty(),
+    })
+}
+
+// A token without its hash, with the expiry as a UTC time
+fn token_json(token: &Token) -> Value {
+    json!({
+        "id": token.id,
+        "name": token.name,
+        "scopes": token.scopes,
+        "expires": token.expires.map(tz::format),
+    })
+}
+
+// The scope a route needs, `None` for reading what any token may see
+fn required_scope(method: Method, path: &str) -> Option<Scope> {
+    let route = route_pattern(path);
+    match (method, route) {
+        (_, "/tokens" | "/tokens/*" | "/config/auth" | "/config/signing" | "/config/tls") => {
+            Some(Scope::Admin)
+        }
+        (_, _) if route.starts_with("/config/") => Some(Scope::Configure),
+        (Method::Get, _) => None,
+        (
+            Method::Post,
+            "/servo" | "/strike" | "/pattern" | "/midi" | "/patterns/*" | "/hooks/*",
+        )
+        | (Method::Delete, "/jobs" | "/jobs/*") => Some(Scope::Strike),
+        _ => Some(Scope::Configure),
+    }
+}
+
+// The entry of `ROUTES` matching the path, for grouping requests without
+// one entry per job id
+fn route_pattern(path: &str) -> &'static str {
+    ROUTES
+        .iter()
+        .map(|&(route, _)| route)
+        .find(|route| match route.strip_suffix('*') {
+            Some(prefix) => path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| !rest.is_empty() && !rest.contains('/')),
+            None => *route == path,
+        })
+        .unwrap_or("other")
+}
+
+// Extracts the id from `/jobs/{id}`
+fn job_id(path: &str) -> Option<JobId> {
+    path.strip_prefix("/jobs/")?.parse().ok()
+}
+
+// The whole way from an HTTP request to the duties the servo is driven with,
+// on the simulated hardware
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::hal::ServoMotor;
+    use crate::jobs::{JobRecord, JobState};
+    use crate::link::LinkState;
+    use crate::player::{self, Clock, Player, QUEUE_CAPACITY};
+    use crate::sim::{ManualClock, MemoryStore, Recording, SimActuator, SimSystem, MAX_DUTY};
+
+    const TOKEN: &str = "0123456789abcdef0123";
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    struct Gong {
+        api: Api,
+        clock: ManualClock,
+        duties: Recording<u32>,
+        jobs: Receiver<JobRecord>,
+    }
+
+    impl Gong {
+        fn new() -> Self {
+            let clock = ManualClock::new();
+            let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+            auth::provision_admin(&*store, TOKEN).unwrap();
+            let calibration = Arc::new(Mutex::new(ServoCalibration::default()));
+            let actuator = SimActuator::new(clock.clone());
+            let duties = actuator.duties();
+            let servo = ServoMotor::new(actuator, calibration.clone());
+            let player = player::spawn(Player::new(servo, clock.clone()), QUEUE_CAPACITY).unwrap();
+            let jobs = player.jobs().watch();
+            let api = Api::new(
+                Arc::new(player),
+                calibration,
+                store,
+                Arc::new(SimSystem::new(clock.clone())),
+                Arc::new(LinkStatus::new(LinkState::Up)),
+            );
+            Self {
+                api,
+                clock,
+                duties,
+                jobs,
+            }
+        }
+
+        fn request(&self, method: Method, uri: &str, token: Option<&str>, body: &str) -> Response {
+            let authorization = token.map(|token| format!("Bearer {}", token));
+            let headers: Vec<(&str, &str)> = authorization
+                .iter()
+                .map(|value| ("Authorization", value.as_str()))
+                .collect();
+            self.api.handle(&Request {
+                method,
+                uri,
+                headers: &headers,
+                client: CLIENT,
+                body: body.as_bytes(),
+            })
+        }
+
+        // Waits for the player to end the job
+        fn finished(&self, id: u64) -> JobRecord {
+            loop {
+                let job = self
+                    .jobs
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("the job did not end");
+                if job.id as u64 == id && job.state.is_done() {
+                    return job;
+                }
+            }
+        }
+
+        // Duties set so far with the simulated time in ms they were set at
+        fn duties(&self) -> Vec<(u64, u32)> {
+            self.duties
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|event| (event.at.as_millis() as u64, event.value))
+                .collect()
+        }
+    }
+
+    fn duty(angle: u32) -> u32 {
+        ServoCalibration::default().duty_for(angle, MAX_DUTY)
+    }
+
+    fn job_id(response: &Response) -> u64 {
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        body["job"].as_u64().expect("no job in the response")
+    }
+
+    #[test]
+    fn routes_fit_the_server() {
+        for routes in [ROUTES, crate::portal::ROUTES] {
+            assert!(routes.len() <= MAX_ROUTES);
+            // Registering a route twice fails as well
+            for (idx, route) in routes.iter().enumerate() {
+                assert!(!routes[..idx].contains(route), "{:?}", route);
+            }
+        }
+    }
+
+    #[test]
+    fn servo_request_moves_the_servo() {
+        let gong = Gong::new();
+        let response = gong.request(Method::Post, "/servo", Some(TOKEN), "90,200,30,500,90\n");
+        assert_eq!(response.status, 202, "{}", response.body);
+        let job = gong.finished(job_id(&response));
+        assert_eq!(job.state, JobState::Finished);
+        assert_eq!(
+            gong.duties(),
+            [(0, duty(90)), (200, duty(30)), (700, duty(90))]
+        );
+        assert_eq!(gong.clock.now(), Duration::from_millis(700));
+
+        let status: Value =
+            serde_json::from_str(&gong.request(Method::Get, "/status", Some(TOKEN), "").body)
+                .unwrap();
+        assert_eq!(status["servo_angle"], 90);
+    }
+
+    #[test]
+    fn sequences_play_one_after_the_other() {
+        let gong = Gong::new();
+        let first = gong.request(Method::Post, "/servo", Some(TOKEN), "10,100,20");
+        let second = gong.request(Method::Post, "/servo", Some(TOKEN), "30");
+        gong.finished(job_id(&first));
+        gong.finished(job_id(&second));
+        assert_eq!(
+            gong.duties(),
+            [(0, duty(10)), (100, duty(20)), (100, duty(30))]
+        );
+    }
+
+    #[test]
+    fn dry_run_steps_are_a_servo_body() {
+        let gong = Gong::new();
+        let response = gong.request(
+            Method::Post,
+            "/pattern?dry_run=1",
+            Some(TOKEN),
+            "bpm=1 hold=60000 . x . x",
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        let steps = body["steps"].as_str().unwrap();
+        let sequence = sequence::parse(steps).unwrap();
+        assert_eq!(body["duration_ms"], sequence.duration().as_millis() as u64);
+        assert_eq!(
+            gong.request(Method::Post, "/servo", Some(TOKEN), steps)
+                .status,
+            202
+        );
+    }
+
+    #[test]
+    fn rejected_requests_do_not_move_the_servo() {
+        let gong = Gong::new();
+        assert_eq!(gong.request(Method::Post, "/servo", None, "90").status, 401);
+        assert_eq!(
+            gong.request(Method::Post, "/servo", Some("wrong"), "90")
+                .status,
+            401
+        );
+        let response = gong.request(Method::Post, "/servo", Some(TOKEN), "90,,30");
+        assert_eq!(response.status, 400);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["position"], 3);
+        assert!(gong.duties().is_empty());
+    }
+}
@@ -0,0 +1,491 @@
+// API tokens sent as `Authorization: Bearer gong_...`. Only the SHA-256 of a
+// token is saved, the token itself is shown once when it is created. Each
+// token has scopes: `strike` to play, `configure` to change settings,
+// patterns and schedules, and `admin` for everything including the tokens.
+//
+// Requests without a token are answered 401 unless the anonymous mode is on,
+// which allows everything but the admin routes like before there were
+// tokens.
+//
+// The first admin token is set when building the firmware and saved on the
+// first boot, so only whoever can flash the gong gets one.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::hal::SystemInfo;
+use crate::signing::SignatureError;
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "auth";
+
+pub const AUTHORIZATION: &str = "Authorization";
+
+pub const MAX_TOKENS: usize = 16;
+pub const MAX_NAME_LEN: usize = 32;
+// Shortest admin token accepted from the build
+pub const MIN_PROVISIONED_LEN: usize = 16;
+
+const PROVISIONED_NAME: &str = "provisioned";
+
+const TOKEN_PREFIX: &str = "gong_";
+const TOKEN_BYTES: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Strike,
+    Configure,
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Strike => write!(f, "strike"),
+            Scope::Configure => write!(f, "configure"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub id: u32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // Seconds since the epoch after which the token is no longer accepted
+    pub expires: Option<i64>,
+    // Hex SHA-256 of the token
+    pub hash: String,
+}
+
+impl Token {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    // Requests without a token may do everything but administration
+    pub anonymous: bool,
+    pub tokens: Vec<Token>,
+}
+
+// What `POST /tokens` takes
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // Lifetime in seconds, without one the token is valid until revoked
+    pub expires_in_s: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    // Answered 401
+    Missing,
+    Invalid,
+    Expired,
+    // Answered 403
+    Scope(Scope),
+    Signature(SignatureError),
+    // Rejected token requests
+    InvalidName(String),
+    NoScopes,
+    TooMany,
+    ClockNotSynced,
+    ShortToken,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing `{}: Bearer` token", AUTHORIZATION),
+            AuthError::Invalid => write!(f, "invalid token"),
+            AuthError::Expired => write!(f, "the token has expired"),
+            AuthError::Scope(scope) => write!(f, "not allowed without the {} scope", scope),
+            AuthError::Signature(e) => write!(f, "{}", e),
+            AuthError::InvalidName(name) => write!(
+                f,
+                "the token name `{}` must be 1 to {} printable characters",
+                name, MAX_NAME_LEN
+            ),
+            AuthError::NoScopes => write!(f, "a token needs at least one scope"),
+            AuthError::TooMany => write!(f, "at most {} tokens can be saved", MAX_TOKENS),
+            AuthError::ClockNotSynced => {
+                write!(f, "expiring tokens need the clock to be synchronised")
+            }
+            AuthError::ShortToken => write!(
+                f,
+                "the admin token must be at least {} characters long",
+                MIN_PROVISIONED_LEN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Missing | AuthError::Invalid | AuthError::Expired => 401,
+            AuthError::Scope(_) => 403,
+            AuthError::Signature(e) => e.status(),
+            _ => 400,
+        }
+    }
+}
+
+impl AuthSettings {
+    // Whether a request with the `Authorization` header `header` may use a
+    // route needing `scope`, `None` for routes any token may use. `now` is
+    // `None` until the clock is synchronised, expiring tokens are refused
+    // until then.
+    pub fn authorize(
+        &self,
+        header: Option<&str>,
+        scope: Option<Scope>,
+        now: Option<i64>,
+    ) -> Result<(), AuthError> {
+        let header = match header {
+            Some(header) => header,
+            None if self.anonymous && scope != Some(Scope::Admin) => return Ok(()),
+            None => return Err(AuthError::Missing),
+        };
+        let token = bearer(header).ok_or(AuthError::Missing)?;
+        let hash = crypto::sha256(token.as_bytes());
+        // Every saved hash is compared, so the time taken does not tell
+        // which one came closest
+        let found = self.tokens.iter().fold(None, |found, saved| {
+            let matches = crypto::from_hex(&saved.hash)
+                .is_some_and(|saved| crypto::constant_time_eq(&saved, &hash));
+            if matches {
+                Some(saved)
+            } else {
+                found
+            }
+        });
+        let found = found.ok_or(AuthError::Invalid)?;
+        if let Some(expires) = found.expires {
+            if now.map_or(true, |now| now >= expires) {
+                return Err(AuthError::Expired);
+            }
+        }
+        match scope {
+            Some(scope) if !found.allows(scope) => Err(AuthError::Scope(scope)),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether anyone can administer the tokens
+    pub fn has_admin(&self) -> bool {
+        self.tokens.iter().any(|token| token.allows(Scope::Admin))
+    }
+
+    // Adds `token` from the build as an admin token unless there already is
+    // one, so revoking it is not undone by the next boot. Whether it was
+    // added.
+    pub fn provision(&mut self, token: &str) -> Result<bool, AuthError> {
+        if token.len() < MIN_PROVISIONED_LEN {
+            return Err(AuthError::ShortToken);
+        }
+        if self.has_admin() {
+            return Ok(false);
+        }
+        if self.tokens.len() >= MAX_TOKENS {
+            return Err(AuthError::TooMany);
+        }
+        self.tokens.push(Token {
+            id: self.next_id(),
+            name: PROVISIONED_NAME.into(),
+            scopes: vec![Scope::Admin],
+            expires: None,
+            hash: crypto::to_hex(&crypto::sha256(token.as_bytes())),
+        });
+        Ok(true)
+    }
+
+    fn next_id(&self) -> u32 {
+        self.tokens.iter().map(|t| t.id).max().unwrap_or(0) + 1
+    }
+
+    // Saves a new token and returns it with the token itself, which is not
+    // kept
+    pub fn create(
+        &mut self,
+        new: NewToken,
+        system: &dyn SystemInfo,
+        now: Option<i64>,
+    ) -> Result<(Token, String), AuthError> {
+        if new.name.is_empty()
+            || new.name.len() > MAX_NAME_LEN
+            || new.name.chars().any(char::is_control)
+        {
+            return Err(AuthError::InvalidName(new.name));
+        }
+        if new.scopes.is_empty() {
+            return Err(AuthError::NoScopes);
+        }
+        if self.tokens.len() >= MAX_TOKENS {
+            return Err(AuthError::TooMany);
+        }
+        let expires = match (new.expires_in_s, now) {
+            (None, _) => None,
+            (Some(lifetime), Some(now)) => Some(now + lifetime as i64),
+            (Some(_), None) => return Err(AuthError::ClockNotSynced),
+        };
+
+        let mut random = [0; TOKEN_BYTES];
+        system.fill_random(&mut random);
+        let secret = format!("{}{}", TOKEN_PREFIX, crypto::to_hex(&random));
+        let mut scopes = new.scopes;
+        scopes.sort();
+        scopes.dedup();
+        let token = Token {
+            id: self.next_id(),
+            name: new.name,
+            scopes,
+            expires,
+            hash: crypto::to_hex(&crypto::sha256(secret.as_bytes())),
+        };
+        self.tokens.push(token.clone());
+        Ok((token, secret))
+    }
+
+    // Whether there was a token with `id`
+    pub fn revoke(&mut self, id: u32) -> bool {
+        let count = self.tokens.len();
+        self.tokens.retain(|token| token.id != id);
+        self.tokens.len() != count
+    }
+}
+
+// The token of `Bearer <token>`, the scheme in any case
+fn bearer(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+pub fn load(store: &dyn Store) -> AuthSettings {
+    storage::load(store, STORE_KEY).unwrap_or_default()
+}
+
+pub fn save(store: &dyn Store, settings: &AuthSettings) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, settings)
+}
+
+// Saves the admin token from the build if there is no admin token yet
+pub fn provision_admin(store: &dyn Store, token: &str) -> anyhow::Result<()> {
+    let mut settings = load(store);
+    if settings.provision(token)? {
+        save(store, &settings)?;
+        log::info!("Saved the admin token from the build");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::hal::HeapInfo;
+
+    const ADMIN: &str = "0123456789abcdef0123";
+
+    struct FixedRandom(u8);
+
+    impl SystemInfo for FixedRandom {
+        fn uptime(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn heap(&self) -> Option<HeapInfo> {
+            None
+        }
+
+        fn reset_reason(&self) -> &'static str {
+            "power_on"
+        }
+
+        fn rssi(&self) -> Option<i8> {
+            None
+        }
+
+        fn fill_random(&self, buf: &mut [u8]) {
+            buf.fill(self.0);
+        }
+    }
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    fn provisioned() -> AuthSettings {
+        let mut settings = AuthSettings::default();
+        assert_eq!(settings.provision(ADMIN), Ok(true));
+        settings
+    }
+
+    #[test]
+    fn the_provisioned_token_is_an_admin() {
+        let settings = provisioned();
+        assert!(settings.has_admin());
+        assert_eq!(settings.tokens[0].name, "provisioned");
+        assert_ne!(settings.tokens[0].hash, ADMIN);
+        let header = bearer(ADMIN);
+        assert_eq!(
+            settings.authorize(Some(&header), Some(Scope::Admin), None),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn provisioning_only_adds_the_first_admin() {
+        let mut settings = provisioned();
+        assert_eq!(settings.provision("another-long-admin-token"), Ok(false));
+        assert_eq!(settings.tokens.len(), 1);
+        assert_eq!(
+            AuthSettings::default().provision("short"),
+            Err(AuthError::ShortToken)
+        );
+        // A revoked one is not added back while another admin exists
+        let (admin, _) = settings
+            .create(
+                NewToken {
+                    name: "owner".into(),
+                    scopes: vec![Scope::Admin],
+                    expires_in_s: None,
+                },
+                &FixedRandom(1),
+                None,
+            )
+            .unwrap();
+        assert!(settings.revoke(1));
+        assert_eq!(settings.provision(ADMIN), Ok(false));
+        assert_eq!(settings.tokens, [admin]);
+    }
+
+    #[test]
+    fn scopes() {
+        let mut settings = provisioned();
+        let (_, strike) = settings
+            .create(
+                NewToken {
+                    name: "ci".into(),
+                    scopes: vec![Scope::Strike, Scope::Strike],
+                    expires_in_s: None,
+                },
+                &FixedRandom(0xab),
+                None,
+            )
+            .unwrap();
+        assert_eq!(strike, format!("gong_{}", "ab".repeat(TOKEN_BYTES)));
+        assert_eq!(settings.tokens[1].scopes, [Scope::Strike]);
+        let header = bearer(&strike);
+        assert_eq!(settings.authorize(Some(&header), None, None), Ok(()));
+        assert_eq!(
+            settings.authorize(Some(&header), Some(Scope::Strike), None),
+            Ok(())
+        );
+        assert_eq!(
+            settings.authorize(Some(&header), Some(Scope::Configure), None),
+            Err(AuthError::Scope(Scope::Configure))
+        );
+        assert_eq!(
+            settings.authorize(Some("bearer  gong_nope "), None, None),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            settings.authorize(Some("Basic abc"), None, None),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn anonymous_requests() {
+        let mut settings = provisioned();
+        assert_eq!(
+            settings.authorize(None, Some(Scope::Strike), None),
+            Err(AuthError::Missing)
+        );
+        settings.anonymous = true;
+        assert_eq!(
+            settings.authorize(None, Some(Scope::Configure), None),
+            Ok(())
+        );
+        assert_eq!(
+            settings.authorize(None, Some(Scope::Admin), None),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn expiring_tokens() {
+        let mut settings = AuthSettings::default();
+        let new = || NewToken {
+            name: "visitor".into(),
+            scopes: vec![Scope::Strike],
+            expires_in_s: Some(60),
+        };
+        assert_eq!(
+            settings.create(new(), &FixedRandom(2), None),
+            Err(AuthError::ClockNotSynced)
+        );
+        let (token, secret) = settings
+            .create(new(), &FixedRandom(2), Some(1_000))
+            .unwrap();
+        assert_eq!(token.expires, Some(1_060));
+        let header = bearer(&secret);
+        assert_eq!(settings.authorize(Some(&header), None, Some(1_059)), Ok(()));
+        assert_eq!(
+            settings.authorize(Some(&header), None, Some(1_060)),
+            Err(AuthError::Expired)
+        );
+        assert_eq!(
+            settings.authorize(Some(&header), None, None),
+            Err(AuthError::Expired)
+        );
+    }
+
+    #[test]
+    fn invalid_new_tokens() {
+        let mut settings = AuthSettings::default();
+        let new = |name: &str, scopes: Vec<Scope>| NewToken {
+            name: name.into(),
+            scopes,
+            expires_in_s: None,
+        };
+        let random = FixedRandom(3);
+        assert_eq!(
+            settings.create(new("", vec![Scope::Strike]), &random, None),
+            Err(AuthError::InvalidName("".into()))
+        );
+        assert_eq!(
+            settings.create(new("a\nb", vec![Scope::Strike]), &random, None),
+            Err(AuthError::InvalidName("a\nb".into()))
+        );
+        assert_eq!(
+            settings.create(new("none", vec![]), &random, None),
+            Err(AuthError::NoScopes)
+        );
+        for _ in 0..MAX_TOKENS {
+            settings
+                .create(new("many", vec![Scope::Strike]), &random, None)
+                .unwrap();
+        }
+        assert_eq!(
+            settings.create(new("many", vec![Scope::Strike]), &random, None),
+            Err(AuthError::TooMany)
+        );
+    }
+}
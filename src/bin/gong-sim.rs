@@ -0,0 +1,147 @@
+// Serves the firmware's HTTP API on the host with a simulated servo, for
+// developing clients without access to the gong.
+//
+// cargo run --no-default-features --features sim --target x86_64-unknown-linux-gnu --bin gong-sim [address]
+//
+// `GONG_ADMIN_TOKEN` is the admin token, like `ESP32_ADMIN_TOKEN` when
+// building the firmware. With `GONG_MQTT_URL=mqtt://127.0.0.1:1883` it also
+// connects to an MQTT broker, with the id from `GONG_MQTT_ID` if set.
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::*;
+
+use gong::api::{body_limit, Api, Method, Request, Response, HEADERS};
+use gong::auth;
+use gong::calibration::ServoCalibration;
+use gong::hal::ServoMotor;
+use gong::link::{self, Connection, LinkState};
+use gong::mqtt::{Bridge, MqttSettings};
+use gong::mqtt_client;
+use gong::player::{self, Player, SystemClock, QUEUE_CAPACITY};
+use gong::schedule;
+use gong::sim::{MemoryStore, SimActuator, SimIndicator, SimSystem};
+use gong::storage::Store;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_owned());
+
+    let clock = SystemClock::new();
+
+    // There is no WiFi to wait for, the link is up straight away
+    let link = link::spawn_indicator(SimIndicator::new(clock))?;
+    match address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(address)) => link.set_up(Connection {
+            ssid: "simulated".into(),
+            ip: *address.ip(),
+        }),
+        _ => link.set(LinkState::Up),
+    }
+
+    let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+    if let Ok(token) = std::env::var("GONG_ADMIN_TOKEN") {
+        auth::provision_admin(&*store, &token)?;
+    }
+    let calibration = Arc::new(Mutex::new(ServoCalibration::default()));
+    let servo = ServoMotor::new(SimActuator::new(clock), calibration.clone());
+    let player = Arc::new(player::spawn(Player::new(servo, clock), QUEUE_CAPACITY)?);
+    let system = Arc::new(SimSystem::new(clock));
+    let api = Arc::new(Api::new(player, calibration, store, system, link));
+
+    if let Ok(url) = std::env::var("GONG_MQTT_URL") {
+        let mut settings = MqttSettings {
+            enabled: true,
+            url,
+            ..Default::default()
+        };
+        if let Ok(id) = std::env::var("GONG_MQTT_ID") {
+            settings.id = id;
+        }
+        settings.validate()?;
+        let bridge = Arc::new(Bridge::new(api.clone(), &settings));
+        mqtt_client::spawn(settings, bridge)?;
+    }
+
+    // The host clock is already set
+    schedule::spawn(api.clone())?;
+
+    let server = tiny_http::Server::http(&address).map_err(|e| anyhow::anyhow!(e))?;
+    info!("Simulated gong listening on http://{}", address);
+
+    for mut request in server.incoming_requests() {
+        let response = match api_method(request.method()) {
+            Some(method) => {
+                let limit = body_limit(request.url().split('?').next().unwrap_or_default());
+                let headers: Vec<(&str, String)> = request
+                    .headers()
+                    .iter()
+                    .filter_map(|header| {
+                        let name = HEADERS.iter().find(|name| header.field.equiv(name))?;
+                        Some((*name, header.value.to_string()))
+                    })
+                    .collect();
+                let headers: Vec<(&str, &str)> = headers
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+                let mut body = Vec::new();
+                if request.body_length().is_some_and(|len| len > limit) {
+                    body.resize(limit + 1, 0);
+                } else {
+                    request
+                        .as_reader()
+                        .take(limit as u64 + 1)
+                        .read_to_end(&mut body)?;
+                }
+                if body.len() > limit {
+                    Response::error(413, "request body too large")
+                } else {
+                    api.handle(&Request {
+                        method,
+                        uri: request.url(),
+                        headers: &headers,
+                        client: request.remote_addr().map(|address| address.ip()),
+                        body: &body,
+                    })
+                }
+            }
+            None => Response::error(405, "method not allowed"),
+        };
+
+        info!(
+            "{} {} -> {}",
+            request.method(),
+            request.url(),
+            response.status
+        );
+        let mut reply = tiny_http::Response::from_string(response.body)
+            .with_status_code(response.status)
+            .with_header(
+                tiny_http::Header::from_bytes("Content-Type", response.content_type).unwrap(),
+            );
+        for (name, value) in &response.headers {
+            reply.add_header(tiny_http::Header::from_bytes(*name, value.as_bytes()).unwrap());
+        }
+        request.respond(reply)?;
+    }
+
+    Ok(())
+}
+
+fn api_method(method: &tiny_http::Method) -> Option<Method> {
+    match method {
+        tiny_http::Method::Get => Some(Method::Get),
+        tiny_http::Method::Post => Some(Method::Post),
+        tiny_http::Method::Put => Some(Method::Put),
+        tiny_http::Method::Delete => Some(Method::Delete),
+        _ => None,
+    }
+}
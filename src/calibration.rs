@@ -0,0 +1,361 @@
+// Servo calibration: how angles map to pulse widths and which angles are safe
+// for the mallet.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::profile::MAX_MOVE_MS;
+use crate::sequence::MAX_ANGLE;
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "servo";
+
+// The servo runs on a 50 Hz signal
+pub const PERIOD_US: u32 = 20_000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoCalibration {
+    // Pulse widths at the two ends of the angle range
+    pub min_pulse_us: u32,
+    pub max_pulse_us: u32,
+    // Angles corresponding to `min_pulse_us` and `max_pulse_us`
+    pub min_angle: u32,
+    pub max_angle: u32,
+    // Swaps the direction of rotation
+    pub inverted: bool,
+    // Where the mallet hangs between sequences
+    pub rest_angle: u32,
+    // Angles outside of these are clamped, to keep the mallet off the frame
+    pub soft_min_angle: u32,
+    pub soft_max_angle: u32,
+    // What strikes of different velocities look like with this servo
+    pub strike: StrikeCalibration,
+}
+
+// A strike swings from a backswing angle to the impact angle. Velocity 0.0
+// uses the `soft_*` values, 1.0 the `hard_*` ones and anything in between is
+// interpolated.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StrikeCalibration {
+    // Angle at which the mallet touches the gong
+    pub impact_angle: u32,
+    pub soft_backswing_angle: u32,
+    pub hard_backswing_angle: u32,
+    // Speed of the swing towards the gong
+    pub soft_speed_deg_per_s: u32,
+    pub hard_speed_deg_per_s: u32,
+    // Time to raise the mallet to the backswing angle
+    pub backswing_ms: u32,
+    // Pause at the top of the backswing before swinging
+    pub settle_ms: u32,
+    // Shortest time the mallet stays at the impact angle
+    pub contact_ms: u32,
+    // Time to return to the rest angle after the strike
+    pub return_ms: u32,
+}
+
+impl Default for StrikeCalibration {
+    fn default() -> Self {
+        Self {
+            impact_angle: 30,
+            soft_backswing_angle: 110,
+            hard_backswing_angle: 160,
+            soft_speed_deg_per_s: 150,
+            hard_speed_deg_per_s: 1000,
+            backswing_ms: 300,
+            settle_ms: 50,
+            contact_ms: 40,
+            return_ms: 300,
+        }
+    }
+}
+
+impl Default for ServoCalibration {
+    fn default() -> Self {
+        Self {
+            min_pulse_us: 500,
+            max_pulse_us: 2500,
+            min_angle: 0,
+            max_angle: 180,
+            inverted: false,
+            rest_angle: 90,
+            soft_min_angle: 0,
+            soft_max_angle: MAX_ANGLE,
+            strike: StrikeCalibration::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    PulseRange,
+    PulseTooLong,
+    AngleRange,
+    SoftLimits,
+    RestOutsideLimits,
+    StrikeOutsideLimits,
+    StrikeSpeed,
+    StrikeTiming,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::PulseRange => {
+                write!(f, "min_pulse_us must be smaller than max_pulse_us")
+            }
+            CalibrationError::PulseTooLong => {
+                write!(f, "max_pulse_us must fit in the {} us period", PERIOD_US)
+            }
+            CalibrationError::AngleRange => write!(f, "min_angle must be smaller than max_angle"),
+            CalibrationError::SoftLimits => write!(
+                f,
+                "soft_min_angle must not be above soft_max_angle and both must be within 0 to {}",
+                MAX_ANGLE
+            ),
+            CalibrationError::RestOutsideLimits => {
+                write!(f, "rest_angle must be within the soft limits")
+            }
+            CalibrationError::StrikeOutsideLimits => {
+                write!(f, "the strike angles must be within the soft limits")
+            }
+            CalibrationError::StrikeSpeed => {
+                write!(
+                    f,
+                    "the strike speeds must be from 1 to 10000 degrees per second"
+                )
+            }
+            CalibrationError::StrikeTiming => write!(
+                f,
+                "the strike durations must not be longer than {} ms",
+                MAX_MOVE_MS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+impl ServoCalibration {
+    pub fn validate(&self) -> Result<(), CalibrationError> {
+        if self.min_pulse_us >= self.max_pulse_us {
+            return Err(CalibrationError::PulseRange);
+        }
+        if self.max_pulse_us > PERIOD_US {
+            return Err(CalibrationError::PulseTooLong);
+        }
+        if self.min_angle >= self.max_angle {
+            return Err(CalibrationError::AngleRange);
+        }
+        if self.soft_min_angle > self.soft_max_angle || self.soft_max_angle > MAX_ANGLE {
+            return Err(CalibrationError::SoftLimits);
+        }
+        let safe = self.soft_min_angle..=self.soft_max_angle;
+        if !safe.contains(&self.rest_angle) {
+            return Err(CalibrationError::RestOutsideLimits);
+        }
+
+        let strike = &self.strike;
+        let strike_angles = [
+            strike.impact_angle,
+            strike.soft_backswing_angle,
+            strike.hard_backswing_angle,
+        ];
+        if !strike_angles.iter().all(|angle| safe.contains(angle)) {
+            return Err(CalibrationError::StrikeOutsideLimits);
+        }
+        let speeds = 1..=10_000;
+        if !speeds.contains(&strike.soft_speed_deg_per_s)
+            || !speeds.contains(&strike.hard_speed_deg_per_s)
+        {
+            return Err(CalibrationError::StrikeSpeed);
+        }
+        let timings = [
+            strike.backswing_ms,
+            strike.settle_ms,
+            strike.contact_ms,
+            strike.return_ms,
+        ];
+        if timings.iter().any(|&ms| ms > MAX_MOVE_MS) {
+            return Err(CalibrationError::StrikeTiming);
+        }
+        Ok(())
+    }
+
+    pub fn clamp(&self, angle: u32) -> u32 {
+        angle.clamp(self.soft_min_angle, self.soft_max_angle)
+    }
+
+    pub fn pulse_us(&self, angle: u32) -> u32 {
+        let angle = angle.clamp(self.min_angle, self.max_angle) - self.min_angle;
+        let offset = (angle as u64 * (self.max_pulse_us - self.min_pulse_us) as u64
+            / (self.max_angle - self.min_angle) as u64) as u32;
+        if self.inverted {
+            self.max_pulse_us - offset
+        } else {
+            self.min_pulse_us + offset
+        }
+    }
+
+    // Duty value for the angle, after clamping it to the soft limits
+    pub fn duty_for(&self, angle: u32, max_duty: u32) -> u32 {
+        (self.pulse_us(self.clamp(angle)) as u64 * max_duty as u64 / PERIOD_US as u64) as u32
+    }
+}
+
+// The saved calibration, or the default one if there is none or it is invalid
+pub fn load(store: &dyn Store) -> ServoCalibration {
+    match storage::load::<ServoCalibration>(store, STORE_KEY) {
+        Some(calibration) => match calibration.validate() {
+            Ok(()) => calibration,
+            Err(e) => {
+                log::warn!("Ignoring saved servo calibration: {}", e);
+                ServoCalibration::default()
+            }
+        },
+        None => ServoCalibration::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // With a duty range of one step per microsecond the duty is the pulse width
+    const US: u32 = PERIOD_US;
+
+    #[test]
+    fn angles_map_to_pulses() {
+        let calibration = ServoCalibration::default();
+        assert_eq!(calibration.pulse_us(0), 500);
+        assert_eq!(calibration.pulse_us(90), 1500);
+        assert_eq!(calibration.pulse_us(180), 2500);
+        assert_eq!(calibration.pulse_us(45), 1000);
+        // Rounded down
+        assert_eq!(calibration.pulse_us(1), 511);
+    }
+
+    #[test]
+    fn inverted_servo_turns_the_other_way() {
+        let calibration = ServoCalibration {
+            inverted: true,
+            ..ServoCalibration::default()
+        };
+        assert_eq!(calibration.duty_for(0, US), 2500);
+        assert_eq!(calibration.duty_for(90, US), 1500);
+        assert_eq!(calibration.duty_for(180, US), 500);
+        assert_eq!(calibration.duty_for(45, US), 2000);
+    }
+
+    #[test]
+    fn angles_are_clamped() {
+        let calibration = ServoCalibration {
+            min_angle: 10,
+            max_angle: 170,
+            soft_min_angle: 20,
+            soft_max_angle: 150,
+            ..ServoCalibration::default()
+        };
+        assert_eq!(calibration.clamp(0), 20);
+        assert_eq!(calibration.clamp(90), 90);
+        assert_eq!(calibration.clamp(180), 150);
+        assert_eq!(calibration.duty_for(0, US), calibration.duty_for(20, US));
+        assert_eq!(calibration.duty_for(180, US), calibration.duty_for(150, US));
+        assert_eq!(calibration.duty_for(20, US), 500 + 10 * 2000 / 160);
+
+        // Outside of the servo's range the pulse stops at the ends
+        assert_eq!(calibration.pulse_us(0), 500);
+        assert_eq!(calibration.pulse_us(180), 2500);
+    }
+
+    #[test]
+    fn pulses_become_duties() {
+        let calibration = ServoCalibration::default();
+        // 1500 us of 20 ms with a 14-bit timer
+        assert_eq!(calibration.duty_for(90, 1 << 14), 1228);
+        assert_eq!(calibration.duty_for(0, 1 << 14), 409);
+        assert_eq!(calibration.duty_for(180, 1 << 14), 2048);
+        assert_eq!(calibration.duty_for(180, u32::MAX), 536_870_911);
+    }
+
+    #[test]
+    fn validates_calibration() {
+        let valid = ServoCalibration::default();
+        assert_eq!(valid.validate(), Ok(()));
+
+        let check = |change: fn(&mut ServoCalibration)| {
+            let mut calibration = ServoCalibration::default();
+            change(&mut calibration);
+            calibration.validate()
+        };
+        let error = |change| check(change).unwrap_err();
+        assert_eq!(
+            error(|c| c.min_pulse_us = c.max_pulse_us),
+            CalibrationError::PulseRange
+        );
+        assert_eq!(
+            error(|c| c.max_pulse_us = PERIOD_US + 1),
+            CalibrationError::PulseTooLong
+        );
+        assert_eq!(check(|c| c.max_pulse_us = PERIOD_US), Ok(()));
+        assert_eq!(
+            error(|c| c.min_angle = c.max_angle),
+            CalibrationError::AngleRange
+        );
+        assert_eq!(
+            error(|c| c.soft_min_angle = c.soft_max_angle + 1),
+            CalibrationError::SoftLimits
+        );
+        assert_eq!(
+            error(|c| c.soft_max_angle = MAX_ANGLE + 1),
+            CalibrationError::SoftLimits
+        );
+        assert_eq!(
+            error(|c| c.soft_max_angle = 80),
+            CalibrationError::RestOutsideLimits
+        );
+        assert_eq!(
+            check(|c| {
+                c.soft_min_angle = 90;
+                c.soft_max_angle = 90;
+                c.strike.impact_angle = 90;
+                c.strike.soft_backswing_angle = 90;
+                c.strike.hard_backswing_angle = 90;
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            error(|c| c.soft_min_angle = 40),
+            CalibrationError::StrikeOutsideLimits
+        );
+        assert_eq!(
+            error(|c| c.soft_max_angle = 150),
+            CalibrationError::StrikeOutsideLimits
+        );
+        assert_eq!(
+            error(|c| c.strike.soft_speed_deg_per_s = 0),
+            CalibrationError::StrikeSpeed
+        );
+        assert_eq!(
+            error(|c| c.strike.hard_speed_deg_per_s = 10_001),
+            CalibrationError::StrikeSpeed
+        );
+        assert_eq!(
+            error(|c| c.strike.contact_ms = MAX_MOVE_MS + 1),
+            CalibrationError::StrikeTiming
+        );
+    }
+
+    #[test]
+    fn missing_fields_are_defaults() {
+        let calibration: ServoCalibration =
+            serde_json::from_str(r#"{"inverted": true, "strike": {"contact_ms": 80}}"#).unwrap();
+        assert!(calibration.inverted);
+        assert_eq!(calibration.strike.contact_ms, 80);
+        assert_eq!(calibration.rest_angle, 90);
+        assert!(serde_json::from_str::<ServoCalibration>(r#"{"invert": true}"#).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+// Cron expressions with the usual five fields, `minute hour day month
+// weekday`, e.g. `0 17 * * fri` or `*/15 9-17 * * 1-5`. Fields take `*`,
+// numbers, ranges, lists and steps, months and weekdays also their English
+// abbreviations. As in Vixie cron, a day matches either the day of the month
+// or the weekday when both are restricted.
+//
+// Times are matched in local time. A time skipped when the clocks go forward
+// fires when they do, a time passed twice when they go back fires the first
+// time only.
+
+use std::fmt;
+
+use crate::tz::{self, LocalTime, TimeZone, SECS_PER_DAY};
+
+// Far enough to find the rarest schedules, like February 29th
+const MAX_DAYS: i64 = 366 * 28;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CronError {
+    FieldCount(String),
+    Field(&'static str, String),
+}
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CronError::FieldCount(expression) => write!(
+                f,
+                "`{}` must have five fields: minute hour day month weekday",
+                expression
+            ),
+            CronError::Field(field, value) => write!(f, "invalid {} field `{}`", field, value),
+        }
+    }
+}
+
+impl std::error::Error for CronError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    // Bit `n` is set when value `n` matches
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    // Whether the field starts with `*`, like `*/2`, for the day rule above
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, CronError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(CronError::FieldCount(expression.to_owned()));
+        }
+        let minutes = field("minute", fields[0], 0, 59, &[])?;
+        let hours = field("hour", fields[1], 0, 23, &[])?;
+        let days = field("day", fields[2], 1, 31, &[])?;
+        let months = field("month", fields[3], 1, 12, &MONTHS)?;
+        // 7 is Sunday as well
+        let weekdays = field("weekday", fields[4], 0, 7, &WEEKDAYS)?;
+        Ok(Self {
+            minutes,
+            hours: hours as u32,
+            days: days as u32,
+            months: months as u16,
+            weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = tz::civil_from_days(days);
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        let day_matches = self.days & 1 << day != 0;
+        let weekday_matches = self.weekdays & 1 << tz::weekday(days) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+
+    // The first time after `utc` the expression fires
+    pub fn next_after(&self, utc: i64, zone: &TimeZone) -> Option<i64> {
+        // Starting a day early covers the local times that lie after `utc`
+        // on the previous day's date in any offset
+        let first_day = zone.to_local(utc).div_euclid(SECS_PER_DAY) - 1;
+        for days in first_day..first_day + MAX_DAYS {
+            if !self.matches_day(days) {
+                continue;
+            }
+            let mut earliest: Option<i64> = None;
+            for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                    let local = days * SECS_PER_DAY + hour * 3600 + minute * 60;
+                    let fires = match zone.to_utc(local) {
+                        LocalTime::Single(at)
+                        | LocalTime::Ambiguous(at, _)
+                        | LocalTime::Gap(at) => at,
+                    };
+                    if fires > utc && earliest.map_or(true, |earliest| fires < earliest) {
+                        earliest = Some(fires);
+                    }
+                }
+            }
+            if earliest.is_some() {
+                return earliest;
+            }
+        }
+        None
+    }
+}
+
+// One field as a bit set, `names` are the alternatives to the numbers
+// starting at `min`
+fn field(
+    name: &'static str,
+    value: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> Result<u64, CronError> {
+    let invalid = || CronError::Field(name, value.to_owned());
+    let number = |s: &str| -> Result<u32, CronError> {
+        let n = match names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            Some(idx) => idx as u32 + min,
+            None => s.parse().map_err(|_| invalid())?,
+        };
+        if (min..=max).contains(&n) {
+            Ok(n)
+        } else {
+            Err(invalid())
+        }
+    };
+
+    let mut bits = 0_u64;
+    for part in value.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `5/15` runs from 5 to the end
+            None if part.contains('/') => (number(range)?, max),
+            None => {
+                let n = number(range)?;
+                (n, n)
+            }
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EUROPE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    fn next(expression: &str, utc: i64, zone: &str) -> Option<i64> {
+        Cron::parse(expression)
+            .unwrap()
+            .next_after(utc, &TimeZone::parse(zone).unwrap())
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "* * * foo *",
+        ] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
+        }
+        assert!(Cron::parse("*/15 9-17 * jan,JUL-dec 1-5").is_ok());
+    }
+
+    #[test]
+    fn fires_on_the_next_match() {
+        // Monday 2024-07-01 00:00 UTC
+        let monday = 1_719_792_000;
+        assert_eq!(
+            next("0 17 * * fri", monday, "UTC0"),
+            Some(monday + 4 * SECS_PER_DAY + 17 * 3600)
+        );
+        assert_eq!(next("*/15 * * * *", monday, "UTC0"), Some(monday + 15 * 60));
+        // Sunday is 0 and 7
+        assert_eq!(
+            next("0 0 * * 7", monday, "UTC0"),
+            next("0 0 * * sun", monday, "UTC0")
+        );
+        // 2028-02-29, the next leap day
+        assert_eq!(
+            next("0 0 29 2 *", 1_709_164_800, "UTC0"),
+            Some(1_835_395_200)
+        );
+    }
+
+    #[test]
+    fn restricted_day_and_weekday_match_either() {
+        // Monday 2024-07-01 12:00 UTC, the next 13th is a Saturday
+        let monday = 1_719_835_200;
+        assert_eq!(
+            next("0 12 13 * mon", monday, "UTC0"),
+            Some(monday + 7 * SECS_PER_DAY)
+        );
+        assert_eq!(
+            next("0 12 13 * fri", monday, "UTC0"),
+            Some(monday + 4 * SECS_PER_DAY)
+        );
+    }
+
+    #[test]
+    fn day_steps_count_as_any_day() {
+        // Monday 2024-07-01 12:00 UTC, the next odd Monday is the 15th
+        let monday = 1_719_835_200;
+        assert_eq!(
+            next("0 12 */2 * mon", monday, "UTC0"),
+            Some(monday + 14 * SECS_PER_DAY)
+        );
+        // Odd days only, every weekday
+        assert_eq!(
+            next("0 12 1-31/2 * */1", monday, "UTC0"),
+            Some(monday + 2 * SECS_PER_DAY)
+        );
+    }
+
+    #[test]
+    fn skipped_time_fires_when_the_clocks_go_forward() {
+        // 2024-03-31 00:00 UTC, 02:30 does not exist that night
+        let night = 1_711_843_200;
+        assert_eq!(next("30 2 * * *", night, EUROPE), Some(1_711_846_800));
+        assert_eq!(
+            next("30 2 * * *", 1_711_846_800, EUROPE),
+            Some(1_711_846_800 + SECS_PER_DAY - 30 * 60)
+        );
+    }
+
+    #[test]
+    fn repeated_time_fires_once_when_the_clocks_go_back() {
+        // 2024-10-27 00:00 UTC, 02:30 is passed at 00:30 and 01:30 UTC
+        let night = 1_729_987_200;
+        assert_eq!(next("30 2 * * *", night, EUROPE), Some(1_729_989_000));
+        assert_eq!(
+            next("30 2 * * *", 1_729_989_000, EUROPE),
+            Some(1_730_079_000)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// mDNS advertisement, so clients on the LAN find the gong as `gong.local` or
+// by browsing for its services instead of needing its address.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ACTUATORS, API_VERSION};
+use crate::storage::{self, Store};
+use crate::tls;
+
+pub const STORE_KEY: &str = "mdns";
+
+// Label limit of DNS names
+pub const MAX_HOSTNAME_LEN: usize = 63;
+
+pub const HTTP_PORT: u16 = 80;
+
+// Service types and protocols advertised, both pointing at the API
+pub const SERVICES: &[(&str, &str)] = &[("_gong", "_tcp"), ("_http", "_tcp")];
+pub const HTTPS_SERVICES: &[(&str, &str)] = &[("_gong", "_tcp"), ("_https", "_tcp")];
+
+// The services and port the API is found at, served over HTTPS or not
+pub fn services(https: bool) -> (&'static [(&'static str, &'static str)], u16) {
+    if https {
+        (HTTPS_SERVICES, tls::HTTPS_PORT)
+    } else {
+        (SERVICES, HTTP_PORT)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoverySettings {
+    // Without `.local`, also used as the instance name of the services
+    pub hostname: String,
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            hostname: "gong".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryError {
+    HostnameLength,
+    // Only letters, digits and hyphens, not at the start or end
+    InvalidHostname(String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::HostnameLength => write!(
+                f,
+                "the hostname must be 1 to {} characters long",
+                MAX_HOSTNAME_LEN
+            ),
+            DiscoveryError::InvalidHostname(hostname) => write!(
+                f,
+                "the hostname `{}` may only contain letters, digits and hyphens, not at the start or end",
+                hostname
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+impl DiscoverySettings {
+    pub fn validate(&self) -> Result<(), DiscoveryError> {
+        let hostname = &self.hostname;
+        if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+            return Err(DiscoveryError::HostnameLength);
+        }
+        if !hostname
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            || hostname.starts_with('-')
+            || hostname.ends_with('-')
+        {
+            return Err(DiscoveryError::InvalidHostname(hostname.clone()));
+        }
+        Ok(())
+    }
+}
+
+// TXT records of the services, telling clients what they found before they
+// make a request
+pub fn txt_records(https: bool) -> Vec<(&'static str, String)> {
+    vec![
+        ("fw", env!("CARGO_PKG_VERSION").to_owned()),
+        ("api", API_VERSION.to_string()),
+        ("actuators", ACTUATORS.to_string()),
+        ("scheme", if https { "https" } else { "http" }.to_owned()),
+    ]
+}
+
+// The saved settings, or the defaults if there are none that are usable
+pub fn load(store: &dyn Store) -> DiscoverySettings {
+    let settings: DiscoverySettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved mDNS settings: {}", e);
+            DiscoverySettings::default()
+        }
+    }
+}
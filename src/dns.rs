@@ -0,0 +1,61 @@
+// The DNS side of the captive portal: in access point mode every name
+// resolves to the gong, so any page a client opens shows the setup form.
+
+use std::net::Ipv4Addr;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+// Clients should not remember the answers once they are on the real network
+const TTL_S: u32 = 10;
+
+// The reply to a DNS query, answering A records with `address` and anything
+// else without records. `None` for packets that are not queries.
+pub fn captive_answer(query: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    // Only standard queries with a question
+    if flags & 0x8000 != 0 || flags & 0x7800 != 0 || questions == 0 {
+        return None;
+    }
+
+    // Only the first question is answered, the name is a list of labels
+    // ending with an empty one
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let question = query.get(HEADER_LEN..end + 4)?;
+    let kind = u16::from_be_bytes([query[end], query[end + 1]]);
+    let class = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+    let answer = kind == TYPE_A && class == CLASS_IN;
+
+    let mut reply = Vec::with_capacity(end + 4 + 16);
+    reply.extend_from_slice(&query[0..2]);
+    // A response, keeping the recursion desired bit, with recursion available
+    reply.extend_from_slice(&(0x8180 | (flags & 0x0100)).to_be_bytes());
+    reply.extend_from_slice(&1_u16.to_be_bytes());
+    reply.extend_from_slice(&(answer as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if answer {
+        // Pointer to the name in the question
+        reply.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL_S.to_be_bytes());
+        reply.extend_from_slice(&4_u16.to_be_bytes());
+        reply.extend_from_slice(&address.octets());
+    }
+    Some(reply)
+}
@@ -0,0 +1,117 @@
+// Board implementations of the hardware traits
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::hal::ledc::LedcDriver;
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::{
+    esp, esp_fill_random, esp_get_free_heap_size, esp_get_minimum_free_heap_size,
+    esp_timer_get_time, esp_wifi_sta_get_ap_info, wifi_ap_record_t,
+};
+use ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriver;
+
+use crate::hal::{Actuator, Color, HeapInfo, StatusIndicator, SystemInfo};
+use crate::storage::Store;
+
+const NVS_NAMESPACE: &str = "gong";
+
+pub struct LedcActuator(pub LedcDriver<'static>);
+
+impl Actuator for LedcActuator {
+    fn max_duty(&self) -> u32 {
+        self.0.get_max_duty()
+    }
+
+    fn set_duty(&mut self, duty: u32) -> anyhow::Result<()> {
+        self.0.set_duty(duty)?;
+        Ok(())
+    }
+}
+
+pub struct Ws2812Indicator(pub Ws2812Esp32RmtDriver);
+
+impl StatusIndicator for Ws2812Indicator {
+    fn set_color(&mut self, color: Color) -> anyhow::Result<()> {
+        // The WS2812 expects the colours in GRB order
+        self.0.write(&[color.g, color.r, color.b])?;
+        Ok(())
+    }
+}
+
+pub struct NvsStore(Mutex<EspNvs<NvsDefault>>);
+
+impl NvsStore {
+    pub fn new(partition: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        Ok(Self(Mutex::new(EspNvs::new(
+            partition,
+            NVS_NAMESPACE,
+            true,
+        )?)))
+    }
+}
+
+impl Store for NvsStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let nvs = self.0.lock().unwrap();
+        let Some(len) = nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buffer = vec![0; len];
+        Ok(nvs.get_blob(key, &mut buffer)?.map(|value| value.to_vec()))
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.0.lock().unwrap().set_blob(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.0.lock().unwrap().remove(key)?;
+        Ok(())
+    }
+}
+
+pub struct EspSystem;
+
+impl SystemInfo for EspSystem {
+    fn uptime(&self) -> Duration {
+        Duration::from_micros(unsafe { esp_timer_get_time() }.max(0) as u64)
+    }
+
+    fn heap(&self) -> Option<HeapInfo> {
+        Some(HeapInfo {
+            free_bytes: unsafe { esp_get_free_heap_size() },
+            min_free_bytes: unsafe { esp_get_minimum_free_heap_size() },
+        })
+    }
+
+    fn reset_reason(&self) -> &'static str {
+        match ResetReason::get() {
+            ResetReason::Software => "software",
+            ResetReason::ExternalPin => "external_pin",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Sdio => "sdio",
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog => "interrupt_watchdog",
+            ResetReason::PowerOn => "power_on",
+            ResetReason::Unknown => "unknown",
+            ResetReason::Brownout => "brownout",
+            ResetReason::TaskWatchdog => "task_watchdog",
+            ResetReason::DeepSleep => "deep_sleep",
+        }
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        // Fails while the client is not connected
+        let mut info = wifi_ap_record_t::default();
+        esp!(unsafe { esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+        Some(info.rssi)
+    }
+
+    // From the hardware generator, which is truly random while WiFi is on
+    fn fill_random(&self, buf: &mut [u8]) {
+        unsafe { esp_fill_random(buf.as_mut_ptr().cast(), buf.len()) }
+    }
+}
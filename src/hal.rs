@@ -0,0 +1,109 @@
+// Interfaces to the hardware the gong drives, implemented by the LEDC/WS2812
+// drivers on the board (`esp`) and by recording fakes on the host (`sim`).
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::calibration::ServoCalibration;
+use crate::player::Servo;
+
+pub trait Actuator {
+    // Duty value corresponding to a 100% duty cycle
+    fn max_duty(&self) -> u32;
+    fn set_duty(&mut self, duty: u32) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::new(0, 0, 0);
+    pub const YELLOW: Color = Color::new(120, 120, 0);
+    pub const GREEN: Color = Color::new(0, 120, 10);
+    pub const BLUE: Color = Color::new(0, 20, 120);
+    pub const RED: Color = Color::new(150, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+pub trait StatusIndicator {
+    fn set_color(&mut self, color: Color) -> anyhow::Result<()>;
+}
+
+// What the board tells about itself, for `/status`
+pub trait SystemInfo: Send + Sync {
+    fn uptime(&self) -> Duration;
+    // Free heap and the lowest it has been since boot, if known
+    fn heap(&self) -> Option<HeapInfo>;
+    // Why the board last restarted, e.g. `power_on` or `panic`
+    fn reset_reason(&self) -> &'static str;
+    // Signal strength of the access point in dBm, while connected
+    fn rssi(&self) -> Option<i8>;
+    // Random bytes good enough for secrets like API tokens
+    fn fill_random(&self, buf: &mut [u8]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct HeapInfo {
+    pub free_bytes: u32,
+    pub min_free_bytes: u32,
+}
+
+// A hobby servo on a 50 Hz PWM signal, positioned according to a calibration
+// that can be changed while it runs
+pub struct ServoMotor<A> {
+    actuator: A,
+    calibration: Arc<Mutex<ServoCalibration>>,
+}
+
+impl<A> ServoMotor<A>
+where
+    A: Actuator,
+{
+    pub fn new(actuator: A, calibration: Arc<Mutex<ServoCalibration>>) -> Self {
+        Self {
+            actuator,
+            calibration,
+        }
+    }
+
+    pub fn actuator(&self) -> &A {
+        &self.actuator
+    }
+}
+
+impl<A> Servo for ServoMotor<A>
+where
+    A: Actuator,
+{
+    fn duty_for(&self, angle: u32) -> u32 {
+        let calibration = self.calibration.lock().unwrap();
+        if calibration.clamp(angle) != angle {
+            log::warn!(
+                "Angle {} is outside of the soft limits {}..={}",
+                angle,
+                calibration.soft_min_angle,
+                calibration.soft_max_angle
+            );
+        }
+        calibration.duty_for(angle, self.actuator.max_duty())
+    }
+
+    fn set_duty(&mut self, duty: u32) -> anyhow::Result<()> {
+        self.actuator.set_duty(duty)
+    }
+
+    fn rest_angle(&self) -> u32 {
+        self.calibration.lock().unwrap().rest_angle
+    }
+
+    fn impact_angle(&self) -> u32 {
+        self.calibration.lock().unwrap().strike.impact_angle
+    }
+}
@@ -0,0 +1,358 @@
+// Home Assistant MQTT discovery. The gong announces itself as a device with
+// a button to strike it, a select of the saved patterns, a light for the LED
+// and diagnostic sensors for the signal strength and uptime. The configs are
+// retained and sent again whenever Home Assistant comes online.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::hal::Color;
+use crate::mqtt::{MqttSettings, Publication};
+
+pub const DEFAULT_PREFIX: &str = "homeassistant";
+
+// Where Home Assistant sends `online` after starting, under the prefix
+pub fn status_topic(settings: &MqttSettings) -> String {
+    format!("{}/status", settings.discovery_prefix)
+}
+
+// Configs of all entities. The select is removed while there are no saved
+// patterns, Home Assistant needs at least one option.
+pub fn configs(settings: &MqttSettings, patterns: &[String]) -> Vec<Publication> {
+    let topics = settings.topics();
+    let diagnostics = |name: &str, value: &str, extra: Value| {
+        let mut config = json!({
+            "name": name,
+            "state_topic": topics.diagnostics(),
+            "value_template": format!("{{{{ value_json.{} }}}}", value),
+            "entity_category": "diagnostic",
+        });
+        merge(&mut config, extra);
+        config
+    };
+    vec![
+        config(
+            settings,
+            "button",
+            "strike",
+            json!({
+                "name": "Strike",
+                "command_topic": topics.strike(),
+                "payload_press": "",
+                "icon": "mdi:gong",
+            }),
+        ),
+        select_config(settings, patterns),
+        config(
+            settings,
+            "light",
+            "led",
+            json!({
+                "name": "LED",
+                "schema": "json",
+                "command_topic": topics.light_set(),
+                "state_topic": topics.light(),
+                "brightness": true,
+                "supported_color_modes": ["rgb"],
+            }),
+        ),
+        config(
+            settings,
+            "sensor",
+            "rssi",
+            diagnostics(
+                "Signal strength",
+                "rssi",
+                json!({
+                    "device_class": "signal_strength",
+                    "unit_of_measurement": "dBm",
+                    "state_class": "measurement",
+                }),
+            ),
+        ),
+        config(
+            settings,
+            "sensor",
+            "uptime",
+            diagnostics(
+                "Uptime",
+                "uptime_s",
+                json!({
+                    "device_class": "duration",
+                    "unit_of_measurement": "s",
+                }),
+            ),
+        ),
+    ]
+}
+
+pub fn select_config(settings: &MqttSettings, patterns: &[String]) -> Publication {
+    if patterns.is_empty() {
+        return Publication {
+            topic: config_topic(settings, "select", "pattern"),
+            payload: String::new(),
+            retain: true,
+        };
+    }
+    config(
+        settings,
+        "select",
+        "pattern",
+        json!({
+            "name": "Pattern",
+            "command_topic": settings.topics().pattern(),
+            "options": patterns,
+            "icon": "mdi:music-note",
+        }),
+    )
+}
+
+fn config_topic(settings: &MqttSettings, component: &str, object: &str) -> String {
+    format!(
+        "{}/{}/{}/{}/config",
+        settings.discovery_prefix, component, settings.id, object
+    )
+}
+
+// Adds what every entity shares: its unique id, the availability and the
+// device it belongs to
+fn config(settings: &MqttSettings, component: &str, object: &str, entity: Value) -> Publication {
+    let topics = settings.topics();
+    let mut config = json!({
+        "unique_id": format!("gong_{}_{}", settings.id, object),
+        "availability_topic": topics.availability(),
+        "device": {
+            "identifiers": [format!("gong_{}", settings.id)],
+            "name": settings.id,
+            "model": "ESP32 gong",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    merge(&mut config, entity);
+    Publication {
+        topic: config_topic(settings, component, object),
+        payload: config.to_string(),
+        retain: true,
+    }
+}
+
+fn merge(config: &mut Value, extra: Value) {
+    if let (Some(config), Value::Object(extra)) = (config.as_object_mut(), extra) {
+        config.extend(extra);
+    }
+}
+
+// The LED as a light in the JSON schema of Home Assistant. While it is on
+// it shows its colour instead of the WiFi state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Light {
+    pub on: bool,
+    pub brightness: u8,
+    pub color: Color,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            on: false,
+            brightness: 255,
+            color: Color::new(255, 255, 255),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+// Other fields Home Assistant may send, like `transition`, are ignored
+#[derive(Deserialize)]
+struct LightCommand {
+    state: Option<String>,
+    brightness: Option<u8>,
+    color: Option<Rgb>,
+}
+
+impl Light {
+    // Applies a command like `{"state": "ON", "brightness": 128, "color":
+    // {"r": 255, "g": 0, "b": 0}}`, fields left out keep their value
+    pub fn apply(&mut self, command: &[u8]) -> Result<(), String> {
+        let command: LightCommand = serde_json::from_slice(command).map_err(|e| e.to_string())?;
+        let mut light = *self;
+        match command.state.as_deref() {
+            Some("ON") => light.on = true,
+            Some("OFF") => light.on = false,
+            Some(state) => return Err(format!("unknown state `{}`", state)),
+            None => {}
+        }
+        if let Some(brightness) = command.brightness {
+            light.brightness = brightness;
+        }
+        if let Some(Rgb { r, g, b }) = command.color {
+            light.color = Color::new(r, g, b);
+        }
+        *self = light;
+        Ok(())
+    }
+
+    // The colour to show, `None` leaves the LED to the WiFi state
+    pub fn shown(&self) -> Option<Color> {
+        let scale = |c: u8| (c as u16 * self.brightness as u16 / 255) as u8;
+        self.on.then(|| {
+            Color::new(
+                scale(self.color.r),
+                scale(self.color.g),
+                scale(self.color.b),
+            )
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "state": if self.on { "ON" } else { "OFF" },
+            "brightness": self.brightness,
+            "color_mode": "rgb",
+            "color": self.color,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns() -> Vec<String> {
+        vec!["intro".into(), "outro".into()]
+    }
+
+    // Config payloads by topic
+    fn configs_by_topic(patterns: &[String]) -> Vec<(String, Value)> {
+        configs(&MqttSettings::default(), patterns)
+            .into_iter()
+            .map(|publication| {
+                assert!(publication.retain, "{}", publication.topic);
+                let payload = match publication.payload.as_str() {
+                    "" => Value::Null,
+                    payload => serde_json::from_str(payload).unwrap(),
+                };
+                (publication.topic, payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn announces_every_entity() {
+        let topics: Vec<String> = configs_by_topic(&patterns())
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/button/gong/strike/config",
+                "homeassistant/select/gong/pattern/config",
+                "homeassistant/light/gong/led/config",
+                "homeassistant/sensor/gong/rssi/config",
+                "homeassistant/sensor/gong/uptime/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn button_config() {
+        let (_, button) = &configs_by_topic(&patterns())[0];
+        assert_eq!(
+            *button,
+            json!({
+                "unique_id": "gong_gong_strike",
+                "availability_topic": "gong/gong/availability",
+                "device": {
+                    "identifiers": ["gong_gong"],
+                    "name": "gong",
+                    "model": "ESP32 gong",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+                "name": "Strike",
+                "command_topic": "gong/gong/strike",
+                // Plays the calibrated strike
+                "payload_press": "",
+                "icon": "mdi:gong",
+            })
+        );
+    }
+
+    #[test]
+    fn select_lists_the_patterns() {
+        let (_, select) = &configs_by_topic(&patterns())[1];
+        assert_eq!(select["command_topic"], "gong/gong/pattern");
+        assert_eq!(select["options"], json!(["intro", "outro"]));
+        assert_eq!(select["unique_id"], "gong_gong_pattern");
+
+        // An empty retained config removes the select
+        let (topic, select) = &configs_by_topic(&[])[1];
+        assert_eq!(topic, "homeassistant/select/gong/pattern/config");
+        assert_eq!(*select, Value::Null);
+    }
+
+    #[test]
+    fn light_and_sensor_topics() {
+        let configs = configs_by_topic(&patterns());
+        let light = &configs[2].1;
+        assert_eq!(light["schema"], "json");
+        assert_eq!(light["command_topic"], "gong/gong/light/set");
+        assert_eq!(light["state_topic"], "gong/gong/light");
+        assert_eq!(light["supported_color_modes"], json!(["rgb"]));
+
+        let (rssi, uptime) = (&configs[3].1, &configs[4].1);
+        for sensor in [rssi, uptime] {
+            assert_eq!(sensor["state_topic"], "gong/gong/diagnostics");
+            assert_eq!(sensor["entity_category"], "diagnostic");
+        }
+        assert_eq!(rssi["value_template"], "{{ value_json.rssi }}");
+        assert_eq!(rssi["unit_of_measurement"], "dBm");
+        assert_eq!(uptime["value_template"], "{{ value_json.uptime_s }}");
+        assert_eq!(uptime["device_class"], "duration");
+    }
+
+    #[test]
+    fn topics_follow_the_settings() {
+        let settings = MqttSettings {
+            id: "hall".into(),
+            discovery_prefix: "ha/test".into(),
+            ..MqttSettings::default()
+        };
+        let button = &configs(&settings, &[])[0];
+        assert_eq!(button.topic, "ha/test/button/hall/strike/config");
+        let button: Value = serde_json::from_str(&button.payload).unwrap();
+        assert_eq!(button["command_topic"], "gong/hall/strike");
+        assert_eq!(status_topic(&settings), "ha/test/status");
+    }
+
+    #[test]
+    fn light_commands() {
+        let mut light = Light::default();
+        assert_eq!(light.shown(), None);
+        light
+            .apply(br#"{"state": "ON", "brightness": 128, "color": {"r": 255, "g": 0, "b": 100}}"#)
+            .unwrap();
+        assert_eq!(light.shown(), Some(Color::new(128, 0, 50)));
+        light
+            .apply(br#"{"state": "OFF", "transition": 2}"#)
+            .unwrap();
+        assert_eq!(light.shown(), None);
+        assert_eq!(light.brightness, 128);
+
+        // Rejected commands change nothing
+        let before = light;
+        assert!(light
+            .apply(br#"{"state": "ON", "brightness": 300}"#)
+            .is_err());
+        assert!(light
+            .apply(br#"{"brightness": 10, "state": "DIM"}"#)
+            .is_err());
+        assert_eq!(light, before);
+    }
+}
@@ -0,0 +1,484 @@
+// Incoming webhooks at `POST /hooks/{name}`. Every hook has an adapter for
+// the tool sending it and rules that pick a saved pattern from the payload,
+// e.g. a successful deploy plays `triple` and a firing alert `roll`.
+//
+// Rules match JSON paths like `deployment_status.state` or `alerts.0.status`
+// against values, the first rule whose fields all match wins. Payloads no
+// rule matches are accepted without playing anything.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::crypto;
+use crate::patterns;
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "hooks";
+
+pub const MAX_HOOKS: usize = 8;
+pub const MAX_RULES: usize = 8;
+
+// GitHub sends the whole repository with most events
+pub const MAX_BODY: usize = 32 * 1024;
+
+pub const GITHUB_EVENT: &str = "X-GitHub-Event";
+pub const GITHUB_SIGNATURE: &str = "X-Hub-Signature-256";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "adapter", rename_all = "snake_case")]
+pub enum Adapter {
+    // Signed with the secret of the GitHub webhook. The event name from
+    // `X-GitHub-Event` is available to the rules as `event`.
+    Github { secret: String },
+    // Alertmanager's webhook receiver, the rules see the notification with
+    // e.g. `status` and `commonLabels.severity`
+    Alertmanager,
+    // Any JSON payload
+    Generic,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    // Paths and the values they must have, strings also match numbers and
+    // booleans written the same way. Empty matches everything.
+    #[serde(default)]
+    pub when: BTreeMap<String, Value>,
+    // Name of a saved pattern
+    pub pattern: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hook {
+    #[serde(flatten)]
+    pub adapter: Adapter,
+    pub rules: Vec<Rule>,
+}
+
+// Hook names to hooks, names follow the rules of pattern names
+pub type Hooks = BTreeMap<String, Hook>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HookError {
+    InvalidName(String),
+    TooManyHooks,
+    TooManyRules(String),
+    SecretMissing(String),
+    InvalidPattern(String),
+    // Answered to the sender
+    Signature,
+    Payload(String),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::InvalidName(name) => write!(
+                f,
+                "the hook name `{}` must be 1 to {} letters, digits, `-` or `_`",
+                name,
+                patterns::MAX_NAME_LEN
+            ),
+            HookError::TooManyHooks => write!(f, "at most {} hooks can be saved", MAX_HOOKS),
+            HookError::TooManyRules(name) => {
+                write!(f, "{} has more than {} rules", name, MAX_RULES)
+            }
+            HookError::SecretMissing(name) => {
+                write!(f, "{} needs the secret of the GitHub webhook", name)
+            }
+            HookError::InvalidPattern(name) => {
+                write!(f, "`{}` is not a valid pattern name", name)
+            }
+            HookError::Signature => write!(f, "missing or invalid {}", GITHUB_SIGNATURE),
+            HookError::Payload(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+// The patterns need not be saved yet, a hook naming a missing one answers
+// 404 when it fires
+pub fn validate(hooks: &Hooks) -> Result<(), HookError> {
+    if hooks.len() > MAX_HOOKS {
+        return Err(HookError::TooManyHooks);
+    }
+    for (name, hook) in hooks {
+        if patterns::validate_name(name).is_err() {
+            return Err(HookError::InvalidName(name.clone()));
+        }
+        if hook.rules.len() > MAX_RULES {
+            return Err(HookError::TooManyRules(name.clone()));
+        }
+        if matches!(&hook.adapter, Adapter::Github { secret } if secret.is_empty()) {
+            return Err(HookError::SecretMissing(name.clone()));
+        }
+        for rule in &hook.rules {
+            if patterns::validate_name(&rule.pattern).is_err() {
+                return Err(HookError::InvalidPattern(rule.pattern.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Hook {
+    // The pattern to play for a delivery, `header` looks up request headers.
+    // `None` if no rule matches.
+    pub fn select<'a>(
+        &self,
+        header: impl Fn(&str) -> Option<&'a str>,
+        body: &[u8],
+    ) -> Result<Option<&str>, HookError> {
+        let payload = match &self.adapter {
+            Adapter::Github { secret } => {
+                verify_github(secret, header(GITHUB_SIGNATURE), body)?;
+                let mut payload = parse(body)?;
+                if let (Some(fields), Some(event)) = (payload.as_object_mut(), header(GITHUB_EVENT))
+                {
+                    fields.insert("event".into(), event.into());
+                }
+                payload
+            }
+            Adapter::Alertmanager => {
+                let payload = parse(body)?;
+                if !payload.get("alerts").is_some_and(Value::is_array) {
+                    return Err(HookError::Payload(
+                        "not an Alertmanager notification".into(),
+                    ));
+                }
+                payload
+            }
+            Adapter::Generic => parse(body)?,
+        };
+        Ok(self
+            .rules
+            .iter()
+            .find(|rule| {
+                rule.when.iter().all(|(path, expected)| {
+                    lookup(&payload, path).is_some_and(|v| value_matches(v, expected))
+                })
+            })
+            .map(|rule| rule.pattern.as_str()))
+    }
+}
+
+fn parse(body: &[u8]) -> Result<Value, HookError> {
+    serde_json::from_slice(body).map_err(|e| HookError::Payload(e.to_string()))
+}
+
+// GitHub signs the body with `sha256=<hex HMAC>`
+fn verify_github(secret: &str, signature: Option<&str>, body: &[u8]) -> Result<(), HookError> {
+    let signature = signature
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(crypto::from_hex)
+        .ok_or(HookError::Signature)?;
+    let expected = crypto::hmac_sha256(secret.as_bytes(), &[body]);
+    if !crypto::constant_time_eq(&signature, &expected) {
+        return Err(HookError::Signature);
+    }
+    Ok(())
+}
+
+// Follows a path of object keys and array indices separated by dots, with an
+// optional leading `$.`
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.strip_prefix("$.").unwrap_or(path);
+    path.split('.').try_fold(value, |value, key| match value {
+        Value::Object(fields) => fields.get(key),
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+fn value_matches(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Number(number), Value::String(expected)) => {
+            number.as_f64().is_some() && expected.parse::<f64>().ok() == number.as_f64()
+        }
+        (Value::Bool(value), Value::String(expected)) => {
+            expected == if *value { "true" } else { "false" }
+        }
+        _ => value == expected,
+    }
+}
+
+// The saved hooks, or none if they are not usable
+pub fn load(store: &dyn Store) -> Hooks {
+    let hooks: Hooks = storage::load(store, STORE_KEY).unwrap_or_default();
+    match validate(&hooks) {
+        Ok(()) => hooks,
+        Err(e) => {
+            log::warn!("Ignoring saved hooks: {}", e);
+            Hooks::new()
+        }
+    }
+}
+
+pub fn save(store: &dyn Store, hooks: &Hooks) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, hooks)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // The example of GitHub's documentation on validating deliveries
+    const SECRET: &str = "It's a Secret to Everybody";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn hook(adapter: Adapter, rules: &[(Value, &str)]) -> Hook {
+        Hook {
+            adapter,
+            rules: rules
+                .iter()
+                .map(|(when, pattern)| Rule {
+                    when: serde_json::from_value(when.clone()).unwrap(),
+                    pattern: (*pattern).into(),
+                })
+                .collect(),
+        }
+    }
+
+    fn github() -> Hook {
+        hook(
+            Adapter::Github {
+                secret: SECRET.into(),
+            },
+            &[
+                (json!({ "event": "ping" }), "ping"),
+                (
+                    json!({ "event": "deployment_status", "deployment_status.state": "success" }),
+                    "triple",
+                ),
+            ],
+        )
+    }
+
+    fn signed(body: &str) -> String {
+        format!(
+            "sha256={}",
+            crypto::to_hex(&crypto::hmac_sha256(SECRET.as_bytes(), &[body.as_bytes()]))
+        )
+    }
+
+    // Selects with the given GitHub headers
+    fn deliver<'a>(
+        hook: &'a Hook,
+        event: Option<&str>,
+        signature: Option<&str>,
+        body: &str,
+    ) -> Result<Option<&'a str>, HookError> {
+        hook.select(
+            |name| match name {
+                GITHUB_EVENT => event,
+                GITHUB_SIGNATURE => signature,
+                _ => None,
+            },
+            body.as_bytes(),
+        )
+    }
+
+    #[test]
+    fn verifies_github_signatures() {
+        let body = b"Hello, World!";
+        assert_eq!(verify_github(SECRET, Some(SIGNATURE), body), Ok(()));
+        assert_eq!(
+            verify_github(
+                SECRET,
+                Some(&SIGNATURE.to_uppercase().replace("SHA256=", "sha256=")),
+                body
+            ),
+            Ok(())
+        );
+        let wrong = SIGNATURE.replace("757107", "757108");
+        for signature in [
+            None,
+            Some(wrong.as_str()),
+            Some(&SIGNATURE[7..]),
+            Some("sha1=757107ea0eb2509fc211221cce984b8a37570b6d"),
+            Some("sha256=xyz"),
+        ] {
+            assert_eq!(
+                verify_github(SECRET, signature, body),
+                Err(HookError::Signature),
+                "{:?}",
+                signature
+            );
+        }
+        assert_eq!(
+            verify_github("another secret", Some(SIGNATURE), body),
+            Err(HookError::Signature)
+        );
+    }
+
+    #[test]
+    fn github_event_is_merged_into_the_payload() {
+        let hook = github();
+        let body = r#"{"zen": "Keep it logically awesome."}"#;
+        assert_eq!(
+            deliver(&hook, Some("ping"), Some(&signed(body)), body),
+            Ok(Some("ping"))
+        );
+        let body = r#"{"deployment_status": {"state": "success"}}"#;
+        assert_eq!(
+            deliver(&hook, Some("deployment_status"), Some(&signed(body)), body),
+            Ok(Some("triple"))
+        );
+        // The header wins over a field of the same name
+        let body = r#"{"event": "ping", "deployment_status": {"state": "failure"}}"#;
+        assert_eq!(
+            deliver(&hook, Some("deployment_status"), Some(&signed(body)), body),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn github_deliveries_are_signed() {
+        let hook = github();
+        let body = r#"{"zen": "Design for failure."}"#;
+        assert_eq!(
+            deliver(&hook, Some("ping"), None, body),
+            Err(HookError::Signature)
+        );
+        assert_eq!(
+            deliver(&hook, Some("ping"), Some(&signed("{}")), body),
+            Err(HookError::Signature)
+        );
+        // The signature is checked before the body is parsed
+        assert_eq!(
+            deliver(&hook, Some("ping"), None, "not json"),
+            Err(HookError::Signature)
+        );
+        assert!(matches!(
+            deliver(&hook, Some("ping"), Some(&signed("not json")), "not json"),
+            Err(HookError::Payload(_))
+        ));
+    }
+
+    #[test]
+    fn alertmanager_needs_alerts() {
+        let hook = hook(
+            Adapter::Alertmanager,
+            &[
+                (
+                    json!({ "status": "firing", "commonLabels.severity": "critical" }),
+                    "roll",
+                ),
+                (json!({ "status": "resolved" }), "single"),
+            ],
+        );
+        let firing =
+            r#"{"status": "firing", "commonLabels": {"severity": "critical"}, "alerts": []}"#;
+        assert_eq!(deliver(&hook, None, None, firing), Ok(Some("roll")));
+        let resolved = r#"{"status": "resolved", "alerts": [{"status": "resolved"}]}"#;
+        assert_eq!(deliver(&hook, None, None, resolved), Ok(Some("single")));
+        for body in [r#"{"status": "firing"}"#, r#"{"alerts": {}}"#, "[]"] {
+            assert!(
+                matches!(deliver(&hook, None, None, body), Err(HookError::Payload(_))),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn looks_up_paths() {
+        let payload = json!({
+            "alerts": [{ "labels": { "severity": "page" } }, { "status": "resolved" }],
+            "a.b": 1,
+            "count": 3,
+        });
+        assert_eq!(
+            lookup(&payload, "alerts.0.labels.severity"),
+            Some(&json!("page"))
+        );
+        assert_eq!(
+            lookup(&payload, "$.alerts.1.status"),
+            Some(&json!("resolved"))
+        );
+        assert_eq!(lookup(&payload, "count"), Some(&json!(3)));
+        for path in [
+            "alerts.2",
+            "alerts.x",
+            "alerts.-1",
+            "count.0",
+            "missing",
+            "a.b",
+            "",
+        ] {
+            assert_eq!(lookup(&payload, path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn strings_match_numbers_and_booleans() {
+        assert!(value_matches(&json!(3), &json!("3")));
+        assert!(value_matches(&json!(3.5), &json!("3.50")));
+        assert!(!value_matches(&json!(3), &json!("three")));
+        assert!(value_matches(&json!(true), &json!("true")));
+        assert!(!value_matches(&json!(false), &json!("true")));
+        assert!(!value_matches(&json!("3"), &json!(3)));
+        assert!(value_matches(&json!(null), &json!(null)));
+        assert!(value_matches(&json!({"a": 1}), &json!({"a": 1})));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let hook = hook(
+            Adapter::Generic,
+            &[
+                (json!({ "level": "5" }), "five"),
+                (json!({ "ok": "true" }), "ok"),
+                (json!({}), "fallback"),
+                (json!({ "ok": true }), "never"),
+            ],
+        );
+        assert_eq!(
+            deliver(&hook, None, None, r#"{"level": 5, "ok": true}"#),
+            Ok(Some("five"))
+        );
+        assert_eq!(
+            deliver(&hook, None, None, r#"{"ok": true}"#),
+            Ok(Some("ok"))
+        );
+        assert_eq!(deliver(&hook, None, None, "[1]"), Ok(Some("fallback")));
+        let strict = Hook {
+            rules: hook.rules[..2].to_vec(),
+            ..hook
+        };
+        assert_eq!(deliver(&strict, None, None, r#"{"ok": false}"#), Ok(None));
+    }
+
+    #[test]
+    fn validates_hooks() {
+        let mut hooks = Hooks::new();
+        hooks.insert("deploy".into(), github());
+        assert_eq!(validate(&hooks), Ok(()));
+        hooks.insert(
+            "unsigned".into(),
+            hook(
+                Adapter::Github {
+                    secret: String::new(),
+                },
+                &[],
+            ),
+        );
+        assert_eq!(
+            validate(&hooks),
+            Err(HookError::SecretMissing("unsigned".into()))
+        );
+        hooks.remove("unsigned");
+        hooks.insert("bad name".into(), hook(Adapter::Generic, &[]));
+        assert_eq!(
+            validate(&hooks),
+            Err(HookError::InvalidName("bad name".into()))
+        );
+    }
+}
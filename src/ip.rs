@@ -0,0 +1,424 @@
+// IP settings of the WiFi client: an address from DHCP, or a static one with
+// its network, gateway and DNS servers. Addresses are written the usual way,
+// like `192.168.1.20`, and read with a parser that says what is wrong with
+// them instead of guessing.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "ip";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum IpConfig {
+    #[default]
+    Dhcp,
+    Static(StaticIp),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticIp {
+    #[serde(with = "address")]
+    pub address: Ipv4Addr,
+    // Length of the network part of the address, 24 for 255.255.255.0
+    #[serde(default = "default_prefix")]
+    pub prefix: u8,
+    #[serde(with = "address")]
+    pub gateway: Ipv4Addr,
+    #[serde(
+        default,
+        with = "optional_address",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dns: Option<Ipv4Addr>,
+    #[serde(
+        default,
+        with = "optional_address",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+fn default_prefix() -> u8 {
+    24
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpError {
+    Empty,
+    // An address needs exactly four numbers
+    OctetCount(usize),
+    // The 1-based number that is not from 0 to 255
+    InvalidOctet(usize, String),
+    InvalidPrefix(String),
+    PrefixRange(u8),
+    // The address is the network or broadcast address of its network
+    HostAddress,
+    GatewayOutsideNetwork,
+    Unspecified(&'static str),
+}
+
+impl fmt::Display for IpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpError::Empty => write!(f, "the address is empty"),
+            IpError::OctetCount(count) => write!(
+                f,
+                "an address has 4 numbers separated by dots, not {}",
+                count
+            ),
+            IpError::InvalidOctet(idx, octet) => write!(
+                f,
+                "number {} of the address, `{}`, is not from 0 to 255",
+                idx, octet
+            ),
+            IpError::InvalidPrefix(prefix) => {
+                write!(f, "the prefix `{}` is not a number", prefix)
+            }
+            IpError::PrefixRange(prefix) => {
+                write!(f, "the prefix {} is not from 1 to 30", prefix)
+            }
+            IpError::HostAddress => write!(
+                f,
+                "the address is the network or broadcast address of its network"
+            ),
+            IpError::GatewayOutsideNetwork => {
+                write!(f, "the gateway is not in the network of the address")
+            }
+            IpError::Unspecified(field) => write!(f, "{} cannot be 0.0.0.0", field),
+        }
+    }
+}
+
+impl std::error::Error for IpError {}
+
+// Reads a dotted address like `192.168.1.20`
+pub fn parse_address(s: &str) -> Result<Ipv4Addr, IpError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(IpError::Empty);
+    }
+    let octets: Vec<&str> = s.split('.').collect();
+    if octets.len() != 4 {
+        return Err(IpError::OctetCount(octets.len()));
+    }
+    let mut address = [0; 4];
+    for (idx, octet) in octets.iter().enumerate() {
+        // No signs, spaces or empty numbers, and leading zeros are not
+        // allowed as they are octal for some programs
+        let valid = !octet.is_empty()
+            && octet.len() <= 3
+            && octet.bytes().all(|b| b.is_ascii_digit())
+            && (octet.len() == 1 || !octet.starts_with('0'));
+        address[idx] = match octet.parse::<u8>() {
+            Ok(value) if valid => value,
+            _ => return Err(IpError::InvalidOctet(idx + 1, octet.to_string())),
+        };
+    }
+    Ok(Ipv4Addr::from(address))
+}
+
+// Reads an address with an optional prefix, like `192.168.1.20/24`
+pub fn parse_cidr(s: &str) -> Result<(Ipv4Addr, Option<u8>), IpError> {
+    match s.trim().split_once('/') {
+        None => Ok((parse_address(s)?, None)),
+        Some((address, prefix)) => {
+            let valid = (1..=2).contains(&prefix.len())
+                && prefix.bytes().all(|b| b.is_ascii_digit())
+                && !prefix.starts_with('0');
+            match prefix.parse::<u8>() {
+                Ok(prefix) if valid => Ok((parse_address(address)?, Some(prefix))),
+                _ => Err(IpError::InvalidPrefix(prefix.to_owned())),
+            }
+        }
+    }
+}
+
+impl StaticIp {
+    pub fn validate(&self) -> Result<(), IpError> {
+        if !(1..=30).contains(&self.prefix) {
+            return Err(IpError::PrefixRange(self.prefix));
+        }
+        if self.address.is_unspecified() {
+            return Err(IpError::Unspecified("the address"));
+        }
+        if self.gateway.is_unspecified() {
+            return Err(IpError::Unspecified("the gateway"));
+        }
+        for dns in [self.dns, self.secondary_dns].into_iter().flatten() {
+            if dns.is_unspecified() {
+                return Err(IpError::Unspecified("a DNS server"));
+            }
+        }
+
+        let mask = self.netmask_bits();
+        let host = u32::from(self.address) & !mask;
+        if host == 0 || host == !mask {
+            return Err(IpError::HostAddress);
+        }
+        if u32::from(self.gateway) & mask != u32::from(self.address) & mask {
+            return Err(IpError::GatewayOutsideNetwork);
+        }
+        Ok(())
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.netmask_bits())
+    }
+
+    fn netmask_bits(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix.min(32) as u32)
+            .unwrap_or(0)
+    }
+}
+
+impl IpConfig {
+    pub fn validate(&self) -> Result<(), IpError> {
+        match self {
+            IpConfig::Dhcp => Ok(()),
+            IpConfig::Static(settings) => settings.validate(),
+        }
+    }
+}
+
+impl fmt::Display for IpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpConfig::Dhcp => write!(f, "DHCP"),
+            IpConfig::Static(settings) => write!(
+                f,
+                "{}/{} via {}",
+                settings.address, settings.prefix, settings.gateway
+            ),
+        }
+    }
+}
+
+// The saved settings, if there are any that are usable
+pub fn load(store: &dyn Store) -> Option<IpConfig> {
+    let config: IpConfig = storage::load(store, STORE_KEY)?;
+    match config.validate() {
+        Ok(()) => Some(config),
+        Err(e) => {
+            log::warn!("Ignoring saved IP settings: {}", e);
+            None
+        }
+    }
+}
+
+// Addresses in JSON go through `parse_address` for its error messages
+mod address {
+    use std::net::Ipv4Addr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(address: &Ipv4Addr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(address)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Ipv4Addr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        super::parse_address(&s).map_err(D::Error::custom)
+    }
+}
+
+mod optional_address {
+    use std::net::Ipv4Addr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        address: &Option<Ipv4Addr>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match address {
+            Some(address) => super::address::serialize(address, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Ipv4Addr>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::address")] Ipv4Addr);
+
+        let address: Option<Wrapper> = Option::deserialize(deserializer)?;
+        Ok(address.map(|Wrapper(address)| address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn static_ip(address: &str, prefix: u8, gateway: &str) -> StaticIp {
+        StaticIp {
+            address: parse_address(address).unwrap(),
+            prefix,
+            gateway: parse_address(gateway).unwrap(),
+            dns: None,
+            secondary_dns: None,
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            parse_address(" 192.168.1.20\n"),
+            Ok(Ipv4Addr::new(192, 168, 1, 20))
+        );
+        assert_eq!(parse_address("0.0.0.0"), Ok(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(parse_address("255.255.255.255"), Ok(Ipv4Addr::BROADCAST));
+        assert_eq!(parse_address(""), Err(IpError::Empty));
+        assert_eq!(parse_address("192.168.1"), Err(IpError::OctetCount(3)));
+        assert_eq!(parse_address("192.168.1.2.3"), Err(IpError::OctetCount(5)));
+        assert_eq!(
+            parse_address("192.168.1.20/24"),
+            Err(IpError::InvalidOctet(4, "20/24".into()))
+        );
+        for (address, idx, octet) in [
+            ("192.168.01.20", 3, "01"),
+            ("192.168.1.256", 4, "256"),
+            ("1000.1.1.1", 1, "1000"),
+            ("192..1.1", 2, ""),
+            ("192.168.+1.1", 3, "+1"),
+            ("192.168. 1.1", 3, " 1"),
+            ("a.b.c.d", 1, "a"),
+        ] {
+            assert_eq!(
+                parse_address(address),
+                Err(IpError::InvalidOctet(idx, octet.into())),
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        assert_eq!(parse_cidr("10.0.0.5"), Ok((address, None)));
+        assert_eq!(parse_cidr("10.0.0.5/8"), Ok((address, Some(8))));
+        assert_eq!(parse_cidr(" 10.0.0.5/32 "), Ok((address, Some(32))));
+        for prefix in ["0", "08", "", "100", "-1", "x"] {
+            assert_eq!(
+                parse_cidr(&format!("10.0.0.5/{}", prefix)),
+                Err(IpError::InvalidPrefix(prefix.into())),
+                "{}",
+                prefix
+            );
+        }
+        assert_eq!(parse_cidr("10.0.0/24"), Err(IpError::OctetCount(3)));
+    }
+
+    #[test]
+    fn validates_prefix_range() {
+        for prefix in [0, 31, 32] {
+            assert_eq!(
+                static_ip("10.0.0.5", prefix, "10.0.0.1").validate(),
+                Err(IpError::PrefixRange(prefix))
+            );
+        }
+        assert_eq!(static_ip("10.0.0.5", 1, "10.0.0.1").validate(), Ok(()));
+        assert_eq!(static_ip("10.0.0.1", 30, "10.0.0.2").validate(), Ok(()));
+        assert_eq!(
+            static_ip("10.0.0.5", 30, "10.0.0.6").netmask(),
+            Ipv4Addr::new(255, 255, 255, 252)
+        );
+    }
+
+    #[test]
+    fn address_is_a_host_of_its_network() {
+        assert_eq!(
+            static_ip("192.168.1.0", 24, "192.168.1.1").validate(),
+            Err(IpError::HostAddress)
+        );
+        assert_eq!(
+            static_ip("192.168.1.255", 24, "192.168.1.1").validate(),
+            Err(IpError::HostAddress)
+        );
+        // Both are hosts of a larger network
+        assert_eq!(
+            static_ip("192.168.1.0", 23, "192.168.0.1").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            static_ip("192.168.0.255", 23, "192.168.0.1").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            static_ip("0.0.0.0", 24, "0.0.0.1").validate(),
+            Err(IpError::Unspecified("the address"))
+        );
+    }
+
+    #[test]
+    fn gateway_is_in_the_network() {
+        assert_eq!(
+            static_ip("192.168.1.20", 24, "192.168.2.1").validate(),
+            Err(IpError::GatewayOutsideNetwork)
+        );
+        assert_eq!(
+            static_ip("192.168.1.20", 16, "192.168.2.1").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            static_ip("192.168.1.20", 24, "0.0.0.0").validate(),
+            Err(IpError::Unspecified("the gateway"))
+        );
+        let mut settings = static_ip("192.168.1.20", 24, "192.168.1.1");
+        settings.secondary_dns = Some(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            settings.validate(),
+            Err(IpError::Unspecified("a DNS server"))
+        );
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        let dhcp = serde_json::to_value(IpConfig::Dhcp).unwrap();
+        assert_eq!(dhcp, serde_json::json!({ "mode": "dhcp" }));
+        assert_eq!(
+            serde_json::from_value::<IpConfig>(dhcp).unwrap(),
+            IpConfig::Dhcp
+        );
+
+        let mut settings = static_ip("192.168.1.20", 24, "192.168.1.1");
+        settings.dns = Some(Ipv4Addr::new(9, 9, 9, 9));
+        let config = IpConfig::Static(settings);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "mode": "static",
+                "address": "192.168.1.20",
+                "prefix": 24,
+                "gateway": "192.168.1.1",
+                "dns": "9.9.9.9",
+            })
+        );
+        assert_eq!(serde_json::from_value::<IpConfig>(json).unwrap(), config);
+
+        // The prefix defaults to 24 and addresses are read strictly
+        let config: IpConfig = serde_json::from_str(
+            r#"{"mode": "static", "address": "10.0.0.5", "gateway": "10.0.0.1"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config,
+            IpConfig::Static(StaticIp { prefix: 24, .. })
+        ));
+        let e = serde_json::from_str::<IpConfig>(
+            r#"{"mode": "static", "address": "10.0.0.05", "gateway": "10.0.0.1"}"#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("`05`"), "{}", e);
+    }
+}
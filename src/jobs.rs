@@ -0,0 +1,446 @@
+// Bookkeeping of queued, running and finished strike sequences.
+//
+// Active jobs are bounded by the player queue and finished jobs are kept in a
+// ring of fixed size, so the table never grows past a known number of entries.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::player::Clock;
+use crate::sequence::StrikeSequence;
+
+pub const HISTORY_CAPACITY: usize = 16;
+
+pub type JobId = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_done(self) -> bool {
+        matches!(
+            self,
+            JobState::Finished | JobState::Cancelled | JobState::Failed
+        )
+    }
+}
+
+// Timestamps are milliseconds since boot
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub state: JobState,
+    pub steps: usize,
+    pub duration_ms: u64,
+    pub queued_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    cancel_requested: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelError {
+    NotFound,
+    AlreadyDone(JobState),
+}
+
+impl std::fmt::Display for CancelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancelError::NotFound => write!(f, "no such job"),
+            CancelError::AlreadyDone(_) => write!(f, "the job has already ended"),
+        }
+    }
+}
+
+impl std::error::Error for CancelError {}
+
+pub struct JobTable {
+    jobs: Mutex<VecDeque<JobRecord>>,
+    clock: Box<dyn Clock + Send + Sync>,
+    history: usize,
+    // Told about jobs starting and ending, see `watch`
+    watchers: Mutex<Vec<Sender<JobRecord>>>,
+}
+
+impl JobTable {
+    pub fn new(clock: impl Clock + Send + Sync + 'static, history: usize) -> Self {
+        Self {
+            jobs: Mutex::new(VecDeque::new()),
+            clock: Box::new(clock),
+            history,
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Receives every job as it starts and as it ends, until dropped
+    pub fn watch(&self) -> Receiver<JobRecord> {
+        let (sender, receiver) = channel();
+        self.watchers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn notify(&self, job: &JobRecord) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.send(job.clone()).is_ok());
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now().as_millis() as u64
+    }
+
+    pub fn insert(&self, id: JobId, sequence: &StrikeSequence) {
+        let record = JobRecord {
+            id,
+            state: JobState::Queued,
+            steps: sequence.steps().len(),
+            duration_ms: sequence.duration().as_millis() as u64,
+            queued_at: self.now(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            cancel_requested: false,
+        };
+        self.jobs.lock().unwrap().push_back(record);
+    }
+
+    // Forget a job that never made it into the player queue
+    pub fn remove(&self, id: JobId) {
+        self.jobs.lock().unwrap().retain(|job| job.id != id);
+    }
+
+    // Marks the job as running, returns false if it was cancelled while queued
+    pub fn start(&self, id: JobId) -> bool {
+        let now = self.now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let started = match jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) if job.state == JobState::Queued => {
+                job.state = JobState::Running;
+                job.started_at = Some(now);
+                job.clone()
+            }
+            _ => return false,
+        };
+        drop(jobs);
+        self.notify(&started);
+        true
+    }
+
+    pub fn cancel_requested(&self, id: JobId) -> bool {
+        !self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|job| job.id == id && !job.cancel_requested)
+    }
+
+    pub fn finish(&self, id: JobId, state: JobState, error: Option<String>) {
+        let now = self.now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished = jobs.iter_mut().find(|job| job.id == id).map(|job| {
+            job.state = state;
+            job.finished_at = Some(now);
+            job.error = error;
+            job.clone()
+        });
+        self.prune(&mut jobs);
+        drop(jobs);
+        if let Some(job) = finished {
+            self.notify(&job);
+        }
+    }
+
+    pub fn get(&self, id: JobId) -> Option<JobRecord> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<JobRecord> {
+        self.jobs.lock().unwrap().iter().cloned().collect()
+    }
+
+    // The job the player is on, if any
+    pub fn running(&self) -> Option<JobId> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.state == JobState::Running)
+            .map(|job| job.id)
+    }
+
+    // Number of jobs waiting for the player
+    pub fn queued(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| job.state == JobState::Queued)
+            .count()
+    }
+
+    // Queued jobs are cancelled right away, a running job stops at the next
+    // step boundary once the player notices the request
+    pub fn cancel(&self, id: JobId) -> Result<JobRecord, CancelError> {
+        let now = self.now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(CancelError::NotFound)?;
+        if job.state.is_done() {
+            return Err(CancelError::AlreadyDone(job.state));
+        }
+        Self::cancel_record(job, now);
+        let job = job.clone();
+        self.prune(&mut jobs);
+        drop(jobs);
+        if job.state.is_done() {
+            self.notify(&job);
+        }
+        Ok(job)
+    }
+
+    pub fn cancel_all(&self) -> Vec<JobId> {
+        let now = self.now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut ended = Vec::new();
+        let cancelled = jobs
+            .iter_mut()
+            .filter(|job| !job.state.is_done())
+            .map(|job| {
+                Self::cancel_record(job, now);
+                if job.state.is_done() {
+                    ended.push(job.clone());
+                }
+                job.id
+            })
+            .collect();
+        self.prune(&mut jobs);
+        drop(jobs);
+        for job in &ended {
+            self.notify(job);
+        }
+        cancelled
+    }
+
+    fn cancel_record(job: &mut JobRecord, now: u64) {
+        job.cancel_requested = true;
+        if job.state == JobState::Queued {
+            job.state = JobState::Cancelled;
+            job.finished_at = Some(now);
+        }
+    }
+
+    fn prune(&self, jobs: &mut VecDeque<JobRecord>) {
+        let mut done = jobs.iter().filter(|job| job.state.is_done()).count();
+        while done > self.history {
+            if let Some(idx) = jobs.iter().position(|job| job.state.is_done()) {
+                jobs.remove(idx);
+            }
+            done -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::sequence;
+
+    #[derive(Clone, Default)]
+    struct FakeClock(Arc<Mutex<Duration>>);
+
+    impl FakeClock {
+        fn advance(&self, ms: u64) {
+            *self.0.lock().unwrap() += Duration::from_millis(ms);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    fn table(history: usize) -> (JobTable, FakeClock) {
+        let clock = FakeClock::default();
+        (JobTable::new(clock.clone(), history), clock)
+    }
+
+    fn insert(table: &JobTable, id: JobId) {
+        table.insert(id, &sequence::parse("90,200,30").unwrap());
+    }
+
+    fn ids(table: &JobTable) -> Vec<JobId> {
+        table.list().iter().map(|job| job.id).collect()
+    }
+
+    #[test]
+    fn records_the_life_of_a_job() {
+        let (table, clock) = table(HISTORY_CAPACITY);
+        let watcher = table.watch();
+        clock.advance(10);
+        insert(&table, 1);
+        let job = table.get(1).unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        assert_eq!((job.steps, job.duration_ms, job.queued_at), (3, 200, 10));
+        assert_eq!(table.queued(), 1);
+
+        clock.advance(5);
+        assert!(table.start(1));
+        assert!(!table.start(1));
+        assert_eq!(table.running(), Some(1));
+        assert_eq!(table.queued(), 0);
+        assert_eq!(watcher.try_recv().unwrap().started_at, Some(15));
+
+        clock.advance(200);
+        table.finish(1, JobState::Failed, Some("servo".into()));
+        let job = watcher.try_recv().unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.finished_at, Some(215));
+        assert_eq!(job.error.as_deref(), Some("servo"));
+        assert_eq!(table.get(1), Some(job));
+        assert_eq!(table.running(), None);
+    }
+
+    #[test]
+    fn cancels_queued_jobs_right_away() {
+        let (table, clock) = table(HISTORY_CAPACITY);
+        let watcher = table.watch();
+        insert(&table, 1);
+        clock.advance(7);
+        let job = table.cancel(1).unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert_eq!(job.finished_at, Some(7));
+        assert_eq!(watcher.try_recv().unwrap(), job);
+        assert!(table.cancel_requested(1));
+        // The player skips it
+        assert!(!table.start(1));
+        assert_eq!(
+            table.cancel(1),
+            Err(CancelError::AlreadyDone(JobState::Cancelled))
+        );
+        assert_eq!(table.cancel(2), Err(CancelError::NotFound));
+    }
+
+    #[test]
+    fn running_jobs_stop_when_the_player_notices() {
+        let (table, _) = table(HISTORY_CAPACITY);
+        insert(&table, 1);
+        table.start(1);
+        let watcher = table.watch();
+        assert!(!table.cancel_requested(1));
+        let job = table.cancel(1).unwrap();
+        assert_eq!(job.state, JobState::Running);
+        assert!(table.cancel_requested(1));
+        // Only the end of the job is announced
+        assert!(watcher.try_recv().is_err());
+        table.finish(1, JobState::Cancelled, None);
+        assert_eq!(watcher.try_recv().unwrap().state, JobState::Cancelled);
+        // Jobs the table does not know about are stopped
+        assert!(table.cancel_requested(2));
+    }
+
+    #[test]
+    fn cancel_all_skips_ended_jobs() {
+        let (table, _) = table(HISTORY_CAPACITY);
+        for id in 1..=4 {
+            insert(&table, id);
+        }
+        table.start(1);
+        table.finish(1, JobState::Finished, None);
+        table.start(2);
+        let watcher = table.watch();
+        assert_eq!(table.cancel_all(), [2, 3, 4]);
+        let states: Vec<JobState> = table.list().iter().map(|job| job.state).collect();
+        assert_eq!(
+            states,
+            [
+                JobState::Finished,
+                JobState::Running,
+                JobState::Cancelled,
+                JobState::Cancelled
+            ]
+        );
+        assert!(table.cancel_requested(2));
+        // Only the queued jobs ended
+        let ended: Vec<JobId> = watcher.try_iter().map(|job| job.id).collect();
+        assert_eq!(ended, [3, 4]);
+        assert!(table.cancel_all().contains(&2));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_ended_jobs() {
+        let (table, _) = table(HISTORY_CAPACITY);
+        let count = HISTORY_CAPACITY as JobId + 3;
+        for id in 1..=count {
+            insert(&table, id);
+            table.start(id);
+            table.finish(id, JobState::Finished, None);
+        }
+        assert_eq!(ids(&table), (4..=count).collect::<Vec<_>>());
+        assert_eq!(table.get(3), None);
+        assert_eq!(table.get(4).unwrap().state, JobState::Finished);
+        assert_eq!(table.cancel(1), Err(CancelError::NotFound));
+    }
+
+    #[test]
+    fn active_jobs_are_not_evicted() {
+        let (table, _) = table(2);
+        insert(&table, 1);
+        insert(&table, 2);
+        table.start(1);
+        for id in 3..=6 {
+            insert(&table, id);
+            table.cancel(id).unwrap();
+        }
+        // The oldest cancelled jobs made room, the running and queued stay
+        assert_eq!(ids(&table), [1, 2, 5, 6]);
+        assert_eq!(table.get(1).unwrap().state, JobState::Running);
+        assert_eq!(table.get(2).unwrap().state, JobState::Queued);
+
+        // Ended jobs leave in the order they were queued
+        table.finish(1, JobState::Finished, None);
+        assert_eq!(ids(&table), [2, 5, 6]);
+        table.cancel_all();
+        assert_eq!(ids(&table), [5, 6]);
+        assert_eq!(table.get(2), None);
+    }
+
+    #[test]
+    fn removed_jobs_are_forgotten() {
+        let (table, _) = table(HISTORY_CAPACITY);
+        insert(&table, 1);
+        table.remove(1);
+        assert_eq!(table.get(1), None);
+        assert!(table.list().is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+// Hardware independent parts of the gong firmware, kept free of esp-idf so
+// they can be built and tested on the host.
+
+pub mod api;
+pub mod auth;
+pub mod calibration;
+pub mod cron;
+pub mod crypto;
+pub mod discovery;
+pub mod dns;
+pub mod hal;
+pub mod home_assistant;
+pub mod hooks;
+pub mod ip;
+pub mod jobs;
+pub mod link;
+pub mod metrics;
+pub mod midi;
+pub mod mqtt;
+pub mod patterns;
+pub mod player;
+pub mod policy;
+pub mod portal;
+pub mod profile;
+pub mod rhythm;
+pub mod schedule;
+pub mod sequence;
+pub mod signing;
+pub mod storage;
+pub mod strike;
+pub mod tls;
+pub mod tz;
+pub mod wifi;
+
+// Board drivers behind the `hal` traits
+#[cfg(feature = "esp")]
+pub mod esp;
+
+// WiFi, setup portal and HTTP server on the board
+#[cfg(feature = "esp")]
+pub mod network;
+
+// Recording stand-ins for the board drivers, for running on the host
+#[cfg(feature = "sim")]
+pub mod sim;
+
+// MQTT over plain TCP for the simulator
+#[cfg(feature = "sim")]
+pub mod mqtt_client;
@@ -0,0 +1,191 @@
+// State of the WiFi connection, shown on the status LED, and the timing of
+// reconnection attempts. The LED can also be taken over as a plain light.
+
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+use serde::Serialize;
+
+use crate::hal::{Color, StatusIndicator};
+
+// Half a period of the blinking in the `Failed` state
+pub const BLINK: Duration = Duration::from_millis(500);
+
+const STACK_SIZE: usize = 4 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    // Yellow
+    Connecting,
+    // Green
+    Up,
+    // Blinking red, waiting to try again
+    Failed,
+    // Blue, serving the setup portal
+    Setup,
+}
+
+impl LinkState {
+    // The LED colour, `lit` alternates while blinking
+    pub fn color(self, lit: bool) -> Color {
+        match self {
+            LinkState::Connecting => Color::YELLOW,
+            LinkState::Up => Color::GREEN,
+            LinkState::Failed if lit => Color::RED,
+            LinkState::Failed => Color::OFF,
+            LinkState::Setup => Color::BLUE,
+        }
+    }
+}
+
+// The network the gong is connected to while `Up`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Connection {
+    pub ssid: String,
+    pub ip: Ipv4Addr,
+}
+
+// The current state, shared between the network code setting it and the LED
+// thread showing it
+pub struct LinkStatus {
+    state: Mutex<LinkState>,
+    changed: Condvar,
+    connection: Mutex<Option<Connection>>,
+    // Times the gong was up again after losing the connection
+    reconnects: AtomicU32,
+    was_up: AtomicBool,
+    // Shown instead of the state while set
+    light: Mutex<Option<Color>>,
+}
+
+impl LinkStatus {
+    pub fn new(state: LinkState) -> Self {
+        Self {
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            connection: Mutex::new(None),
+            reconnects: AtomicU32::new(0),
+            was_up: AtomicBool::new(false),
+            light: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> LinkState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn connection(&self) -> Option<Connection> {
+        self.connection.lock().unwrap().clone()
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn light(&self) -> Option<Color> {
+        *self.light.lock().unwrap()
+    }
+
+    // Shows `color` on the LED until it is set back to `None`
+    pub fn set_light(&self, color: Option<Color>) {
+        *self.light.lock().unwrap() = color;
+        // Under the state lock, so the LED thread cannot miss it
+        let _state = self.state.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    // Changes to `Up` with the network connected to
+    pub fn set_up(&self, connection: Connection) {
+        *self.connection.lock().unwrap() = Some(connection);
+        self.set(LinkState::Up);
+    }
+
+    pub fn set(&self, state: LinkState) {
+        if state != LinkState::Up {
+            *self.connection.lock().unwrap() = None;
+        }
+        let mut current = self.state.lock().unwrap();
+        if *current != state {
+            if state == LinkState::Up && self.was_up.swap(true, Ordering::Relaxed) {
+                self.reconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            info!("WiFi {:?}", state);
+            *current = state;
+            self.changed.notify_all();
+        }
+    }
+}
+
+// Starts a thread showing the link state on the indicator, beginning with
+// `Connecting`
+pub fn spawn_indicator<I>(mut indicator: I) -> anyhow::Result<Arc<LinkStatus>>
+where
+    I: StatusIndicator + Send + 'static,
+{
+    let status = Arc::new(LinkStatus::new(LinkState::Connecting));
+    let shown = status.clone();
+    thread::Builder::new()
+        .name("led".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut lit = true;
+            let mut state = shown.state.lock().unwrap();
+            loop {
+                let color = shown.light().unwrap_or_else(|| state.color(lit));
+                if let Err(e) = indicator.set_color(color) {
+                    warn!("Could not set the LED: {:?}", e);
+                }
+                state = if *state == LinkState::Failed {
+                    lit = !lit;
+                    shown.changed.wait_timeout(state, BLINK).unwrap().0
+                } else {
+                    lit = true;
+                    shown.changed.wait(state).unwrap()
+                };
+            }
+        })?;
+    Ok(status)
+}
+
+// Exponentially growing delays between attempts, up to a maximum
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+            attempts: 0,
+        }
+    }
+
+    // Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    // Failed attempts since the last success
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.attempts = 0;
+    }
+}
@@ -1,50 +1,45 @@
-use std::{
-    str::from_utf8,
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
-};
+use std::sync::{Arc, Mutex};
 
+use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::{
     ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
-    peripheral::Peripheral,
     prelude::Peripherals,
+    units::*,
 };
-use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    http::server::EspHttpServer,
-    nvs::{EspDefaultNvsPartition, EspNvsPartition, NvsDefault},
-    ping::EspPing,
-    timer::{EspTaskTimerService, EspTimerService, Task},
-    wifi::{AsyncWifi, EspWifi},
-};
-use esp_idf_svc::{
-    hal::units::*,
-    netif::{EspNetif, NetifConfiguration},
-};
-
-use esp_idf_svc::{
-    http::Method::Post,
-    wifi::{AuthMethod, ClientConfiguration, Configuration},
-};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::timer::EspTaskTimerService;
 use log::*;
 
 use ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriver;
 
-use esp_idf_svc::ipv4::{
-    ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
-    Configuration as IpConfiguration, Ipv4Addr, Mask, Subnet,
-};
-
+use gong::api::{Api, ROUTES};
+use gong::auth;
+use gong::calibration;
+use gong::discovery;
+use gong::esp::{EspSystem, LedcActuator, NvsStore, Ws2812Indicator};
+use gong::hal::ServoMotor;
+use gong::ip::{self, IpConfig, StaticIp};
+use gong::link;
+use gong::mqtt::{self, Bridge};
+use gong::network;
+use gong::player::{self, Player, SystemClock, QUEUE_CAPACITY};
+use gong::schedule;
+use gong::storage::Store;
+use gong::tls;
+use gong::tz;
+use gong::wifi::{self, WifiSettings};
+
+// Defaults until WiFi settings are saved in the setup portal, all optional.
 // Set these env variables in a config file not commited to git
 // e.g. ~/.cargo/config.toml
-const SSID: &str = env!("ESP32_WIFI_SSID");
-const PASS: &str = env!("ESP32_WIFI_PWD");
-const STATIC_IP: &str = env!("ESP32_STATIC_IP");
-const GATEWAY_IP: &str = env!("ESP32_GATEWAY_IP");
-
-const YELLOW: [u8; 3] = [120, 120, 0];
-const GREEN: [u8; 3] = [120, 0, 10];
+const SSID: Option<&str> = option_env!("ESP32_WIFI_SSID");
+const PASS: Option<&str> = option_env!("ESP32_WIFI_PWD");
+// The static address may have a prefix, like 192.168.1.20/24
+const STATIC_IP: Option<&str> = option_env!("ESP32_STATIC_IP");
+const GATEWAY_IP: Option<&str> = option_env!("ESP32_GATEWAY_IP");
+// The first admin token, saved on the first boot. Without one there is no
+// way to create tokens.
+const ADMIN_TOKEN: Option<&str> = option_env!("ESP32_ADMIN_TOKEN");
 
 fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -54,29 +49,30 @@ fn main() {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    // Set up the LED
-    let mut ws2812 = Ws2812Esp32RmtDriver::new(0, 8).unwrap();
-
-    // The LED glows yellow while WiFi is connecting
-    ws2812.write(&YELLOW).unwrap();
+    // Set up the LED, it shows the WiFi state from now on: yellow while
+    // connecting, green when connected, blinking red while waiting to try
+    // again and blue in setup mode
+    let link =
+        link::spawn_indicator(Ws2812Indicator(Ws2812Esp32RmtDriver::new(0, 8).unwrap())).unwrap();
 
-    // Set up WiFi
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take().unwrap();
     let timer_service = EspTaskTimerService::new().unwrap();
-    let _wifi = wifi(
-        peripherals.modem,
-        sysloop,
-        Some(EspDefaultNvsPartition::take().unwrap()),
-        timer_service,
-    )
-    .unwrap();
-
-    // Then the LED turns green
-    ws2812.write(&GREEN).unwrap();
-
-    // Set up the server to recive POST requests
-    let mut server = EspHttpServer::new(&Default::default()).unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
+    // Settings changed over HTTP are kept in NVS
+    let store: Arc<dyn Store> = Arc::new(NvsStore::new(nvs.clone()).unwrap());
+    match ADMIN_TOKEN {
+        Some(token) => {
+            if let Err(e) = auth::provision_admin(&*store, token) {
+                error!("Invalid ESP32_ADMIN_TOKEN: {:?}", e);
+            }
+        }
+        None if !auth::load(&*store).has_admin() => {
+            warn!("No admin token, build with ESP32_ADMIN_TOKEN to create tokens")
+        }
+        None => {}
+    }
 
     // Set up the servo motor
     // the servo code is adapted from
@@ -89,147 +85,147 @@ fn main() {
             .resolution(esp_idf_svc::hal::ledc::Resolution::Bits14),
     )
     .unwrap();
-    let servo = Arc::new(Mutex::new(
-        LedcDriver::new(
-            peripherals.ledc.channel3,
-            servo_driver,
-            peripherals.pins.gpio3,
-        )
-        .unwrap(),
+    let calibration = Arc::new(Mutex::new(calibration::load(&*store)));
+    let servo = ServoMotor::new(
+        LedcActuator(
+            LedcDriver::new(
+                peripherals.ledc.channel3,
+                servo_driver,
+                peripherals.pins.gpio3,
+            )
+            .unwrap(),
+        ),
+        calibration.clone(),
+    );
+
+    // The player thread owns the servo, requests only queue sequences for it
+    let player =
+        Arc::new(player::spawn(Player::new(servo, SystemClock::new()), QUEUE_CAPACITY).unwrap());
+
+    let api = Arc::new(Api::new(
+        player,
+        calibration,
+        store.clone(),
+        Arc::new(EspSystem),
+        link.clone(),
     ));
 
-    let max_duty = servo.lock().unwrap().get_max_duty();
-
-    let min = max_duty / 40;
-    let max = max_duty / 8;
-
-    fn interpolate(angle: u32, min: u32, max: u32) -> u32 {
-        angle * (max - min) / 180 + min
-    }
+    // Set up WiFi
+    // Networks saved in the setup portal or over HTTP win over the compiled
+    // in one
+    let networks = {
+        let store = store.clone();
+        move || {
+            let networks = wifi::load(&*store);
+            if networks.is_empty() {
+                default_wifi_settings().into_iter().collect()
+            } else {
+                networks
+            }
+        }
+    };
+    let ip = ip::load(&*store)
+        .or_else(default_ip_config)
+        .unwrap_or_default();
+    info!("IP configuration: {}", ip);
+    let wifi = network::wifi(
+        peripherals.modem,
+        sysloop.clone(),
+        Some(nvs),
+        timer_service,
+        &ip,
+    )
+    .unwrap();
 
-    server
-        .fn_handler("/servo", Post, move |mut req| {
-            let mut buffer = [0_u8; 1024];
-            let bytes_read = req.read(&mut buffer).unwrap();
-            let angle_string = from_utf8(&buffer[0..bytes_read]).unwrap();
-
-            // Parse the request of the form ({angle},{pause},)*{angle}
-            let times_angles: Vec<u32> = angle_string
-                .split(",")
-                .map(|s| s.parse::<u32>().unwrap())
-                .collect();
-            servo
-                .lock()
-                .unwrap()
-                .set_duty(interpolate(times_angles[0] as u32, min, max))
-                .unwrap();
-            info!("Set servo to {}", times_angles[0]);
-            for i in 0..(times_angles.len() - 1) / 2 {
-                let wait_time = times_angles[i * 2 + 1];
-                info!("Wait {}", wait_time);
-                sleep(Duration::from_millis(wait_time as u64));
-                let servo_angle = times_angles[i * 2 + 2];
-                info!("Set servo to {}", servo_angle);
-                servo
-                    .lock()
-                    .unwrap()
-                    .set_duty(interpolate(servo_angle as u32, min, max))
-                    .unwrap();
+    // Without a network the gong opens its own for entering the settings, it
+    // restarts once they are saved. Otherwise the supervisor keeps it
+    // connected and serves the API whenever it is.
+    let result = if networks().is_empty() {
+        info!("No WiFi networks yet");
+        network::run_setup_portal(wifi, &link, store, None)
+    } else {
+        // Without a usable certificate the API is served over HTTP, where
+        // the TLS settings can be fixed
+        let discovery = discovery::load(&*store);
+        let certificate = network::server_certificate(&*store, &discovery.hostname)
+            .map_err(|e| error!("Could not set up HTTPS: {:?}", e))
+            .ok()
+            .flatten();
+        if certificate.is_some() && tls::load(&*store).redirect {
+            if let Err(e) = network::redirect_to_https() {
+                error!("Could not redirect HTTP: {:?}", e);
+            }
+        }
+        // Not being found by name is no reason to stay offline
+        let _mdns = network::advertise(&discovery, certificate.is_some())
+            .map_err(|e| error!("Could not start mDNS: {:?}", e))
+            .ok();
+        // The client keeps trying until the WiFi is up
+        let mqtt = mqtt::load(&*store);
+        if mqtt.enabled {
+            let bridge = Arc::new(Bridge::new(api.clone(), &mqtt));
+            if let Err(e) = network::mqtt(&mqtt, bridge) {
+                error!("Could not start MQTT: {:?}", e);
+            }
+        }
+        if let Err(e) = schedule::spawn(api.clone()) {
+            error!("Could not start the schedules: {:?}", e);
+        }
+        // Started on the first connection and kept running from then on
+        let time = tz::load(&*store);
+        let mut sntp = None;
+        network::supervise(wifi, &sysloop, networks, &link, store, || {
+            if sntp.is_none() {
+                sntp = network::sync_time(&time)
+                    .map_err(|e| error!("Could not start SNTP: {:?}", e))
+                    .ok();
             }
-            Ok(())
+            let api = api.clone();
+            network::serve(ROUTES, certificate, move |req| api.handle(req))
         })
-        .unwrap();
-
-    loop {
-        sleep(Duration::from_secs(1));
+    };
+    if let Err(e) = result {
+        error!("WiFi failed, restarting: {:?}", e);
+        esp_idf_svc::hal::reset::restart();
     }
 }
 
-pub fn wifi(
-    modem: impl Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-    nvs: Option<EspNvsPartition<NvsDefault>>,
-    timer_service: EspTimerService<Task>,
-) -> anyhow::Result<AsyncWifi<EspWifi<'static>>> {
-    use futures::executor::block_on;
-    let mut wifi = AsyncWifi::wrap(
-        EspWifi::new(modem, sysloop.clone(), nvs)?,
-        sysloop,
-        timer_service.clone(),
-    )?;
-
-    block_on(connect_wifi(&mut wifi))?;
-
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-
-    println!("Wifi DHCP info: {:?}", ip_info);
-
-    EspPing::default().ping(
-        ip_info.subnet.gateway,
-        &esp_idf_svc::ping::Configuration::default(),
-    )?;
-    Ok(wifi)
+fn default_wifi_settings() -> Option<WifiSettings> {
+    let settings = WifiSettings {
+        ssid: SSID?.to_owned(),
+        password: PASS.unwrap_or_default().to_owned(),
+        auth_method: Default::default(),
+        priority: 0,
+    };
+    settings.validate().ok()?;
+    Some(settings)
 }
 
-async fn connect_wifi(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<()> {
-    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: SSID.into(),
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal,
-        password: PASS.into(),
-        channel: None,
-    });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    // Setting up static IP configuration
-    // perhaps an easier solution is possible,
-    // this seemed to be the simplest one with the high-level driver is possible
-
-    let ipconfig = IpConfiguration::Client(IpClientConfiguration::Fixed(IpClientSettings {
-        ip: Ipv4Addr::from(parse_ip(STATIC_IP)),
-        subnet: Subnet {
-            gateway: Ipv4Addr::from(parse_ip(GATEWAY_IP)),
-            mask: Mask(24),
-        },
-        dns: None,
-        secondary_dns: None,
-    }));
-
-    let netif_config = NetifConfiguration {
-        ip_configuration: ipconfig,
-        key: "StaticClient".into(),
-        ..NetifConfiguration::wifi_default_client()
+fn default_ip_config() -> Option<IpConfig> {
+    let (address, prefix) = match ip::parse_cidr(STATIC_IP?) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Invalid ESP32_STATIC_IP: {}", e);
+            return None;
+        }
     };
-
-    let netif_ap_config = NetifConfiguration {
-        key: "StaticAP".into(),
-        ..NetifConfiguration::wifi_default_router()
+    let gateway = match ip::parse_address(GATEWAY_IP?) {
+        Ok(gateway) => gateway,
+        Err(e) => {
+            error!("Invalid ESP32_GATEWAY_IP: {}", e);
+            return None;
+        }
     };
-
-    wifi.wifi_mut()
-        .swap_netif(
-            EspNetif::new_with_conf(&netif_config).unwrap(),
-            EspNetif::new_with_conf(&netif_ap_config).unwrap(),
-        )
-        .unwrap();
-
-    wifi.start().await?;
-    info!("Wifi started");
-
-    wifi.connect().await?;
-    info!("Wifi connected");
-
-    wifi.wait_netif_up().await?;
-    info!("Wifi netif up");
-
-    Ok(())
-}
-
-fn parse_ip(ip: &str) -> [u8; 4] {
-    let mut result = [0u8; 4];
-    for (idx, octet) in ip.split(".").into_iter().enumerate() {
-        result[idx] = u8::from_str_radix(octet, 10).unwrap();
+    let config = IpConfig::Static(StaticIp {
+        address,
+        prefix: prefix.unwrap_or(24),
+        gateway,
+        dns: None,
+        secondary_dns: None,
+    });
+    if let Err(e) = config.validate() {
+        error!("Invalid compiled in static IP: {}", e);
+        return None;
     }
-    result
+    Some(config)
 }
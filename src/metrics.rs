@@ -0,0 +1,164 @@
+// Writer for the Prometheus text exposition format, used by `/metrics`.
+//
+// Every metric family starts with its `# HELP` and `# TYPE` lines, followed
+// by its samples. Label values and help texts are escaped as the format
+// requires, names are expected to be valid already.
+
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Starts a metric family, the samples written next belong to it
+    pub fn family(&mut self, name: &str, kind: MetricType, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {} {}", name, escape_help(help));
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind.as_str());
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (idx, (label, value)) in labels.iter().enumerate() {
+                if idx > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", label, escape_label(value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", format_value(value));
+        self
+    }
+
+    // A family with a single sample without labels
+    pub fn single(&mut self, name: &str, kind: MetricType, help: &str, value: f64) -> &mut Self {
+        self.family(name, kind, help).sample(name, &[], value)
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Whole numbers without a fraction, and the special values the way
+// Prometheus spells them
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.into()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_families_and_samples() {
+        let mut exposition = Exposition::new();
+        exposition
+            .single(
+                "gong_strikes_total",
+                MetricType::Counter,
+                "Strikes since boot.",
+                3.0,
+            )
+            .family(
+                "gong_http_requests_total",
+                MetricType::Counter,
+                "HTTP requests by route and status.",
+            )
+            .sample(
+                "gong_http_requests_total",
+                &[("route", "/servo"), ("status", "202")],
+                2.0,
+            )
+            .sample(
+                "gong_http_requests_total",
+                &[("route", "/servo"), ("status", "400")],
+                1.0,
+            )
+            .single("gong_wifi_rssi_dbm", MetricType::Gauge, "RSSI.", -61.0);
+        assert_eq!(
+            exposition.finish(),
+            "# HELP gong_strikes_total Strikes since boot.\n\
+             # TYPE gong_strikes_total counter\n\
+             gong_strikes_total 3\n\
+             # HELP gong_http_requests_total HTTP requests by route and status.\n\
+             # TYPE gong_http_requests_total counter\n\
+             gong_http_requests_total{route=\"/servo\",status=\"202\"} 2\n\
+             gong_http_requests_total{route=\"/servo\",status=\"400\"} 1\n\
+             # HELP gong_wifi_rssi_dbm RSSI.\n\
+             # TYPE gong_wifi_rssi_dbm gauge\n\
+             gong_wifi_rssi_dbm -61\n"
+        );
+    }
+
+    #[test]
+    fn escapes_help_and_label_values() {
+        let mut exposition = Exposition::new();
+        exposition
+            .family("x", MetricType::Gauge, "a \\ b\nc \"d\"")
+            .sample("x", &[("name", "a \\ \"b\"\nc")], 1.0);
+        assert_eq!(
+            exposition.finish(),
+            "# HELP x a \\\\ b\\nc \"d\"\n\
+             # TYPE x gauge\n\
+             x{name=\"a \\\\ \\\"b\\\"\\nc\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn formats_values() {
+        assert_eq!(format_value(0.0), "0");
+        assert_eq!(format_value(-0.0), "0");
+        assert_eq!(format_value(4_294_967_295.0), "4294967295");
+        assert_eq!(format_value(0.25), "0.25");
+        assert_eq!(format_value(1e20), "100000000000000000000");
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+    }
+}
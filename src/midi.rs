@@ -0,0 +1,566 @@
+// Reader for Standard MIDI Files (format 0 and 1) turning the note-on events
+// of a channel or note into a strike timeline, so rhythms sketched in a DAW
+// can be played on the gong.
+
+use std::fmt;
+
+use crate::rhythm::{Hit, Timeline, MAX_STRIKES};
+
+// Largest file `/midi` accepts
+pub const MAX_FILE: usize = 16 * 1024;
+
+// Notes later than this are refused, longer songs would not fit into a
+// sequence anyway
+pub const MAX_LENGTH_US: u64 = 60 * 60 * 1_000_000;
+
+// 120 bpm, until the file sets a tempo
+const DEFAULT_TEMPO_US: u64 = 500_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MidiError {
+    NotMidi,
+    Truncated,
+    UnsupportedFormat(u16),
+    InvalidDivision,
+    InvalidVarLen,
+    MissingStatus,
+    InvalidEvent(u8),
+    TooManyNotes,
+    TooLong,
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiError::NotMidi => write!(f, "not a MIDI file, the `MThd` header is missing"),
+            MidiError::Truncated => write!(f, "the MIDI file ends in the middle of a chunk"),
+            MidiError::UnsupportedFormat(format) => write!(
+                f,
+                "MIDI format {} is not supported, only formats 0 and 1",
+                format
+            ),
+            MidiError::InvalidDivision => write!(f, "the time division of the file is invalid"),
+            MidiError::InvalidVarLen => write!(f, "a variable length number is too long"),
+            MidiError::MissingStatus => write!(f, "running status without a previous event"),
+            MidiError::InvalidEvent(status) => write!(f, "unknown event 0x{:02x}", status),
+            MidiError::TooManyNotes => {
+                write!(f, "the file has more than {} matching notes", MAX_STRIKES)
+            }
+            MidiError::TooLong => write!(
+                f,
+                "the file has notes more than {} minutes from the start",
+                MAX_LENGTH_US / 60_000_000
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoteOn {
+    pub time_us: u64,
+    // From 0 to 15, shown as 1 to 16 in most programs
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+// The note-on events of all tracks in time order, with the tempo changes
+// applied, and the time the last track ends
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Song {
+    pub notes: Vec<NoteOn>,
+    pub length_us: u64,
+}
+
+// Which notes become strikes, `None` matches any
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub channel: Option<u8>,
+    pub note: Option<u8>,
+}
+
+impl Filter {
+    pub fn matches(&self, note: &NoteOn) -> bool {
+        self.channel.map_or(true, |channel| channel == note.channel)
+            && self.note.map_or(true, |number| number == note.note)
+    }
+}
+
+impl Song {
+    // Strikes for the matching notes with their velocity mapped onto the
+    // strike velocity. Notes starting in the same millisecond, like chords,
+    // are a single strike at the loudest velocity.
+    pub fn timeline(&self, filter: &Filter, damping_hold_ms: u64) -> Result<Timeline, MidiError> {
+        let mut hits: Vec<Hit> = Vec::new();
+        for note in self.notes.iter().filter(|note| filter.matches(note)) {
+            let time_ms = note.time_us / 1000;
+            let velocity = note.velocity as f64 / 127.0;
+            let count = hits.len();
+            match hits.last_mut() {
+                Some(last) if last.time_ms == time_ms => {
+                    last.velocity = last.velocity.max(velocity)
+                }
+                _ if count == MAX_STRIKES => return Err(MidiError::TooManyNotes),
+                _ => hits.push(Hit {
+                    time_ms,
+                    velocity,
+                    damping_hold_ms,
+                    location: None,
+                }),
+            }
+        }
+        Ok(Timeline::new(hits, self.length_us / 1000))
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MidiError> {
+        if len > self.bytes.len() {
+            return Err(MidiError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MidiError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Variable length quantity of at most four bytes, seven bits each
+    fn var_len(&mut self) -> Result<u32, MidiError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiError::InvalidVarLen)
+    }
+
+    fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>), MidiError> {
+        let kind = self.take(4)?;
+        let len = self.u32()? as usize;
+        Ok((
+            kind,
+            Reader {
+                bytes: self.take(len)?,
+            },
+        ))
+    }
+}
+
+// Time of a tick, in microseconds
+enum Division {
+    // Ticks per quarter note, scaled by the tempo
+    Metrical(u16),
+    // Microseconds per tick as a fraction, independent of the tempo
+    Timecode { us: u64, ticks: u64 },
+}
+
+impl Division {
+    // Microseconds of `ticks` at `tempo`, `None` beyond any time a song can
+    // last
+    fn duration_us(&self, ticks: u64, tempo: u64) -> Option<u64> {
+        let (us, per) = match *self {
+            Division::Metrical(ticks_per_quarter) => (tempo, ticks_per_quarter as u64),
+            Division::Timecode { us, ticks } => (us, ticks),
+        };
+        u64::try_from(ticks as u128 * us as u128 / per as u128).ok()
+    }
+}
+
+// Event of a track at a tick from the start of the song
+struct Event {
+    tick: u64,
+    kind: EventKind,
+}
+
+enum EventKind {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    Tempo(u64),
+    End,
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Song, MidiError> {
+    let mut file = Reader { bytes };
+    let (kind, mut header) = file.chunk().map_err(|_| MidiError::NotMidi)?;
+    if kind != b"MThd" {
+        return Err(MidiError::NotMidi);
+    }
+    let format = header.u16()?;
+    if format > 1 {
+        return Err(MidiError::UnsupportedFormat(format));
+    }
+    let _tracks = header.u16()?;
+    let division = match header.u16()? {
+        0 => return Err(MidiError::InvalidDivision),
+        division if division & 0x8000 == 0 => Division::Metrical(division),
+        division => {
+            // Negative frames per second in the high byte, 29 meaning 29.97
+            let fps = -((division >> 8) as u8 as i8 as i16);
+            let ticks_per_frame = (division & 0xff) as u64;
+            if fps <= 0 || ticks_per_frame == 0 {
+                return Err(MidiError::InvalidDivision);
+            }
+            match fps {
+                29 => Division::Timecode {
+                    us: 100_000_000,
+                    ticks: 2997 * ticks_per_frame,
+                },
+                fps => Division::Timecode {
+                    us: 1_000_000,
+                    ticks: fps as u64 * ticks_per_frame,
+                },
+            }
+        }
+    };
+
+    let mut events = Vec::new();
+    while !file.is_empty() {
+        let (kind, track) = file.chunk()?;
+        // Other chunk types are to be skipped
+        if kind == b"MTrk" {
+            read_track(track, &mut events)?;
+        }
+    }
+    // Stable, so the order within a track is kept
+    events.sort_by_key(|event| event.tick);
+
+    // The time is `None` once it is past `MAX_LENGTH_US`, which is only an
+    // error if notes come after it
+    let mut song = Song::default();
+    let (mut tick, mut time_us, mut tempo) = (0, Some(0_u64), DEFAULT_TEMPO_US);
+    for event in events {
+        time_us = time_us
+            .zip(division.duration_us(event.tick - tick, tempo))
+            .and_then(|(time, duration)| time.checked_add(duration))
+            .filter(|&time| time <= MAX_LENGTH_US);
+        tick = event.tick;
+        match event.kind {
+            EventKind::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => song.notes.push(NoteOn {
+                time_us: time_us.ok_or(MidiError::TooLong)?,
+                channel,
+                note,
+                velocity,
+            }),
+            EventKind::Tempo(us) => tempo = us,
+            EventKind::End => {}
+        }
+        song.length_us = time_us.unwrap_or(MAX_LENGTH_US);
+    }
+    Ok(song)
+}
+
+fn read_track(mut track: Reader, events: &mut Vec<Event>) -> Result<(), MidiError> {
+    let mut tick = 0_u64;
+    let mut running_status = None;
+    while !track.is_empty() {
+        tick += track.var_len()? as u64;
+        let mut status = track.u8()?;
+        let mut first_data = None;
+        if status & 0x80 == 0 {
+            first_data = Some(status);
+            status = running_status.ok_or(MidiError::MissingStatus)?;
+        }
+
+        match status {
+            0xff => {
+                let kind = track.u8()?;
+                let len = track.var_len()? as usize;
+                let data = track.take(len)?;
+                match (kind, data) {
+                    (0x51, &[a, b, c]) => events.push(Event {
+                        tick,
+                        kind: EventKind::Tempo(u32::from_be_bytes([0, a, b, c]) as u64),
+                    }),
+                    (0x2f, _) => {
+                        events.push(Event {
+                            tick,
+                            kind: EventKind::End,
+                        });
+                        break;
+                    }
+                    _ => {}
+                }
+                running_status = None;
+            }
+            0xf0 | 0xf7 => {
+                let len = track.var_len()? as usize;
+                track.take(len)?;
+                running_status = None;
+            }
+            0x80..=0xef => {
+                running_status = Some(status);
+                let mut data = || match first_data.take() {
+                    Some(byte) => Ok(byte),
+                    None => track.u8(),
+                };
+                let first = data()?;
+                // Program change and channel pressure have one data byte
+                let second = match status & 0xf0 {
+                    0xc0 | 0xd0 => 0,
+                    _ => data()?,
+                };
+                // A note-on with zero velocity is a note-off
+                if status & 0xf0 == 0x90 && second > 0 {
+                    events.push(Event {
+                        tick,
+                        kind: EventKind::NoteOn {
+                            channel: status & 0x0f,
+                            note: first & 0x7f,
+                            velocity: second & 0x7f,
+                        },
+                    });
+                }
+            }
+            status => return Err(MidiError::InvalidEvent(status)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut header = format.to_be_bytes().to_vec();
+        header.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        header.extend_from_slice(&division.to_be_bytes());
+        let mut file = chunk(b"MThd", &header);
+        for track in tracks {
+            file.extend(chunk(b"MTrk", track));
+        }
+        file
+    }
+
+    fn times(song: &Song) -> Vec<u64> {
+        song.notes.iter().map(|note| note.time_us).collect()
+    }
+
+    const END: [u8; 4] = [0x00, 0xff, 0x2f, 0x00];
+
+    #[test]
+    fn notes_at_the_default_tempo() {
+        let track = [
+            &[0x00, 0x99, 38, 100][..],
+            // Running status, then a note-on with velocity 0 as note-off
+            &[0x60, 42, 64, 0x10, 38, 0][..],
+            &[0x50, 0x89, 42, 0, 0x00, 0x99, 38, 127][..],
+            &END,
+        ]
+        .concat();
+        let song = parse(&file(0, 96, &[&track])).unwrap();
+        assert_eq!(
+            song.notes,
+            [
+                NoteOn {
+                    time_us: 0,
+                    channel: 9,
+                    note: 38,
+                    velocity: 100
+                },
+                NoteOn {
+                    time_us: 500_000,
+                    channel: 9,
+                    note: 42,
+                    velocity: 64
+                },
+                NoteOn {
+                    time_us: 1_000_000,
+                    channel: 9,
+                    note: 38,
+                    velocity: 127
+                },
+            ]
+        );
+        assert_eq!(song.length_us, 1_000_000);
+    }
+
+    #[test]
+    fn tempo_changes_apply_from_their_tick() {
+        let track = [
+            &[0x00, 0x90, 60, 64][..],
+            &[0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40][..],
+            &[0x00, 0x90, 60, 64, 0x60, 0x90, 60, 64][..],
+            &END,
+        ]
+        .concat();
+        let song = parse(&file(0, 96, &[&track])).unwrap();
+        assert_eq!(times(&song), [0, 500_000, 1_500_000]);
+    }
+
+    #[test]
+    fn tracks_are_merged() {
+        let tempo = [&[0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20][..], &END].concat();
+        let kick = [&[0x00, 0x99, 36, 90, 0x81, 0x40, 0x99, 36, 90][..], &END].concat();
+        let snare = [&[0x60, 0x99, 38, 80][..], &END].concat();
+        let song = parse(&file(1, 96, &[&tempo, &kick, &snare])).unwrap();
+        let notes: Vec<_> = song
+            .notes
+            .iter()
+            .map(|note| (note.time_us, note.note))
+            .collect();
+        assert_eq!(notes, [(0, 36), (500_000, 38), (1_000_000, 36)]);
+    }
+
+    #[test]
+    fn timecode_divisions() {
+        let track = [&[0x00, 0x90, 60, 64, 0x28, 0x90, 60, 64][..], &END].concat();
+        // 25 frames per second of 40 ticks, a millisecond per tick
+        let song = parse(&file(0, 0xe728, &[&track])).unwrap();
+        assert_eq!(times(&song), [0, 40_000]);
+        // 20408 ticks at 29.97 frames per second of 40 ticks
+        let track = [
+            &[0x00, 0x90, 60, 64, 0x81, 0x9f, 0x38, 0x90, 60, 64][..],
+            &END,
+        ]
+        .concat();
+        let song = parse(&file(0, 0xe328, &[&track])).unwrap();
+        assert_eq!(times(&song), [0, 17_023_690]);
+    }
+
+    #[test]
+    fn other_chunks_and_events_are_skipped() {
+        let track = [
+            &[0x00, 0xf0, 0x03, 0x7e, 0x7f, 0xf7][..],
+            &[0x00, 0xff, 0x03, 0x04][..],
+            b"Drum",
+            &[0x00, 0xc9, 0x05, 0x00, 0xb9, 0x07, 0x64][..],
+            &[0x00, 0x99, 38, 100][..],
+            &END,
+        ]
+        .concat();
+        let mut bytes = file(0, 96, &[&track]);
+        bytes.extend(chunk(b"XFIH", b"vendor"));
+        assert_eq!(times(&parse(&bytes).unwrap()), [0]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert_eq!(parse(b"RIFF\0\0\0\x04WAVE"), Err(MidiError::NotMidi));
+        assert_eq!(parse(b"MThd"), Err(MidiError::NotMidi));
+        assert_eq!(
+            parse(&file(2, 96, &[])),
+            Err(MidiError::UnsupportedFormat(2))
+        );
+        assert_eq!(parse(&file(0, 0, &[])), Err(MidiError::InvalidDivision));
+        assert_eq!(
+            parse(&file(0, 0xe700, &[])),
+            Err(MidiError::InvalidDivision)
+        );
+        let mut truncated = file(0, 96, &[&END]);
+        truncated.pop();
+        assert_eq!(parse(&truncated), Err(MidiError::Truncated));
+        assert_eq!(
+            parse(&file(0, 96, &[&[0x00, 60, 64]])),
+            Err(MidiError::MissingStatus)
+        );
+        assert_eq!(
+            parse(&file(0, 96, &[&[0xff, 0xff, 0xff, 0xff, 0x00]])),
+            Err(MidiError::InvalidVarLen)
+        );
+        assert_eq!(
+            parse(&file(0, 96, &[&[0x00, 0xf4]])),
+            Err(MidiError::InvalidEvent(0xf4))
+        );
+    }
+
+    #[test]
+    fn notes_past_the_longest_song_are_refused() {
+        // The slowest tempo and a delta of 2^28 ticks at one tick per quarter
+        // note, over a century
+        let track = [
+            0x00, 0xff, 0x51, 0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0x90, 60, 64,
+        ];
+        assert_eq!(parse(&file(0, 1, &[&track])), Err(MidiError::TooLong));
+        // Only notes count, a long silence at the end is cut short
+        let track = [
+            &[0x00, 0x90, 60, 64][..],
+            &[0xff, 0xff, 0xff, 0x7f, 0xff, 0x2f, 0x00][..],
+        ]
+        .concat();
+        let song = parse(&file(0, 1, &[&track])).unwrap();
+        assert_eq!(times(&song), [0]);
+        assert_eq!(song.length_us, MAX_LENGTH_US);
+    }
+
+    #[test]
+    fn chords_are_a_single_strike() {
+        let track = [
+            &[0x00, 0x99, 36, 64, 0x00, 38, 127, 0x00, 0x90, 60, 10][..],
+            &[0x60, 0x99, 38, 32][..],
+            &END,
+        ]
+        .concat();
+        let song = parse(&file(0, 96, &[&track])).unwrap();
+        let timeline = song.timeline(&Filter::default(), 50).unwrap();
+        let hits: Vec<_> = timeline
+            .hits
+            .iter()
+            .map(|hit| (hit.time_ms, hit.velocity, hit.damping_hold_ms))
+            .collect();
+        assert_eq!(hits, [(0, 1.0, 50), (500, 32.0 / 127.0, 50)]);
+        assert_eq!(timeline.length_ms, 500);
+
+        let snare = Filter {
+            channel: Some(9),
+            note: Some(38),
+        };
+        let timeline = song.timeline(&snare, 0).unwrap();
+        assert_eq!(timeline.hits.len(), 2);
+        let piano = Filter {
+            channel: Some(0),
+            note: None,
+        };
+        let timeline = song.timeline(&piano, 0).unwrap();
+        assert_eq!(timeline.hits[0].velocity, 10.0 / 127.0);
+    }
+
+    #[test]
+    fn too_many_notes() {
+        let mut track = Vec::new();
+        for _ in 0..=MAX_STRIKES {
+            track.extend_from_slice(&[0x01, 0x90, 60, 64]);
+        }
+        let song = parse(&file(0, 1, &[&track])).unwrap();
+        assert_eq!(
+            song.timeline(&Filter::default(), 0),
+            Err(MidiError::TooManyNotes)
+        );
+    }
+}
@@ -0,0 +1,617 @@
+// MQTT side of the API, independent of the client library. Sequences arrive
+// on `gong/<id>/strike` and `gong/<id>/sequence` and are handled like
+// `POST /servo`. The gong reports:
+//
+// - `gong/<id>/availability`: `online`, or `offline` as the last will
+// - `gong/<id>/state`: whether the player is idle or playing, retained
+// - `gong/<id>/result`: the answer to every command, like the HTTP body
+// - `gong/<id>/jobs`: each job once it has ended, like `GET /jobs/{id}`
+// - `gong/<id>/diagnostics`: signal strength and uptime, every minute
+//
+// `gong/<id>/pattern` plays a saved pattern by name and `gong/<id>/light/set`
+// drives the LED, both mostly for Home Assistant (see `home_assistant`).
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api::{Api, Method, Request};
+use crate::home_assistant::{self, Light};
+use crate::jobs::JobRecord;
+use crate::patterns;
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "mqtt";
+
+pub const MAX_ID_LEN: usize = 32;
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+// Delays between attempts to reach the broker
+pub const FIRST_RETRY: Duration = Duration::from_secs(2);
+pub const MAX_RETRY: Duration = Duration::from_secs(60);
+
+// How often the diagnostics are published
+pub const TICK: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    // `mqtt://host:port`, or `mqtts://` on the board
+    pub url: String,
+    // Names the gong in the topics, also the client id
+    pub id: String,
+    // Both empty for brokers without authentication
+    pub username: String,
+    pub password: String,
+    pub keep_alive_s: u16,
+    // Announce the gong to Home Assistant under `discovery_prefix`
+    pub home_assistant: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            id: "gong".into(),
+            username: String::new(),
+            password: String::new(),
+            keep_alive_s: 30,
+            home_assistant: true,
+            discovery_prefix: home_assistant::DEFAULT_PREFIX.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MqttError {
+    InvalidUrl(String),
+    // Letters, digits, `-` and `_` only, so it cannot add topic levels or
+    // wildcards
+    InvalidId(String),
+    KeepAlive,
+    InvalidPrefix(String),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::InvalidUrl(url) => write!(
+                f,
+                "the broker URL `{}` is not of the form mqtt://host:port",
+                url
+            ),
+            MqttError::InvalidId(id) => write!(
+                f,
+                "the id `{}` must be 1 to {} letters, digits, `-` or `_`",
+                id, MAX_ID_LEN
+            ),
+            MqttError::KeepAlive => write!(f, "the keep alive must be at least 5 seconds"),
+            MqttError::InvalidPrefix(prefix) => write!(
+                f,
+                "the discovery prefix `{}` must be a topic without wildcards",
+                prefix
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl MqttSettings {
+    pub fn validate(&self) -> Result<(), MqttError> {
+        // The URL only matters once enabled
+        if self.enabled && broker_address(&self.url).is_none() {
+            return Err(MqttError::InvalidUrl(self.url.clone()));
+        }
+        if self.id.is_empty()
+            || self.id.len() > MAX_ID_LEN
+            || !self
+                .id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(MqttError::InvalidId(self.id.clone()));
+        }
+        if self.keep_alive_s < 5 {
+            return Err(MqttError::KeepAlive);
+        }
+        let prefix = &self.discovery_prefix;
+        if prefix.is_empty()
+            || prefix.starts_with('/')
+            || prefix.ends_with('/')
+            || prefix.contains(['+', '#', ' '])
+        {
+            return Err(MqttError::InvalidPrefix(prefix.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn topics(&self) -> Topics {
+        Topics {
+            prefix: format!("gong/{}", self.id),
+        }
+    }
+}
+
+// Host and port of a `mqtt://` or `mqtts://` URL, the port defaults to the
+// standard one of the scheme
+pub fn broker_address(url: &str) -> Option<(bool, &str, u16)> {
+    let (tls, rest) = match url.split_once("://")? {
+        ("mqtt", rest) => (false, rest),
+        ("mqtts", rest) => (true, rest),
+        _ => return None,
+    };
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (rest, if tls { 8883 } else { 1883 }),
+    };
+    if host.is_empty() || host.contains('/') || port == 0 {
+        return None;
+    }
+    Some((tls, host, port))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    pub fn strike(&self) -> String {
+        format!("{}/strike", self.prefix)
+    }
+
+    pub fn sequence(&self) -> String {
+        format!("{}/sequence", self.prefix)
+    }
+
+    pub fn availability(&self) -> String {
+        format!("{}/availability", self.prefix)
+    }
+
+    pub fn state(&self) -> String {
+        format!("{}/state", self.prefix)
+    }
+
+    pub fn result(&self) -> String {
+        format!("{}/result", self.prefix)
+    }
+
+    pub fn jobs(&self) -> String {
+        format!("{}/jobs", self.prefix)
+    }
+
+    pub fn pattern(&self) -> String {
+        format!("{}/pattern", self.prefix)
+    }
+
+    pub fn light(&self) -> String {
+        format!("{}/light", self.prefix)
+    }
+
+    pub fn light_set(&self) -> String {
+        format!("{}/light/set", self.prefix)
+    }
+
+    pub fn diagnostics(&self) -> String {
+        format!("{}/diagnostics", self.prefix)
+    }
+}
+
+// A message for the client to send
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+// Turns messages into API requests and job changes into messages. The
+// client calls it and publishes whatever it returns.
+pub struct Bridge {
+    api: Arc<Api>,
+    settings: MqttSettings,
+    topics: Topics,
+    light: Mutex<Light>,
+    // Pattern names in the select last announced to Home Assistant
+    announced: Mutex<Vec<String>>,
+}
+
+impl Bridge {
+    pub fn new(api: Arc<Api>, settings: &MqttSettings) -> Self {
+        Self {
+            api,
+            settings: settings.clone(),
+            topics: settings.topics(),
+            light: Mutex::new(Light::default()),
+            announced: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn api(&self) -> &Api {
+        &self.api
+    }
+
+    pub fn topics(&self) -> &Topics {
+        &self.topics
+    }
+
+    // Topics to subscribe to after every connect
+    pub fn subscriptions(&self) -> Vec<String> {
+        let mut topics = vec![
+            self.topics.strike(),
+            self.topics.sequence(),
+            self.topics.pattern(),
+            self.topics.light_set(),
+        ];
+        if self.settings.home_assistant {
+            topics.push(home_assistant::status_topic(&self.settings));
+        }
+        topics
+    }
+
+    // The last will, published by the broker when the gong disappears
+    pub fn last_will(&self) -> Publication {
+        Publication {
+            topic: self.topics.availability(),
+            payload: OFFLINE.into(),
+            retain: true,
+        }
+    }
+
+    // Messages to publish after every connect
+    pub fn connected(&self) -> Vec<Publication> {
+        let mut publications = self.announce();
+        publications.extend([
+            Publication {
+                topic: self.topics.availability(),
+                payload: ONLINE.into(),
+                retain: true,
+            },
+            self.state(),
+            self.light_state(),
+            self.diagnostics(),
+        ]);
+        publications
+    }
+
+    // Handles a message on one of the subscribed topics
+    pub fn message(&self, topic: &str, payload: &[u8]) -> Vec<Publication> {
+        let response = if topic == self.topics.strike() || topic == self.topics.sequence() {
+            // The body of `/servo`, an empty strike is the calibrated one
+            // of `/strike` for the Home Assistant button
+            let default_strike =
+                topic == self.topics.strike() && payload.iter().all(u8::is_ascii_whitespace);
+            self.api.handle(&Request {
+                method: Method::Post,
+                uri: if default_strike { "/strike" } else { "/servo" },
+                headers: &[],
+                client: None,
+                body: if default_strike { &[] } else { payload },
+            })
+        } else if topic == self.topics.pattern() {
+            // Checked first, so the name cannot change the path
+            let name = String::from_utf8_lossy(payload);
+            let name = name.trim();
+            if let Err(e) = patterns::validate_name(name) {
+                return vec![self.result(json!({ "error": e.to_string() }).to_string())];
+            }
+            self.api.handle(&Request {
+                method: Method::Post,
+                uri: &format!("/patterns/{}", name),
+                headers: &[],
+                client: None,
+                body: &[],
+            })
+        } else if topic == self.topics.light_set() {
+            let mut light = self.light.lock().unwrap();
+            if let Err(e) = light.apply(payload) {
+                log::warn!("Rejected light command: {}", e);
+                return vec![self.result(json!({ "error": e }).to_string())];
+            }
+            self.api.link().set_light(light.shown());
+            drop(light);
+            return vec![self.light_state()];
+        } else if self.settings.home_assistant
+            && topic == home_assistant::status_topic(&self.settings)
+        {
+            // Home Assistant forgets the entities when it restarts
+            return if payload == ONLINE.as_bytes() {
+                self.announce()
+            } else {
+                Vec::new()
+            };
+        } else {
+            return Vec::new();
+        };
+        log::info!("MQTT {} -> {}", topic, response.status);
+        vec![self.result(response.body)]
+    }
+
+    // Messages for a job that started or ended
+    pub fn job_changed(&self, job: &JobRecord) -> Vec<Publication> {
+        let mut publications = vec![self.state()];
+        if job.state.is_done() {
+            publications.push(Publication {
+                topic: self.topics.jobs(),
+                payload: json!(job).to_string(),
+                retain: false,
+            });
+        }
+        publications
+    }
+
+    // Messages to publish every `TICK`: the diagnostics, and the select
+    // again if the saved patterns changed
+    pub fn tick(&self) -> Vec<Publication> {
+        let mut publications = vec![self.diagnostics()];
+        if self.settings.home_assistant {
+            let names = self.pattern_names();
+            let mut announced = self.announced.lock().unwrap();
+            if *announced != names {
+                publications.push(home_assistant::select_config(&self.settings, &names));
+                *announced = names;
+            }
+        }
+        publications
+    }
+
+    // The Home Assistant discovery configs, if enabled
+    fn announce(&self) -> Vec<Publication> {
+        if !self.settings.home_assistant {
+            return Vec::new();
+        }
+        let names = self.pattern_names();
+        let configs = home_assistant::configs(&self.settings, &names);
+        *self.announced.lock().unwrap() = names;
+        configs
+    }
+
+    fn pattern_names(&self) -> Vec<String> {
+        patterns::load(self.api.store()).into_keys().collect()
+    }
+
+    fn state(&self) -> Publication {
+        let jobs = self.api.player().jobs();
+        let running = jobs.running();
+        Publication {
+            topic: self.topics.state(),
+            payload: json!({
+                "state": if running.is_some() { "playing" } else { "idle" },
+                "job": running,
+                "queue_depth": jobs.queued(),
+            })
+            .to_string(),
+            retain: true,
+        }
+    }
+
+    fn light_state(&self) -> Publication {
+        Publication {
+            topic: self.topics.light(),
+            payload: self.light.lock().unwrap().to_json().to_string(),
+            retain: true,
+        }
+    }
+
+    fn diagnostics(&self) -> Publication {
+        let system = self.api.system();
+        Publication {
+            topic: self.topics.diagnostics(),
+            payload: json!({
+                "rssi": system.rssi(),
+                "uptime_s": system.uptime().as_secs(),
+            })
+            .to_string(),
+            retain: false,
+        }
+    }
+
+    fn result(&self, payload: String) -> Publication {
+        Publication {
+            topic: self.topics.result(),
+            payload,
+            retain: false,
+        }
+    }
+}
+
+// The saved settings, or the defaults (disabled) if there are none that are
+// usable
+pub fn load(store: &dyn Store) -> MqttSettings {
+    let settings: MqttSettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved MQTT settings: {}", e);
+            MqttSettings::default()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::calibration::ServoCalibration;
+    use crate::hal::ServoMotor;
+    use crate::jobs::JobState;
+    use crate::link::{LinkState, LinkStatus};
+    use crate::player::{self, Player, QUEUE_CAPACITY};
+    use crate::sim::{ManualClock, MemoryStore, SimActuator, SimSystem};
+
+    struct Gong {
+        bridge: Bridge,
+        topics: Topics,
+        jobs: Receiver<JobRecord>,
+    }
+
+    impl Gong {
+        fn new() -> Self {
+            let clock = ManualClock::new();
+            let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+            let mut saved = patterns::Patterns::new();
+            patterns::insert(&mut saved, "intro", "x x").unwrap();
+            patterns::save(&*store, &saved).unwrap();
+            let calibration = Arc::new(Mutex::new(ServoCalibration::default()));
+            let servo = ServoMotor::new(SimActuator::new(clock.clone()), calibration.clone());
+            let player = player::spawn(Player::new(servo, clock.clone()), QUEUE_CAPACITY).unwrap();
+            let jobs = player.jobs().watch();
+            let api = Api::new(
+                Arc::new(player),
+                calibration,
+                store,
+                Arc::new(SimSystem::new(clock)),
+                Arc::new(LinkStatus::new(LinkState::Up)),
+            );
+            let settings = MqttSettings::default();
+            Self {
+                bridge: Bridge::new(Arc::new(api), &settings),
+                topics: settings.topics(),
+                jobs,
+            }
+        }
+
+        // The single publication of the message, parsed
+        fn result(&self, topic: &str, payload: &str) -> Value {
+            let publications = self.bridge.message(topic, payload.as_bytes());
+            assert_eq!(publications.len(), 1, "{:?}", publications);
+            assert_eq!(publications[0].topic, self.topics.result());
+            assert!(!publications[0].retain);
+            serde_json::from_str(&publications[0].payload).unwrap()
+        }
+
+        // Waits for the player to end the job
+        fn finished(&self, result: &Value) -> JobRecord {
+            let id = result["job"].as_u64().expect("no job in the result");
+            loop {
+                let job = self
+                    .jobs
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("the job did not end");
+                if job.id as u64 == id && job.state.is_done() {
+                    return job;
+                }
+            }
+        }
+    }
+
+    fn payload(publication: &Publication) -> Value {
+        serde_json::from_str(&publication.payload).unwrap()
+    }
+
+    #[test]
+    fn empty_strike_plays_the_calibrated_strike() {
+        let gong = Gong::new();
+        for body in ["", " \n"] {
+            let result = gong.result(&gong.topics.strike(), body);
+            let job = gong.finished(&result);
+            assert_eq!(job.state, JobState::Finished);
+            // Backswing, settling, swing, contact and return
+            assert_eq!(job.steps, 5);
+        }
+    }
+
+    #[test]
+    fn strike_and_sequence_take_a_servo_body() {
+        let gong = Gong::new();
+        for topic in [gong.topics.strike(), gong.topics.sequence()] {
+            let result = gong.result(&topic, "90,200,30\n");
+            let job = gong.finished(&result);
+            assert_eq!(job.state, JobState::Finished);
+            assert_eq!(job.steps, 3);
+        }
+    }
+
+    #[test]
+    fn bad_body_publishes_the_error() {
+        let gong = Gong::new();
+        let result = gong.result(&gong.topics.strike(), "90,,30");
+        assert_eq!(result["error"], "empty_field");
+        assert_eq!(result["position"], 3);
+        let result = gong.result(&gong.topics.sequence(), "");
+        assert_eq!(result["error"], "empty");
+        assert!(gong.jobs.try_recv().is_err());
+    }
+
+    #[test]
+    fn pattern_plays_by_name() {
+        let gong = Gong::new();
+        let result = gong.result(&gong.topics.pattern(), "intro\n");
+        assert_eq!(gong.finished(&result).state, JobState::Finished);
+
+        let result = gong.result(&gong.topics.pattern(), "outro");
+        assert_eq!(result["error"], "no such pattern");
+        // Names cannot reach other routes
+        let result = gong.result(&gong.topics.pattern(), "../servo");
+        assert!(result["error"].is_string());
+        assert!(result.get("job").is_none());
+    }
+
+    #[test]
+    fn other_topics_are_ignored() {
+        let gong = Gong::new();
+        assert!(gong.bridge.message("gong/other/strike", b"").is_empty());
+        assert!(gong.bridge.message(&gong.topics.result(), b"{}").is_empty());
+        assert!(gong
+            .bridge
+            .message("homeassistant/status", b"offline")
+            .is_empty());
+        assert_eq!(
+            gong.bridge.message("homeassistant/status", b"online").len(),
+            5
+        );
+    }
+
+    #[test]
+    fn state_follows_the_jobs() {
+        let gong = Gong::new();
+        let connected = gong.bridge.connected();
+        let state = connected
+            .iter()
+            .find(|publication| publication.topic == gong.topics.state())
+            .unwrap();
+        assert!(state.retain);
+        assert_eq!(
+            payload(state),
+            json!({ "state": "idle", "job": null, "queue_depth": 0 })
+        );
+
+        let result = gong.result(&gong.topics.strike(), "90");
+        let job = gong.finished(&result);
+        let publications = gong.bridge.job_changed(&job);
+        assert_eq!(publications.len(), 2);
+        assert_eq!(publications[0].topic, gong.topics.state());
+        assert_eq!(payload(&publications[0])["state"], "idle");
+        assert_eq!(publications[1].topic, gong.topics.jobs());
+        assert_eq!(payload(&publications[1])["id"], result["job"]);
+        assert_eq!(payload(&publications[1])["state"], "finished");
+    }
+
+    #[test]
+    fn light_commands_answer_with_the_state() {
+        let gong = Gong::new();
+        let publications = gong.bridge.message(
+            &gong.topics.light_set(),
+            br#"{"state": "ON", "color": {"r": 255, "g": 0, "b": 0}}"#,
+        );
+        assert_eq!(publications.len(), 1);
+        assert_eq!(publications[0].topic, gong.topics.light());
+        assert_eq!(payload(&publications[0])["state"], "ON");
+
+        let result = gong.result(&gong.topics.light_set(), r#"{"state": "DIM"}"#);
+        assert_eq!(result["error"], "unknown state `DIM`");
+    }
+}
@@ -0,0 +1,305 @@
+// A small MQTT 3.1.1 client over plain TCP for the simulator, so the MQTT
+// side of the gong can be tried against a local broker like mosquitto. The
+// board uses the esp-idf client instead.
+//
+// Only what the gong needs is implemented: one connection with a last will,
+// subscriptions and publishing with QoS 1, and keep alive pings.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::link::Backoff;
+use crate::mqtt::{self, Bridge, MqttSettings, Publication};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+// Largest packet accepted from the broker
+const MAX_PACKET: usize = 64 * 1024;
+
+// The socket of the current connection, `None` while reconnecting
+#[derive(Clone, Default)]
+struct Connection(Arc<Mutex<Option<TcpStream>>>);
+
+impl Connection {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stream) => stream.write_all(packet),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn publish(&self, publication: &Publication, id: u16) {
+        match self.send(&publish_packet(publication, id)) {
+            Ok(()) => {}
+            // Nothing to tell while reconnecting
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => {}
+            Err(e) => warn!("Could not publish to {}: {}", publication.topic, e),
+        }
+    }
+}
+
+// Connects to the broker on a thread of its own, reconnecting with growing
+// delays whenever the connection fails
+pub fn spawn(settings: MqttSettings, bridge: Arc<Bridge>) -> anyhow::Result<()> {
+    let (tls, host, port) = mqtt::broker_address(&settings.url)
+        .ok_or_else(|| anyhow::anyhow!("invalid broker URL {}", settings.url))?;
+    if tls {
+        anyhow::bail!("the simulator only supports mqtt://, not mqtts://");
+    }
+    let address = format!("{}:{}", host, port);
+    let connection = Connection::default();
+
+    let jobs = bridge.api().player().jobs().watch();
+    let job_bridge = bridge.clone();
+    let job_connection = connection.clone();
+    thread::Builder::new()
+        .name("mqtt-jobs".into())
+        .spawn(move || {
+            for job in jobs {
+                for publication in job_bridge.job_changed(&job) {
+                    job_connection.publish(&publication, 0);
+                }
+            }
+        })?;
+
+    let tick_bridge = bridge.clone();
+    let tick_connection = connection.clone();
+    thread::Builder::new()
+        .name("mqtt-tick".into())
+        .spawn(move || loop {
+            thread::sleep(mqtt::TICK);
+            for publication in tick_bridge.tick() {
+                tick_connection.publish(&publication, 0);
+            }
+        })?;
+
+    thread::Builder::new().name("mqtt".into()).spawn(move || {
+        let mut backoff = Backoff::new(mqtt::FIRST_RETRY, mqtt::MAX_RETRY);
+        loop {
+            match session(&address, &settings, &bridge, &connection, &mut backoff) {
+                Ok(()) => warn!("MQTT broker {} closed the connection", address),
+                Err(e) => warn!("MQTT connection to {} failed: {}", address, e),
+            }
+            *connection.0.lock().unwrap() = None;
+            let delay = backoff.next_delay();
+            info!("Reconnecting to the MQTT broker in {:?}", delay);
+            thread::sleep(delay);
+        }
+    })?;
+    Ok(())
+}
+
+// One connection, from connecting until it fails
+fn session(
+    address: &str,
+    settings: &MqttSettings,
+    bridge: &Bridge,
+    connection: &Connection,
+    backoff: &mut Backoff,
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect(address)?;
+    let keep_alive = Duration::from_secs(settings.keep_alive_s as u64);
+    stream.set_read_timeout(Some(keep_alive / 2))?;
+    stream.write_all(&connect_packet(settings, &bridge.last_will()))?;
+    match read_packet(&mut stream)? {
+        (CONNACK, body) if body.get(1) == Some(&0) => {}
+        (CONNACK, body) => {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("refused with code {:?}", body.get(1)),
+            ))
+        }
+        (kind, _) => return Err(unexpected(kind)),
+    }
+    info!("Connected to the MQTT broker {}", address);
+    backoff.reset();
+    *connection.0.lock().unwrap() = Some(stream.try_clone()?);
+
+    let mut next_id = 1_u16;
+    let mut id = || {
+        next_id = next_id.checked_add(1).unwrap_or(1);
+        next_id
+    };
+    connection.send(&subscribe_packet(&bridge.subscriptions(), id()))?;
+    for publication in bridge.connected() {
+        connection.publish(&publication, id());
+    }
+
+    // A ping is sent after half the keep alive without packets, a second
+    // silent half means the broker is gone
+    let mut pinged = false;
+    loop {
+        let (kind, body) = match read_packet(&mut stream) {
+            Ok(packet) => packet,
+            Err(e) if is_timeout(&e) && !pinged => {
+                connection.send(&[PINGREQ, 0])?;
+                pinged = true;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        pinged = false;
+        match kind & 0xf0 {
+            PUBLISH => {
+                let (topic, packet_id, payload) = parse_publish(kind, &body)?;
+                if let Some(packet_id) = packet_id {
+                    connection.send(&[PUBACK, 2, (packet_id >> 8) as u8, packet_id as u8])?;
+                }
+                for publication in bridge.message(&topic, payload) {
+                    connection.publish(&publication, id());
+                }
+            }
+            SUBACK if body.iter().skip(2).any(|&code| code == 0x80) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the broker refused a subscription",
+                ))
+            }
+            SUBACK | PUBACK | PINGRESP => {}
+            _ => return Err(unexpected(kind)),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn unexpected(kind: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected packet type {:#04x}", kind),
+    )
+}
+
+// A packet from its first byte and the rest after the length
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_str(body: &mut Vec<u8>, s: &[u8]) {
+    body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    body.extend_from_slice(s);
+}
+
+fn connect_packet(settings: &MqttSettings, will: &Publication) -> Vec<u8> {
+    // Clean session and a last will with QoS 1
+    let mut flags = 0x02 | 0x04 | 0x08;
+    if will.retain {
+        flags |= 0x20;
+    }
+    if !settings.username.is_empty() {
+        flags |= 0x80;
+    }
+    if !settings.password.is_empty() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    push_str(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&settings.keep_alive_s.to_be_bytes());
+    push_str(&mut body, settings.id.as_bytes());
+    push_str(&mut body, will.topic.as_bytes());
+    push_str(&mut body, will.payload.as_bytes());
+    if !settings.username.is_empty() {
+        push_str(&mut body, settings.username.as_bytes());
+    }
+    if !settings.password.is_empty() {
+        push_str(&mut body, settings.password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+fn subscribe_packet(topics: &[String], id: u16) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    for topic in topics {
+        push_str(&mut body, topic.as_bytes());
+        body.push(1);
+    }
+    packet(SUBSCRIBE, &body)
+}
+
+// QoS 1 with an id, QoS 0 with 0
+fn publish_packet(publication: &Publication, id: u16) -> Vec<u8> {
+    let mut kind = PUBLISH;
+    if publication.retain {
+        kind |= 0x01;
+    }
+    let mut body = Vec::new();
+    push_str(&mut body, publication.topic.as_bytes());
+    if id != 0 {
+        kind |= 0x02;
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    body.extend_from_slice(publication.payload.as_bytes());
+    packet(kind, &body)
+}
+
+// Topic, packet id for QoS 1 and 2, and payload
+fn parse_publish(kind: u8, body: &[u8]) -> io::Result<(String, Option<u16>, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed PUBLISH");
+    let len = body.get(..2).ok_or_else(invalid)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let topic = body.get(2..2 + len).ok_or_else(invalid)?;
+    let topic = String::from_utf8(topic.to_vec()).map_err(|_| invalid())?;
+    let mut rest = &body[2 + len..];
+    let id = if kind & 0x06 != 0 {
+        let id = rest.get(..2).ok_or_else(invalid)?;
+        let id = u16::from_be_bytes([id[0], id[1]]);
+        rest = &rest[2..];
+        Some(id)
+    } else {
+        None
+    };
+    Ok((topic, id, rest))
+}
+
+fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0_u8];
+    stream.read_exact(&mut byte)?;
+    let kind = byte[0];
+    let mut len = 0_usize;
+    for shift in 0..4 {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > MAX_PACKET {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "packet too large",
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((kind, body))
+}
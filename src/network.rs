@@ -0,0 +1,945 @@
+// The board's side of the network: connecting to WiFi and staying connected,
+// the setup portal for when that is not possible, and serving `api` style
+// routes with the esp-idf HTTP server, over HTTPS once it is enabled.
+
+use std::ffi::{c_int, c_uchar, c_void, CStr, CString};
+use std::io::{Read, Write as _};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::{modem::Modem, peripheral::Peripheral};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::server::{
+    Configuration as HttpServerConfiguration, EspHttpConnection, EspHttpServer, HandlerResult,
+    Request,
+};
+use esp_idf_svc::io::Write;
+use esp_idf_svc::ipv4::{
+    ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
+    Configuration as IpConfiguration, Ipv4Addr, Mask, RouterConfiguration, Subnet,
+};
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, Event as MqttClientEvent, LwtConfiguration, Message,
+    MqttClientConfiguration, QoS,
+};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
+use esp_idf_svc::ping::EspPing;
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+use esp_idf_svc::sys::{
+    esp_fill_random, mbedtls_ecp_gen_key, mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+    mbedtls_ecp_keypair, mbedtls_md_type_t_MBEDTLS_MD_SHA256, mbedtls_pk_check_pair,
+    mbedtls_pk_context, mbedtls_pk_free, mbedtls_pk_info_from_type, mbedtls_pk_init,
+    mbedtls_pk_parse_key, mbedtls_pk_setup, mbedtls_pk_type_t_MBEDTLS_PK_ECKEY,
+    mbedtls_pk_write_key_pem, mbedtls_x509_crt, mbedtls_x509_crt_free, mbedtls_x509_crt_init,
+    mbedtls_x509_crt_parse, mbedtls_x509write_cert, mbedtls_x509write_crt_free,
+    mbedtls_x509write_crt_init, mbedtls_x509write_crt_pem,
+    mbedtls_x509write_crt_set_basic_constraints, mbedtls_x509write_crt_set_issuer_key,
+    mbedtls_x509write_crt_set_issuer_name, mbedtls_x509write_crt_set_md_alg,
+    mbedtls_x509write_crt_set_serial_raw, mbedtls_x509write_crt_set_subject_key,
+    mbedtls_x509write_crt_set_subject_name, mbedtls_x509write_crt_set_validity,
+    mbedtls_x509write_crt_set_version, MBEDTLS_X509_CRT_VERSION_3,
+};
+use esp_idf_svc::timer::{EspTimerService, Task};
+use esp_idf_svc::tls::X509;
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AsyncWifi, AuthMethod as EspAuthMethod, ClientConfiguration,
+    Configuration, EspWifi, WifiEvent,
+};
+use futures::executor::block_on;
+use log::*;
+
+use crate::api::{body_limit, Method, Request as ApiRequest, Response, HEADERS, MAX_ROUTES};
+use crate::discovery::{self, DiscoverySettings};
+use crate::dns;
+use crate::ip::IpConfig;
+use crate::jobs::JobRecord;
+use crate::link::{Backoff, Connection, LinkState, LinkStatus};
+use crate::mqtt::{self, Bridge, MqttSettings};
+use crate::portal::{self, Portal, SETUP_SSID};
+use crate::storage::Store;
+use crate::tls::{self, TlsSettings};
+use crate::tz::{self, TimeSettings};
+use crate::wifi::{self, AuthMethod, Candidate, ScannedNetwork, WifiSettings};
+
+// Delays between connection attempts
+pub const FIRST_RETRY: Duration = Duration::from_secs(2);
+pub const MAX_RETRY: Duration = Duration::from_secs(300);
+
+// Failed attempts after booting before the setup portal opens instead
+pub const SETUP_AFTER_ATTEMPTS: u32 = 5;
+
+// Time without requests to the setup portal after which the gong restarts
+// to try its known networks again, e.g. a router still starting after a
+// power cut
+pub const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// How often the connection is checked in case a disconnect event was missed
+const POLL: Duration = Duration::from_secs(10);
+
+pub fn wifi(
+    modem: impl Peripheral<P = Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    nvs: Option<EspNvsPartition<NvsDefault>>,
+    timer_service: EspTimerService<Task>,
+    ip: &IpConfig,
+) -> anyhow::Result<AsyncWifi<EspWifi<'static>>> {
+    let mut wifi = AsyncWifi::wrap(
+        EspWifi::new(modem, sysloop.clone(), nvs)?,
+        sysloop,
+        timer_service.clone(),
+    )?;
+
+    // Setting up the IP configuration
+    // perhaps an easier solution is possible,
+    // this seemed to be the simplest one with the high-level driver is possible
+    let ipconfig = match ip {
+        IpConfig::Dhcp => IpConfiguration::Client(IpClientConfiguration::default()),
+        IpConfig::Static(settings) => {
+            IpConfiguration::Client(IpClientConfiguration::Fixed(IpClientSettings {
+                ip: Ipv4Addr::from(settings.address.octets()),
+                subnet: Subnet {
+                    gateway: Ipv4Addr::from(settings.gateway.octets()),
+                    mask: Mask(settings.prefix),
+                },
+                dns: settings.dns.map(|dns| Ipv4Addr::from(dns.octets())),
+                secondary_dns: settings
+                    .secondary_dns
+                    .map(|dns| Ipv4Addr::from(dns.octets())),
+            }))
+        }
+    };
+
+    // The client keeps the default key, mDNS finds its interface by it
+    let netif_config = NetifConfiguration {
+        ip_configuration: ipconfig,
+        ..NetifConfiguration::wifi_default_client()
+    };
+
+    // Devices on the setup network are told to use the gong for DNS, which
+    // answers every name with its own address
+    let router = RouterConfiguration::default();
+    let netif_ap_config = NetifConfiguration {
+        key: "StaticAP".into(),
+        ip_configuration: IpConfiguration::Router(RouterConfiguration {
+            dns: Some(router.subnet.gateway),
+            secondary_dns: None,
+            ..router
+        }),
+        ..NetifConfiguration::wifi_default_router()
+    };
+
+    wifi.wifi_mut().swap_netif(
+        EspNetif::new_with_conf(&netif_config)?,
+        EspNetif::new_with_conf(&netif_ap_config)?,
+    )?;
+
+    Ok(wifi)
+}
+
+// Starts the client without a network, so it can scan
+fn start_client(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<()> {
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    block_on(wifi.start())?;
+    info!("Wifi started");
+    Ok(())
+}
+
+fn esp_auth_method(auth_method: AuthMethod) -> EspAuthMethod {
+    match auth_method {
+        AuthMethod::Open => EspAuthMethod::None,
+        AuthMethod::Auto | AuthMethod::Wpa2 => EspAuthMethod::WPA2Personal,
+        AuthMethod::Wpa3 => EspAuthMethod::WPA3Personal,
+        AuthMethod::Wpa2Wpa3 => EspAuthMethod::WPA2WPA3Personal,
+    }
+}
+
+// Older methods like WEP and WPA are not supported, they are treated as WPA2
+// which the connection then fails with
+fn scanned_auth_method(auth_method: EspAuthMethod) -> AuthMethod {
+    match auth_method {
+        EspAuthMethod::None => AuthMethod::Open,
+        EspAuthMethod::WPA3Personal => AuthMethod::Wpa3,
+        EspAuthMethod::WPA2WPA3Personal => AuthMethod::Wpa2Wpa3,
+        _ => AuthMethod::Wpa2,
+    }
+}
+
+async fn scan(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<Vec<ScannedNetwork>> {
+    let found = wifi.scan().await?;
+    info!("Scan found {} access points", found.len());
+    Ok(found
+        .into_iter()
+        .map(|ap| ScannedNetwork {
+            ssid: ap.ssid.as_str().to_owned(),
+            signal_strength: ap.signal_strength,
+            auth_method: scanned_auth_method(ap.auth_method),
+        })
+        .collect())
+}
+
+// Scans and connects to the best known network in range, trying the others
+// in turn when that fails. Returns the SSID and address.
+async fn join(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    networks: &[WifiSettings],
+) -> anyhow::Result<(String, Ipv4Addr)> {
+    let scanned = scan(wifi).await?;
+    let candidates = wifi::candidates(networks, &scanned);
+    if candidates.is_empty() {
+        anyhow::bail!("none of the {} known networks is in range", networks.len());
+    }
+
+    let mut failure = None;
+    for Candidate {
+        network,
+        auth_method,
+        signal_strength,
+    } in candidates
+    {
+        info!(
+            "Connecting to {} ({} dBm, {:?})",
+            network.ssid, signal_strength, auth_method
+        );
+        // A failed attempt may leave the driver still trying
+        let _ = wifi.wifi_mut().disconnect();
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+            ssid: network.ssid.as_str().into(),
+            auth_method: esp_auth_method(auth_method),
+            password: network.password.as_str().into(),
+            ..Default::default()
+        }))?;
+        match connect(wifi).await {
+            Ok(ip) => return Ok((network.ssid.clone(), ip)),
+            Err(e) => {
+                warn!("Could not connect to {}: {:?}", network.ssid, e);
+                failure = Some(e);
+            }
+        }
+    }
+    Err(failure.unwrap())
+}
+
+// Connects the started client, returning its address
+async fn connect(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<Ipv4Addr> {
+    wifi.connect().await?;
+    info!("Wifi connected");
+
+    wifi.wait_netif_up().await?;
+    info!("Wifi netif up");
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    info!("Wifi IP info: {:?}", ip_info);
+
+    if let Err(e) = EspPing::default().ping(
+        ip_info.subnet.gateway,
+        &esp_idf_svc::ping::Configuration::default(),
+    ) {
+        warn!("Could not ping the gateway: {:?}", e);
+    }
+    Ok(ip_info.ip)
+}
+
+// Advertises the API over mDNS as `<hostname>.local` with the `_gong._tcp`
+// and `_http._tcp` services, `_https._tcp` instead of the latter when
+// `https`. The advertisement lasts while the returned value is kept and
+// follows the client interface going up and down.
+pub fn advertise(settings: &DiscoverySettings, https: bool) -> anyhow::Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&settings.hostname)?;
+    mdns.set_instance_name(&settings.hostname)?;
+
+    let records = discovery::txt_records(https);
+    let txt: Vec<(&str, &str)> = records
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect();
+    let (services, port) = discovery::services(https);
+    for &(service, proto) in services {
+        mdns.add_service(None, service, proto, port, &txt)?;
+    }
+    info!(
+        "Advertising {}.local over mDNS with {:?}",
+        settings.hostname, records
+    );
+    Ok(mdns)
+}
+
+// Sets the clock over SNTP in the background, the schedules wait for it. The
+// configured server is asked first, the esp-idf defaults after it.
+pub fn sync_time(settings: &TimeSettings) -> anyhow::Result<EspSntp<'static>> {
+    let mut conf = SntpConf::default();
+    conf.servers[0] = &settings.ntp_server;
+    let sntp = EspSntp::new_with_callback(&conf, |since_epoch| {
+        info!(
+            "Clock synchronised: {} UTC",
+            tz::format(since_epoch.as_secs() as i64)
+        )
+    })?;
+    info!("Synchronising the clock with {}", settings.ntp_server);
+    Ok(sntp)
+}
+
+// What the MQTT thread handles, in the order it happened
+enum MqttEvent {
+    Connected,
+    Received(String, Vec<u8>),
+    Job(JobRecord),
+    Tick,
+}
+
+// Runs the API calls made over MQTT, they need about as much as an HTTP
+// handler
+const MQTT_STACK_SIZE: usize = 8192;
+
+// Connects to the MQTT broker and bridges it to the API. The esp-idf client
+// reconnects by itself, the subscriptions and the availability are renewed
+// after every connect. The client cannot be used from its own callback, so
+// its events and the job changes are handled on a thread that owns it.
+pub fn mqtt(settings: &MqttSettings, bridge: Arc<Bridge>) -> anyhow::Result<()> {
+    let (events, received) = mpsc::channel();
+    let will = bridge.last_will();
+    let conf = MqttClientConfiguration {
+        client_id: Some(&settings.id),
+        keep_alive_interval: Some(Duration::from_secs(settings.keep_alive_s.into())),
+        reconnect_timeout: Some(mqtt::FIRST_RETRY),
+        lwt: Some(LwtConfiguration {
+            topic: &will.topic,
+            payload: will.payload.as_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: will.retain,
+        }),
+        username: Some(settings.username.as_str()).filter(|s| !s.is_empty()),
+        password: Some(settings.password.as_str()).filter(|s| !s.is_empty()),
+        // Brokers behind `mqtts://` are checked against the bundled CAs
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+
+    let client_events = events.clone();
+    let mut client = EspMqttClient::new(&settings.url, &conf, move |event| {
+        let event = match event {
+            Ok(MqttClientEvent::Connected(_)) => MqttEvent::Connected,
+            Ok(MqttClientEvent::Received(message)) => match (message.details(), message.topic()) {
+                (Details::Complete, Some(topic)) => {
+                    MqttEvent::Received(topic.to_owned(), message.data().to_vec())
+                }
+                _ => {
+                    warn!("Ignoring an MQTT message split into chunks");
+                    return;
+                }
+            },
+            Ok(MqttClientEvent::Disconnected) => {
+                warn!("Disconnected from the MQTT broker");
+                return;
+            }
+            Err(e) => {
+                warn!("MQTT error: {:?}", e);
+                return;
+            }
+            _ => return,
+        };
+        let _ = client_events.send(event);
+    })?;
+
+    let jobs = bridge.api().player().jobs().watch();
+    let job_events = events.clone();
+    thread::Builder::new()
+        .name("mqtt-jobs".into())
+        .stack_size(4096)
+        .spawn(move || {
+            for job in jobs {
+                if job_events.send(MqttEvent::Job(job)).is_err() {
+                    break;
+                }
+            }
+        })?;
+    thread::Builder::new()
+        .name("mqtt-tick".into())
+        .stack_size(2048)
+        .spawn(move || loop {
+            sleep(mqtt::TICK);
+            if events.send(MqttEvent::Tick).is_err() {
+                break;
+            }
+        })?;
+
+    let url = settings.url.clone();
+    thread::Builder::new()
+        .name("mqtt".into())
+        .stack_size(MQTT_STACK_SIZE)
+        .spawn(move || {
+            for event in received {
+                let publications = match event {
+                    MqttEvent::Connected => {
+                        info!("Connected to the MQTT broker {}", url);
+                        for topic in bridge.subscriptions() {
+                            if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce) {
+                                warn!("Could not subscribe to {}: {:?}", topic, e);
+                            }
+                        }
+                        bridge.connected()
+                    }
+                    MqttEvent::Received(topic, payload) => bridge.message(&topic, &payload),
+                    MqttEvent::Job(job) => bridge.job_changed(&job),
+                    MqttEvent::Tick => bridge.tick(),
+                };
+                for publication in publications {
+                    if let Err(e) = client.publish(
+                        &publication.topic,
+                        QoS::AtLeastOnce,
+                        publication.retain,
+                        publication.payload.as_bytes(),
+                    ) {
+                        warn!("Could not publish to {}: {:?}", publication.topic, e);
+                    }
+                }
+            }
+        })?;
+    info!("MQTT bridge to {} started", settings.url);
+    Ok(())
+}
+
+// Keeps the WiFi connected to one of the known networks, scanning for them
+// again with growing delays whenever the connection is lost, and runs the
+// HTTP server made by `serve` while it is up. The networks are read from
+// `networks` before every scan so changes apply on the next reconnect. If the
+// gong never connects after booting, the setup portal is opened instead
+// until it restarts to try again.
+pub fn supervise(
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    sysloop: &EspSystemEventLoop,
+    networks: impl Fn() -> Vec<WifiSettings>,
+    link: &LinkStatus,
+    store: Arc<dyn Store>,
+    mut serve: impl FnMut() -> anyhow::Result<EspHttpServer<'static>>,
+) -> anyhow::Result<()> {
+    let (events, disconnects) = mpsc::channel();
+    let _subscription = sysloop.subscribe::<WifiEvent, _>(move |event| {
+        if *event == WifiEvent::StaDisconnected {
+            let _ = events.send(());
+        }
+    })?;
+
+    start_client(&mut wifi)?;
+
+    let mut backoff = Backoff::new(FIRST_RETRY, MAX_RETRY);
+    let mut server = None;
+    let mut address = None;
+    loop {
+        link.set(LinkState::Connecting);
+        match block_on(join(&mut wifi, &networks())) {
+            Ok((ssid, ip)) => {
+                backoff.reset();
+                link.set_up(Connection {
+                    ssid: ssid.clone(),
+                    ip,
+                });
+
+                // The server listens on every address, it only needs a
+                // restart when the address changed under open connections
+                if address != Some(ip) {
+                    if server.take().is_some() {
+                        info!("Restarting the HTTP server on the new address {}", ip);
+                    }
+                    server = Some(serve()?);
+                    address = Some(ip);
+                }
+
+                while disconnects.try_recv().is_ok() {}
+                loop {
+                    match disconnects.recv_timeout(POLL) {
+                        Err(mpsc::RecvTimeoutError::Timeout) if wifi.is_up()? => {}
+                        _ => break,
+                    }
+                }
+                warn!("Lost the connection to {}", ssid);
+            }
+            Err(e) if address.is_none() && backoff.attempts() + 1 >= SETUP_AFTER_ATTEMPTS => {
+                warn!(
+                    "Could not connect after {} attempts: {:?}",
+                    SETUP_AFTER_ATTEMPTS, e
+                );
+                return run_setup_portal(wifi, link, store, Some(PORTAL_TIMEOUT));
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!("Could not connect, trying again in {:?}: {:?}", delay, e);
+                link.set(LinkState::Failed);
+                sleep(delay);
+            }
+        }
+    }
+}
+
+// Opens the open `gong-setup` network with the setup page, restarting once
+// new settings are saved, or after `timeout` passed without requests
+pub fn run_setup_portal(
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    link: &LinkStatus,
+    store: Arc<dyn Store>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    link.set(LinkState::Setup);
+    if wifi.is_started()? {
+        block_on(wifi.stop())?;
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: SETUP_SSID.into(),
+        auth_method: EspAuthMethod::None,
+        ..Default::default()
+    }))?;
+    block_on(wifi.start())?;
+
+    let address = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!(
+        "Setup portal on network {} at http://{}",
+        SETUP_SSID, address
+    );
+
+    thread::Builder::new()
+        .name("dns".into())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = captive_dns(address.octets().into()) {
+                error!("Captive DNS stopped: {:?}", e);
+            }
+        })?;
+
+    let portal = Arc::new(Portal::new(store, || {
+        // Give the saved page time to reach the browser
+        thread::spawn(|| {
+            sleep(Duration::from_secs(2));
+            esp_idf_svc::hal::reset::restart();
+        });
+    }));
+    let last_request = Arc::new(Mutex::new(Instant::now()));
+    let _server = {
+        let last_request = last_request.clone();
+        serve(portal::ROUTES, None, move |req| {
+            *last_request.lock().unwrap() = Instant::now();
+            portal.handle(req)
+        })?
+    };
+
+    loop {
+        sleep(Duration::from_secs(1));
+        if timeout.is_some_and(|timeout| last_request.lock().unwrap().elapsed() >= timeout) {
+            info!("Nobody used the setup portal, restarting to try the known networks again");
+            esp_idf_svc::hal::reset::restart();
+        }
+    }
+}
+
+// Answers every DNS query on the setup network with the gong's address
+fn captive_dns(address: std::net::Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buffer = [0_u8; 512];
+    loop {
+        let (len, client) = socket.recv_from(&mut buffer)?;
+        if let Some(reply) = dns::captive_answer(&buffer[..len], address) {
+            socket.send_to(&reply, client)?;
+        }
+    }
+}
+
+// A certificate and its key for serving HTTPS
+#[derive(Clone, Copy, Debug)]
+pub struct ServerCertificate {
+    certificate: X509<'static>,
+    private_key: X509<'static>,
+}
+
+impl ServerCertificate {
+    // The server reads them on every handshake, so they are kept for as long
+    // as the firmware runs
+    fn leak(settings: &TlsSettings) -> anyhow::Result<Self> {
+        let certificate =
+            Box::leak(CString::new(settings.certificate.as_str())?.into_boxed_c_str());
+        let private_key =
+            Box::leak(CString::new(settings.private_key.as_str())?.into_boxed_c_str());
+        Ok(Self {
+            certificate: X509::pem(certificate),
+            private_key: X509::pem(private_key),
+        })
+    }
+}
+
+// The certificate to serve the API with, `None` while HTTPS is off. The first
+// time HTTPS is on without one, a self-signed certificate for
+// `<hostname>.local` is generated and saved.
+pub fn server_certificate(
+    store: &dyn Store,
+    hostname: &str,
+) -> anyhow::Result<Option<ServerCertificate>> {
+    let mut settings = tls::load(store);
+    if !settings.enabled {
+        return Ok(None);
+    }
+    if settings.needs_certificate() {
+        info!(
+            "Generating a self-signed certificate for {}.local",
+            hostname
+        );
+        let (certificate, private_key) = self_signed_certificate(hostname)?;
+        settings.certificate = certificate;
+        settings.private_key = private_key;
+        tls::save(store, &settings)?;
+    }
+    let certificate = ServerCertificate::leak(&settings)?;
+    check_pair(&certificate)?;
+    Ok(Some(certificate))
+}
+
+// Whether the certificate parses and is for the private key, which the
+// server would otherwise only find out when it fails to start
+fn check_pair(certificate: &ServerCertificate) -> anyhow::Result<()> {
+    let cert = certificate.certificate.data();
+    let private_key = certificate.private_key.data();
+    let mut parsed = MaybeUninit::<mbedtls_x509_crt>::uninit();
+    let mut key = MaybeUninit::<mbedtls_pk_context>::uninit();
+    unsafe {
+        mbedtls_x509_crt_init(parsed.as_mut_ptr());
+        mbedtls_pk_init(key.as_mut_ptr());
+        let parsed = &mut *parsed.as_mut_ptr();
+        let key = &mut *key.as_mut_ptr();
+        let result = (|| {
+            mbedtls_check(
+                "mbedtls_x509_crt_parse",
+                mbedtls_x509_crt_parse(parsed, cert.as_ptr(), cert.len()),
+            )?;
+            mbedtls_check(
+                "mbedtls_pk_parse_key",
+                mbedtls_pk_parse_key(
+                    key,
+                    private_key.as_ptr(),
+                    private_key.len(),
+                    std::ptr::null(),
+                    0,
+                    Some(mbedtls_random),
+                    std::ptr::null_mut(),
+                ),
+            )?;
+            mbedtls_check(
+                "mbedtls_pk_check_pair",
+                mbedtls_pk_check_pair(
+                    &parsed.private_pk,
+                    key,
+                    Some(mbedtls_random),
+                    std::ptr::null_mut(),
+                ),
+            )
+        })();
+        mbedtls_pk_free(key);
+        mbedtls_x509_crt_free(parsed);
+        result
+    }
+}
+
+// An ECDSA P-256 key and a certificate for it signed by itself, both PEM
+fn self_signed_certificate(hostname: &str) -> anyhow::Result<(String, String)> {
+    let subject = CString::new(format!("CN={}.local", hostname))?;
+    let mut serial = [0_u8; 16];
+    unsafe { esp_fill_random(serial.as_mut_ptr().cast(), serial.len()) };
+    // A positive number that does not start with a zero byte
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+    let mut certificate = vec![0_u8; tls::MAX_PEM_LEN];
+    let mut private_key = vec![0_u8; tls::MAX_PEM_LEN];
+
+    let mut key = MaybeUninit::<mbedtls_pk_context>::uninit();
+    let mut writer = MaybeUninit::<mbedtls_x509write_cert>::uninit();
+    let result = unsafe {
+        mbedtls_pk_init(key.as_mut_ptr());
+        mbedtls_x509write_crt_init(writer.as_mut_ptr());
+        let key = &mut *key.as_mut_ptr();
+        let writer = writer.as_mut_ptr();
+        let result = (|| {
+            mbedtls_check(
+                "mbedtls_pk_setup",
+                mbedtls_pk_setup(
+                    key,
+                    mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+                ),
+            )?;
+            mbedtls_check(
+                "mbedtls_ecp_gen_key",
+                mbedtls_ecp_gen_key(
+                    mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                    key.private_pk_ctx as *mut mbedtls_ecp_keypair,
+                    Some(mbedtls_random),
+                    std::ptr::null_mut(),
+                ),
+            )?;
+            mbedtls_x509write_crt_set_version(writer, MBEDTLS_X509_CRT_VERSION_3 as c_int);
+            mbedtls_x509write_crt_set_md_alg(writer, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            mbedtls_x509write_crt_set_subject_key(writer, key);
+            mbedtls_x509write_crt_set_issuer_key(writer, key);
+            mbedtls_check(
+                "mbedtls_x509write_crt_set_subject_name",
+                mbedtls_x509write_crt_set_subject_name(writer, subject.as_ptr()),
+            )?;
+            mbedtls_check(
+                "mbedtls_x509write_crt_set_issuer_name",
+                mbedtls_x509write_crt_set_issuer_name(writer, subject.as_ptr()),
+            )?;
+            mbedtls_check(
+                "mbedtls_x509write_crt_set_serial_raw",
+                mbedtls_x509write_crt_set_serial_raw(writer, serial.as_mut_ptr(), serial.len()),
+            )?;
+            // The clock may not be set yet, so the validity does not start
+            // from now
+            mbedtls_check(
+                "mbedtls_x509write_crt_set_validity",
+                mbedtls_x509write_crt_set_validity(
+                    writer,
+                    b"20240101000000\0".as_ptr().cast(),
+                    b"20491231235959\0".as_ptr().cast(),
+                ),
+            )?;
+            mbedtls_check(
+                "mbedtls_x509write_crt_set_basic_constraints",
+                mbedtls_x509write_crt_set_basic_constraints(writer, 0, -1),
+            )?;
+            mbedtls_check(
+                "mbedtls_x509write_crt_pem",
+                mbedtls_x509write_crt_pem(
+                    writer,
+                    certificate.as_mut_ptr(),
+                    certificate.len(),
+                    Some(mbedtls_random),
+                    std::ptr::null_mut(),
+                ),
+            )?;
+            mbedtls_check(
+                "mbedtls_pk_write_key_pem",
+                mbedtls_pk_write_key_pem(key, private_key.as_mut_ptr(), private_key.len()),
+            )
+        })();
+        mbedtls_x509write_crt_free(writer);
+        mbedtls_pk_free(key);
+        result
+    };
+    result?;
+
+    // Both are written with a terminating NUL
+    let pem = |buf: &[u8]| -> anyhow::Result<String> {
+        Ok(CStr::from_bytes_until_nul(buf)?.to_str()?.to_owned())
+    };
+    Ok((pem(&certificate)?, pem(&private_key)?))
+}
+
+// The random number generator mbedtls is given
+unsafe extern "C" fn mbedtls_random(_: *mut c_void, buf: *mut c_uchar, len: usize) -> c_int {
+    esp_fill_random(buf.cast(), len);
+    0
+}
+
+fn mbedtls_check(function: &str, result: c_int) -> anyhow::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        anyhow::bail!("{} failed with -0x{:04x}", function, result.unsigned_abs())
+    }
+}
+
+// Answers plain HTTP on port 80 with redirects to the same path over HTTPS,
+// in a thread of its own. A second esp-idf HTTP server would clash with the
+// first on its control port, so this is a bare listener reading no more than
+// the request head.
+pub fn redirect_to_https() -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", discovery::HTTP_PORT))?;
+    thread::Builder::new()
+        .name("redirect".into())
+        .stack_size(4096)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = redirect(stream) {
+                            debug!("Could not redirect to HTTPS: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("Could not accept an HTTP connection: {:?}", e),
+                }
+            }
+        })?;
+    info!("Redirecting HTTP to HTTPS");
+    Ok(())
+}
+
+fn redirect(mut stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut head = [0_u8; 1024];
+    let mut len = 0;
+    while len < head.len() && !head[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let head = String::from_utf8_lossy(&head[..len]);
+    let mut lines = head.lines();
+    let target = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .unwrap_or("/");
+    let host = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then_some(value)
+    });
+    let local = stream.local_addr()?.ip().to_string();
+    let location = tls::https_url(host, &local, target);
+    write!(
+        stream,
+        "HTTP/1.1 308 Permanent Redirect\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    )?;
+    Ok(())
+}
+
+// lwIP has 10 sockets, the server takes 3 of them for itself and the MQTT
+// client and the redirect to HTTPS need some as well. Every TLS session
+// also keeps about 20 kB of buffers.
+const HTTP_SOCKETS: usize = 4;
+const HTTPS_SOCKETS: usize = 3;
+// The TLS handshake, with the ECDSA key of the self-signed certificate, runs
+// on the server's task
+const HTTP_STACK_SIZE: usize = 6 * 1024;
+const HTTPS_STACK_SIZE: usize = 10 * 1024;
+
+// Starts an HTTP server passing the requests of all routes to `handler`, or
+// an HTTPS one with `certificate`
+pub fn serve<H>(
+    routes: &[(&str, Method)],
+    certificate: Option<ServerCertificate>,
+    handler: H,
+) -> anyhow::Result<EspHttpServer<'static>>
+where
+    H: Fn(&ApiRequest) -> Response + Send + Sync + 'static,
+{
+    anyhow::ensure!(
+        routes.len() <= MAX_ROUTES,
+        "{} routes are more than the {} the server takes",
+        routes.len(),
+        MAX_ROUTES
+    );
+    let https = certificate.is_some();
+    // Wildcard matching is needed for the `/jobs/{id}` routes
+    let mut server = EspHttpServer::new(&HttpServerConfiguration {
+        uri_match_wildcard: true,
+        // Registering a handler beyond these fails
+        max_uri_handlers: routes.len(),
+        max_open_sockets: if https { HTTPS_SOCKETS } else { HTTP_SOCKETS },
+        stack_size: if https {
+            HTTPS_STACK_SIZE
+        } else {
+            HTTP_STACK_SIZE
+        },
+        https_port: tls::HTTPS_PORT,
+        server_certificate: certificate.map(|c| c.certificate),
+        private_key: certificate.map(|c| c.private_key),
+        ..Default::default()
+    })?;
+
+    let handler = Arc::new(handler);
+    for &(uri, method) in routes {
+        let handler = handler.clone();
+        server.fn_handler(uri, esp_method(method), move |req| {
+            handle(method, req, &*handler)
+        })?;
+    }
+    Ok(server)
+}
+
+fn esp_method(method: Method) -> esp_idf_svc::http::Method {
+    match method {
+        Method::Get => esp_idf_svc::http::Method::Get,
+        Method::Post => esp_idf_svc::http::Method::Post,
+        Method::Put => esp_idf_svc::http::Method::Put,
+        Method::Delete => esp_idf_svc::http::Method::Delete,
+    }
+}
+
+fn handle(
+    method: Method,
+    mut req: Request<&mut EspHttpConnection>,
+    handler: &dyn Fn(&ApiRequest) -> Response,
+) -> HandlerResult {
+    let uri = req.uri().to_owned();
+    // Requests over the network are always authenticated, also when the
+    // address cannot be found
+    let client = peer_address(&mut req).or(Some(IpAddr::from([0, 0, 0, 0])));
+    let limit = body_limit(uri.split('?').next().unwrap_or_default());
+    let headers: Vec<(&str, String)> = HEADERS
+        .iter()
+        .filter_map(|&name| Some((name, req.header(name)?.to_owned())))
+        .collect();
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+
+    // Read the body in chunks instead of into a fixed buffer, so routes can
+    // accept bodies of different sizes
+    let declared = req
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok());
+    let mut body = Vec::new();
+    let mut chunk = [0_u8; 512];
+    let mut too_large = declared.is_some_and(|len| len > limit);
+    while !too_large {
+        match req.read(&mut chunk)? {
+            0 => break,
+            n if body.len() + n > limit => too_large = true,
+            n => body.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let response = if too_large {
+        Response::error(413, "request body too large")
+    } else {
+        handler(&ApiRequest {
+            method,
+            uri: &uri,
+            headers: &headers,
+            client,
+            body: &body,
+        })
+    };
+
+    let headers: Vec<(&str, &str)> = std::iter::once(("Content-Type", response.content_type))
+        .chain(
+            response
+                .headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str())),
+        )
+        .collect();
+    req.into_response(response.status, Some(response.reason()), &headers)?
+        .write_all(response.body.as_bytes())?;
+    Ok(())
+}
+
+// Address of the client sending `req`, as IPv4 when it is one
+fn peer_address(req: &mut Request<&mut EspHttpConnection>) -> Option<IpAddr> {
+    let fd = unsafe { esp_idf_svc::sys::httpd_req_to_sockfd(req.connection().handle()) };
+    if fd < 0 {
+        return None;
+    }
+    // The server owns the socket and closes it, it is only borrowed here
+    let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    match socket.peer_addr().ok()?.ip() {
+        IpAddr::V6(address) => Some(
+            address
+                .to_ipv4_mapped()
+                .map_or(IpAddr::V6(address), IpAddr::V4),
+        ),
+        address => Some(address),
+    }
+}
@@ -0,0 +1,103 @@
+// Rhythms saved under a name, so they can be played without sending the
+// notation each time, e.g. from the Home Assistant select.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::rhythm;
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "patterns";
+
+// All patterns are one NVS entry, which holds a few KiB at most
+pub const MAX_PATTERNS: usize = 16;
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_SOURCE_LEN: usize = 512;
+
+// Names to patterns in the notation of `POST /pattern`
+pub type Patterns = BTreeMap<String, String>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatternError {
+    // Letters, digits, `-` and `_`, so the name fits in a path segment and an
+    // MQTT payload as it is
+    InvalidName(String),
+    SourceLength,
+    Invalid(String),
+    TooMany,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::InvalidName(name) => write!(
+                f,
+                "the name `{}` must be 1 to {} letters, digits, `-` or `_`",
+                name, MAX_NAME_LEN
+            ),
+            PatternError::SourceLength => write!(
+                f,
+                "the pattern must be at most {} bytes long",
+                MAX_SOURCE_LEN
+            ),
+            PatternError::Invalid(e) => write!(f, "{}", e),
+            PatternError::TooMany => write!(f, "at most {} patterns can be saved", MAX_PATTERNS),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+pub fn validate_name(name: &str) -> Result<(), PatternError> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LEN
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(PatternError::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+// Checks that the source parses, so only playable patterns are saved
+pub fn validate_source(source: &str) -> Result<(), PatternError> {
+    if source.len() > MAX_SOURCE_LEN {
+        return Err(PatternError::SourceLength);
+    }
+    rhythm::parse(source).map_err(|e| PatternError::Invalid(e.to_string()))?;
+    Ok(())
+}
+
+// Adds or replaces a pattern
+pub fn insert(patterns: &mut Patterns, name: &str, source: &str) -> Result<(), PatternError> {
+    validate_name(name)?;
+    validate_source(source)?;
+    if !patterns.contains_key(name) && patterns.len() >= MAX_PATTERNS {
+        return Err(PatternError::TooMany);
+    }
+    patterns.insert(name.to_owned(), source.to_owned());
+    Ok(())
+}
+
+// The saved patterns that are still valid
+pub fn load(store: &dyn Store) -> Patterns {
+    let patterns: Patterns = storage::load(store, STORE_KEY).unwrap_or_default();
+    patterns
+        .into_iter()
+        .filter(
+            |(name, source)| match validate_name(name).and_then(|()| validate_source(source)) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Ignoring saved pattern {}: {}", name, e);
+                    false
+                }
+            },
+        )
+        .take(MAX_PATTERNS)
+        .collect()
+}
+
+pub fn save(store: &dyn Store, patterns: &Patterns) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, patterns)
+}
@@ -0,0 +1,483 @@
+// Plays strike sequences on a dedicated thread so that HTTP handlers only have
+// to queue them and can answer immediately.
+//
+// The servo and the clock are traits so that the timing and ordering of the
+// player can be checked on the host with fake implementations.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::jobs::{JobId, JobState, JobTable, HISTORY_CAPACITY};
+use crate::profile::{self, MotionProfile, TICK};
+use crate::sequence::{Step, StrikeSequence};
+
+pub const QUEUE_CAPACITY: usize = 8;
+// How often a long pause checks whether its job was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+const STACK_SIZE: usize = 8 * 1024;
+
+pub trait Servo {
+    // Duty value that positions the servo at `angle`
+    fn duty_for(&self, angle: u32) -> u32;
+    fn set_duty(&mut self, duty: u32) -> anyhow::Result<()>;
+    // Angle the mallet returns to when a sequence is cancelled
+    fn rest_angle(&self) -> u32;
+    // Angle at which the mallet touches the gong, moves ending there are
+    // counted as strikes
+    fn impact_angle(&self) -> u32;
+}
+
+pub trait Clock {
+    // Time elapsed since some fixed point, e.g. boot
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub id: JobId,
+    pub sequence: StrikeSequence,
+}
+
+// What the player thread did so far, readable from other threads
+#[derive(Debug)]
+pub struct PlayerStats {
+    // `NO_ANGLE` until the first move
+    angle: AtomicU32,
+    strikes: AtomicU32,
+}
+
+const NO_ANGLE: u32 = u32::MAX;
+
+impl PlayerStats {
+    fn new() -> Self {
+        Self {
+            angle: AtomicU32::new(NO_ANGLE),
+            strikes: AtomicU32::new(0),
+        }
+    }
+
+    // Last angle the servo was set to
+    pub fn angle(&self) -> Option<u32> {
+        match self.angle.load(Ordering::Relaxed) {
+            NO_ANGLE => None,
+            angle => Some(angle),
+        }
+    }
+
+    // Moves onto the gong since boot
+    pub fn strikes(&self) -> u32 {
+        self.strikes.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Player<S, C> {
+    servo: S,
+    clock: C,
+    // Last angle and duty set, unknown until the first move
+    position: Option<(u32, u32)>,
+    stats: Arc<PlayerStats>,
+}
+
+impl<S, C> Player<S, C>
+where
+    S: Servo,
+    C: Clock,
+{
+    pub fn new(servo: S, clock: C) -> Self {
+        Self {
+            servo,
+            clock,
+            position: None,
+            stats: Arc::new(PlayerStats::new()),
+        }
+    }
+
+    pub fn servo(&self) -> &S {
+        &self.servo
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn stats(&self) -> &Arc<PlayerStats> {
+        &self.stats
+    }
+
+    pub fn play(&mut self, sequence: &StrikeSequence) -> anyhow::Result<Outcome> {
+        self.play_until(sequence, || false)
+    }
+
+    // Plays the sequence, checking `cancelled` between steps and during pauses.
+    // A cancelled sequence leaves the mallet at rest.
+    pub fn play_until(
+        &mut self,
+        sequence: &StrikeSequence,
+        cancelled: impl Fn() -> bool,
+    ) -> anyhow::Result<Outcome> {
+        for step in sequence.steps() {
+            if cancelled() {
+                return self.rest();
+            }
+            match *step {
+                Step::Move { angle, profile } => {
+                    info!("Set servo to {} ({})", angle, profile);
+                    if self.move_to(angle, profile, &cancelled)? == Outcome::Cancelled {
+                        return self.rest();
+                    }
+                }
+                Step::Wait(pause) => {
+                    info!("Wait {}", pause.as_millis());
+                    let mut remaining = pause;
+                    while !remaining.is_zero() {
+                        let slice = remaining.min(CANCEL_POLL);
+                        self.clock.sleep(slice);
+                        remaining -= slice;
+                        if cancelled() {
+                            return self.rest();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Outcome::Completed)
+    }
+
+    // Steps the duty along the profile's trajectory, once per tick
+    fn move_to(
+        &mut self,
+        angle: u32,
+        profile: MotionProfile,
+        cancelled: impl Fn() -> bool,
+    ) -> anyhow::Result<Outcome> {
+        let target = self.servo.duty_for(angle);
+        let from = self.position.map(|(from, _)| from);
+        let (trajectory, ramped) = match self.position {
+            Some((from_angle, from_duty)) if profile != MotionProfile::Instant => (
+                profile::duty_trajectory(profile, from_angle, angle, from_duty, target, TICK),
+                true,
+            ),
+            _ => (vec![target], false),
+        };
+
+        // Each duty of a ramp is held for one tick, so the move takes the
+        // time given by the profile
+        for duty in trajectory {
+            self.servo.set_duty(duty)?;
+            self.position = Some((angle, duty));
+            self.stats.angle.store(angle, Ordering::Relaxed);
+            if ramped {
+                self.clock.sleep(TICK);
+                if cancelled() {
+                    return Ok(Outcome::Cancelled);
+                }
+            }
+        }
+        if angle == self.servo.impact_angle() && from != Some(angle) {
+            self.stats.strikes.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(Outcome::Completed)
+    }
+
+    fn rest(&mut self) -> anyhow::Result<Outcome> {
+        let angle = self.servo.rest_angle();
+        info!("Cancelled, returning servo to {}", angle);
+        self.move_to(angle, MotionProfile::Instant, || false)?;
+        Ok(Outcome::Cancelled)
+    }
+
+    // Plays queued jobs in order until every sender is dropped
+    pub fn run(mut self, jobs: Receiver<Job>, table: Arc<JobTable>) {
+        for job in jobs {
+            if !table.start(job.id) {
+                info!("Skipping cancelled job {}", job.id);
+                continue;
+            }
+            info!("Playing job {}", job.id);
+            match self.play_until(&job.sequence, || table.cancel_requested(job.id)) {
+                Ok(Outcome::Completed) => table.finish(job.id, JobState::Finished, None),
+                Ok(Outcome::Cancelled) => table.finish(job.id, JobState::Cancelled, None),
+                Err(e) => {
+                    error!("Job {} failed: {:?}", job.id, e);
+                    table.finish(job.id, JobState::Failed, Some(e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    QueueFull,
+    Stopped,
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmitError::QueueFull => write!(f, "the player queue is full"),
+            SubmitError::Stopped => write!(f, "the player is not running"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+pub struct PlayerHandle {
+    jobs: SyncSender<Job>,
+    table: Arc<JobTable>,
+    stats: Arc<PlayerStats>,
+    next_id: AtomicU32,
+}
+
+impl PlayerHandle {
+    pub fn new(jobs: SyncSender<Job>, table: Arc<JobTable>, stats: Arc<PlayerStats>) -> Self {
+        Self {
+            jobs,
+            table,
+            stats,
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    pub fn jobs(&self) -> &JobTable {
+        &self.table
+    }
+
+    pub fn stats(&self) -> &PlayerStats {
+        &self.stats
+    }
+
+    pub fn submit(&self, sequence: StrikeSequence) -> Result<JobId, SubmitError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.table.insert(id, &sequence);
+        match self.jobs.try_send(Job { id, sequence }) {
+            Ok(()) => Ok(id),
+            Err(e) => {
+                self.table.remove(id);
+                match e {
+                    TrySendError::Full(_) => Err(SubmitError::QueueFull),
+                    TrySendError::Disconnected(_) => Err(SubmitError::Stopped),
+                }
+            }
+        }
+    }
+}
+
+pub fn spawn<S, C>(player: Player<S, C>, capacity: usize) -> anyhow::Result<PlayerHandle>
+where
+    S: Servo + Send + 'static,
+    C: Clock + Clone + Send + Sync + 'static,
+{
+    let table = Arc::new(JobTable::new(player.clock.clone(), HISTORY_CAPACITY));
+    let (sender, receiver) = sync_channel(capacity);
+    let player_table = table.clone();
+    let stats = player.stats.clone();
+    thread::Builder::new()
+        .name("player".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || player.run(receiver, player_table))?;
+    Ok(PlayerHandle::new(sender, table, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::sequence;
+
+    const REST: u32 = 90;
+    const IMPACT: u32 = 30;
+
+    #[derive(Clone, Default)]
+    struct FakeClock(Arc<Mutex<Duration>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    // Duties with the time in ms they were set
+    type Duties = Arc<Mutex<Vec<(u64, u32)>>>;
+
+    // A degree is 10 duty
+    struct FakeServo {
+        clock: FakeClock,
+        duties: Duties,
+    }
+
+    impl Servo for FakeServo {
+        fn duty_for(&self, angle: u32) -> u32 {
+            angle * 10
+        }
+
+        fn set_duty(&mut self, duty: u32) -> anyhow::Result<()> {
+            let at = self.clock.now().as_millis() as u64;
+            self.duties.lock().unwrap().push((at, duty));
+            Ok(())
+        }
+
+        fn rest_angle(&self) -> u32 {
+            REST
+        }
+
+        fn impact_angle(&self) -> u32 {
+            IMPACT
+        }
+    }
+
+    fn player() -> (Player<FakeServo, FakeClock>, Duties) {
+        let clock = FakeClock::default();
+        let duties = Arc::new(Mutex::new(Vec::new()));
+        let servo = FakeServo {
+            clock: clock.clone(),
+            duties: duties.clone(),
+        };
+        (Player::new(servo, clock), duties)
+    }
+
+    fn taken(duties: &Duties) -> Vec<(u64, u32)> {
+        std::mem::take(&mut *duties.lock().unwrap())
+    }
+
+    #[test]
+    fn plays_steps_at_their_times() {
+        let (mut player, duties) = player();
+        let sequence = sequence::parse("90,200,30,500,90").unwrap();
+        assert_eq!(player.play(&sequence).unwrap(), Outcome::Completed);
+        assert_eq!(taken(&duties), [(0, 900), (200, 300), (700, 900)]);
+        assert_eq!(player.stats().angle(), Some(90));
+        assert_eq!(player.stats().strikes(), 1);
+    }
+
+    #[test]
+    fn ramps_once_per_tick() {
+        let (mut player, duties) = player();
+        // 50 degrees at 500 per second take five ticks
+        let sequence = sequence::parse("80,0,30@linear:500").unwrap();
+        player.play(&sequence).unwrap();
+        assert_eq!(
+            taken(&duties),
+            [
+                (0, 800),
+                (0, 700),
+                (20, 600),
+                (40, 500),
+                (60, 400),
+                (80, 300)
+            ]
+        );
+        assert_eq!(player.clock().now(), Duration::from_millis(100));
+        assert_eq!(player.stats().strikes(), 1);
+    }
+
+    #[test]
+    fn cancelled_pause_returns_to_rest() {
+        let (mut player, duties) = player();
+        let sequence = sequence::parse("30,1000,60").unwrap();
+        let clock = player.clock().clone();
+        let cancelled = move || clock.now() >= Duration::from_millis(120);
+        let outcome = player.play_until(&sequence, cancelled).unwrap();
+        assert_eq!(outcome, Outcome::Cancelled);
+        // Noticed at the first poll after 120 ms
+        assert_eq!(taken(&duties), [(0, 300), (150, REST * 10)]);
+    }
+
+    #[test]
+    fn cancelled_ramp_returns_to_rest() {
+        let (mut player, duties) = player();
+        let sequence = sequence::parse("0,0,100@ease:1000").unwrap();
+        let clock = player.clock().clone();
+        let cancelled = move || clock.now() >= Duration::from_millis(40);
+        assert_eq!(
+            player.play_until(&sequence, cancelled).unwrap(),
+            Outcome::Cancelled
+        );
+        let duties = taken(&duties);
+        assert_eq!(duties.len(), 4);
+        assert_eq!(duties.last(), Some(&(40, REST * 10)));
+    }
+
+    #[test]
+    fn runs_jobs_in_order_and_skips_cancelled_ones() {
+        let (player, duties) = player();
+        let table = Arc::new(JobTable::new(player.clock().clone(), HISTORY_CAPACITY));
+        let (sender, receiver) = sync_channel(2);
+        let handle = PlayerHandle::new(sender, table.clone(), player.stats().clone());
+        let first = handle.submit(sequence::parse("10").unwrap()).unwrap();
+        let second = handle.submit(sequence::parse("20").unwrap()).unwrap();
+        assert_eq!(
+            handle.submit(sequence::parse("40").unwrap()),
+            Err(SubmitError::QueueFull)
+        );
+        table.cancel(first).unwrap();
+        drop(handle);
+
+        player.run(receiver, table.clone());
+        assert_eq!(taken(&duties), [(0, 200)]);
+        assert_eq!(table.get(first).unwrap().state, JobState::Cancelled);
+        assert_eq!(table.get(second).unwrap().state, JobState::Finished);
+        // The rejected job is forgotten
+        assert_eq!(table.list().len(), 2);
+    }
+
+    #[test]
+    fn submit_fails_once_the_player_stopped() {
+        let (player, _) = player();
+        let table = Arc::new(JobTable::new(player.clock().clone(), HISTORY_CAPACITY));
+        let (sender, receiver) = sync_channel(1);
+        drop(receiver);
+        let handle = PlayerHandle::new(sender, table, player.stats().clone());
+        assert_eq!(
+            handle.submit(sequence::parse("10").unwrap()),
+            Err(SubmitError::Stopped)
+        );
+    }
+}
@@ -0,0 +1,576 @@
+// Limits on what anyone on the network can make the gong play: quiet hours,
+// token buckets per client and for everyone together, a longest sequence and
+// a pause between sequences. All of them are off until configured.
+//
+// Quiet hours are in the local time of `/config/time` and only apply once the
+// clock is synchronised. Sequences held back by them are played when they
+// end.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cron::Cron;
+use crate::sequence::StrikeSequence;
+use crate::storage::{self, Store};
+use crate::tz::{TimeZone, SECS_PER_DAY};
+
+pub const STORE_KEY: &str = "policy";
+
+// Clients with a bucket, the ones seen longest ago make room for new ones
+const MAX_CLIENTS: usize = 32;
+
+// Sequences waiting for the end of the quiet hours, they fit in the player
+// queue together
+pub const MAX_DEFERRED: usize = crate::player::QUEUE_CAPACITY;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietAction {
+    // Answered 403
+    Reject,
+    // Played when the quiet hours end
+    Queue,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuietHours {
+    // Local times as `HH:MM`, the end may be on the next day
+    pub start: String,
+    pub end: String,
+    pub action: QuietAction,
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        Self {
+            start: "22:00".into(),
+            end: "07:00".into(),
+            action: QuietAction::Reject,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    // Sequences that can be sent at once
+    pub burst: u32,
+    // Sequences added back to the bucket every minute
+    pub per_minute: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            per_minute: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySettings {
+    pub quiet_hours: Option<QuietHours>,
+    pub client_rate: Option<RateLimit>,
+    pub global_rate: Option<RateLimit>,
+    pub max_duration_ms: Option<u32>,
+    // Counted from the expected end of the sequence before
+    pub min_pause_ms: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    InvalidTime(String),
+    EmptyQuietHours,
+    InvalidRate,
+    InvalidDuration,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::InvalidTime(time) => {
+                write!(f, "`{}` is not a time of day like 22:00", time)
+            }
+            PolicyError::EmptyQuietHours => {
+                write!(f, "the quiet hours must not start and end together")
+            }
+            PolicyError::InvalidRate => {
+                write!(f, "rate limits need a burst and per_minute of at least 1")
+            }
+            PolicyError::InvalidDuration => write!(f, "max_duration_ms must be at least 1"),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl PolicySettings {
+    pub fn validate(&self) -> Result<(), PolicyError> {
+        if let Some(quiet) = &self.quiet_hours {
+            if minute_of_day(&quiet.start)? == minute_of_day(&quiet.end)? {
+                return Err(PolicyError::EmptyQuietHours);
+            }
+        }
+        for rate in self.client_rate.iter().chain(&self.global_rate) {
+            if rate.burst == 0 || rate.per_minute == 0 {
+                return Err(PolicyError::InvalidRate);
+            }
+        }
+        if self.max_duration_ms == Some(0) {
+            return Err(PolicyError::InvalidDuration);
+        }
+        Ok(())
+    }
+}
+
+// `HH:MM` as minutes after midnight
+fn minute_of_day(time: &str) -> Result<i64, PolicyError> {
+    let invalid = || PolicyError::InvalidTime(time.to_owned());
+    let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+    if minute.len() != 2 {
+        return Err(invalid());
+    }
+    let hour: i64 = hour.parse().map_err(|_| invalid())?;
+    let minute: i64 = minute.parse().map_err(|_| invalid())?;
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+        return Err(invalid());
+    }
+    Ok(hour * 60 + minute)
+}
+
+impl QuietHours {
+    fn contains(&self, local: i64) -> bool {
+        let (Ok(start), Ok(end)) = (minute_of_day(&self.start), minute_of_day(&self.end)) else {
+            return false;
+        };
+        let minute = local.rem_euclid(SECS_PER_DAY) / 60;
+        if start < end {
+            start <= minute && minute < end
+        } else {
+            minute >= start || minute < end
+        }
+    }
+
+    // The next end after `utc`, found like a daily schedule so that changes
+    // of the clocks are taken into account
+    fn next_end(&self, utc: i64, zone: &TimeZone) -> Option<i64> {
+        let end = minute_of_day(&self.end).ok()?;
+        Cron::parse(&format!("{} {} * * *", end % 60, end / 60))
+            .ok()?
+            .next_after(utc, zone)
+    }
+}
+
+// Why a sequence was not accepted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Denied {
+    // 403 for what waiting does not change, 429 otherwise
+    pub status: u16,
+    pub reason: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl Denied {
+    fn forbidden(reason: impl ToString) -> Self {
+        Self {
+            status: 403,
+            reason: reason.to_string(),
+            retry_after: None,
+        }
+    }
+
+    fn too_many(reason: impl ToString, retry_after: Duration) -> Self {
+        Self {
+            status: 429,
+            reason: reason.to_string(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    // Rounded up, so retrying after it succeeds. Also sent as `Retry-After`.
+    pub fn retry_after_s(&self) -> Option<u64> {
+        self.retry_after
+            .map(|after| (after.as_millis() as u64 + 999) / 1000)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "error": self.reason, "retry_after_s": self.retry_after_s() })
+    }
+}
+
+pub enum Verdict {
+    // With what it was charged, to be refunded if it cannot be queued
+    Play(StrikeSequence, Charge),
+    // Held back until the end of the quiet hours, at this time
+    Deferred(i64),
+}
+
+// What a played sequence took from the limits
+pub struct Charge {
+    client: Option<IpAddr>,
+    global: bool,
+    duration: Duration,
+    // `busy_until` before and after the sequence was booked
+    busy_before: Option<Instant>,
+    busy_after: Instant,
+}
+
+// The wall clock once it is synchronised, for the quiet hours
+pub struct LocalClock {
+    pub utc: i64,
+    pub zone: TimeZone,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * rate.per_minute as f64 / 60.0).min(rate.burst as f64);
+        self.updated = now;
+    }
+
+    // Time until the next token, zero if there is one
+    fn wait(&self, rate: &RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / rate.per_minute as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<IpAddr, Bucket>,
+    global: Option<Bucket>,
+    // Expected end of the sequences accepted so far
+    busy_until: Option<Instant>,
+    deferred: Vec<StrikeSequence>,
+}
+
+impl State {
+    // Takes a sequence that will not be played out of `busy_until`
+    fn unbook(&mut self, duration: Duration) {
+        self.busy_until = self
+            .busy_until
+            .map(|busy| busy.checked_sub(duration).unwrap_or(busy));
+    }
+}
+
+#[derive(Default)]
+pub struct Policy {
+    state: Mutex<State>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Decides on a sequence from `client`, `None` for the gong's own like
+    // MQTT and schedules, which only count towards the global rate. The
+    // buckets are only drawn from for sequences that are played, and are
+    // given back with `refund` if the player does not take them.
+    pub fn check(
+        &self,
+        settings: &PolicySettings,
+        client: Option<IpAddr>,
+        sequence: StrikeSequence,
+        clock: Option<LocalClock>,
+    ) -> Result<Verdict, Denied> {
+        let duration = sequence.duration();
+        if let Some(max) = settings.max_duration_ms {
+            if duration > Duration::from_millis(max as u64) {
+                return Err(Denied::forbidden(format!(
+                    "the sequence lasts {} ms, at most {} ms are allowed",
+                    duration.as_millis(),
+                    max
+                )));
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let (Some(quiet), Some(clock)) = (&settings.quiet_hours, &clock) {
+            if quiet.contains(clock.zone.to_local(clock.utc)) {
+                let reason = format!("quiet hours until {}", quiet.end);
+                match quiet.action {
+                    QuietAction::Reject => return Err(Denied::forbidden(reason)),
+                    QuietAction::Queue => {
+                        let until = quiet
+                            .next_end(clock.utc, &clock.zone)
+                            .ok_or_else(|| Denied::forbidden(&reason))?;
+                        if state.deferred.len() >= MAX_DEFERRED {
+                            return Err(Denied::too_many(
+                                format!(
+                                    "{}, {} sequences are already waiting",
+                                    reason, MAX_DEFERRED
+                                ),
+                                Duration::from_secs((until - clock.utc).max(0) as u64),
+                            ));
+                        }
+                        state.deferred.push(sequence);
+                        return Ok(Verdict::Deferred(until));
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if let Some(busy_until) = state.busy_until {
+            let free_at = busy_until + Duration::from_millis(settings.min_pause_ms as u64);
+            if settings.min_pause_ms > 0 && now < free_at {
+                return Err(Denied::too_many(
+                    format!(
+                        "sequences need a pause of {} ms between them",
+                        settings.min_pause_ms
+                    ),
+                    free_at - now,
+                ));
+            }
+        }
+
+        let client_rate = settings.client_rate.zip(client);
+        if let Some((rate, client)) = client_rate {
+            if !state.clients.contains_key(&client) && state.clients.len() >= MAX_CLIENTS {
+                let oldest = state
+                    .clients
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(&client, _)| client);
+                if let Some(oldest) = oldest {
+                    state.clients.remove(&oldest);
+                }
+            }
+            let bucket = state
+                .clients
+                .entry(client)
+                .or_insert_with(|| Bucket::full(&rate, now));
+            bucket.refill(&rate, now);
+            let wait = bucket.wait(&rate);
+            if !wait.is_zero() {
+                return Err(Denied::too_many(
+                    format!(
+                        "at most {} sequences a minute from one client",
+                        rate.per_minute
+                    ),
+                    wait,
+                ));
+            }
+        }
+        if let Some(rate) = settings.global_rate {
+            let bucket = state.global.get_or_insert_with(|| Bucket::full(&rate, now));
+            bucket.refill(&rate, now);
+            let wait = bucket.wait(&rate);
+            if !wait.is_zero() {
+                return Err(Denied::too_many(
+                    format!("at most {} sequences a minute", rate.per_minute),
+                    wait,
+                ));
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let Some((_, client)) = client_rate {
+            if let Some(bucket) = state.clients.get_mut(&client) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        let busy_before = state.busy_until;
+        let busy_after = busy_before.map_or(now, |busy| busy.max(now)) + duration;
+        state.busy_until = Some(busy_after);
+        let charge = Charge {
+            client: client_rate.map(|(_, client)| client),
+            global: settings.global_rate.is_some(),
+            duration,
+            busy_before,
+            busy_after,
+        };
+        Ok(Verdict::Play(sequence, charge))
+    }
+
+    // Gives back what a sequence that could not be queued was charged. The
+    // buckets are clamped to their burst again on the next refill.
+    pub fn refund(&self, charge: Charge) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = charge
+            .client
+            .and_then(|client| state.clients.get_mut(&client))
+        {
+            bucket.tokens += 1.0;
+        }
+        if charge.global {
+            if let Some(bucket) = &mut state.global {
+                bucket.tokens += 1.0;
+            }
+        }
+        // Unless another sequence was booked after it
+        if state.busy_until == Some(charge.busy_after) {
+            state.busy_until = charge.busy_before;
+        } else {
+            state.unbook(charge.duration);
+        }
+    }
+
+    // The deferred sequences once the quiet hours are over, or when they
+    // were switched off
+    pub fn release(
+        &self,
+        settings: &PolicySettings,
+        clock: Option<LocalClock>,
+    ) -> Vec<StrikeSequence> {
+        let quiet = match (&settings.quiet_hours, &clock) {
+            (Some(quiet), Some(clock)) => {
+                quiet.action == QuietAction::Queue && quiet.contains(clock.zone.to_local(clock.utc))
+            }
+            _ => false,
+        };
+        let mut state = self.state.lock().unwrap();
+        if quiet || state.deferred.is_empty() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let released = std::mem::take(&mut state.deferred);
+        let duration: Duration = released.iter().map(StrikeSequence::duration).sum();
+        state.busy_until = Some(state.busy_until.map_or(now, |busy| busy.max(now)) + duration);
+        released
+    }
+
+    // Puts released sequences the player did not take back in front of the
+    // deferred ones, to be released again
+    pub fn requeue(&self, sequences: Vec<StrikeSequence>) {
+        let mut state = self.state.lock().unwrap();
+        let duration: Duration = sequences.iter().map(StrikeSequence::duration).sum();
+        state.unbook(duration);
+        state.deferred.splice(0..0, sequences);
+    }
+
+    pub fn deferred(&self) -> usize {
+        self.state.lock().unwrap().deferred.len()
+    }
+}
+
+// The saved settings, or no limits if there are none that are usable
+pub fn load(store: &dyn Store) -> PolicySettings {
+    let settings: PolicySettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved policy: {}", e);
+            PolicySettings::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 7)));
+
+    fn one_a_minute() -> PolicySettings {
+        PolicySettings {
+            client_rate: Some(RateLimit {
+                burst: 1,
+                per_minute: 1,
+            }),
+            global_rate: Some(RateLimit {
+                burst: 1,
+                per_minute: 1,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn strike() -> StrikeSequence {
+        sequence::parse("90,200,30").unwrap()
+    }
+
+    #[test]
+    fn draws_from_the_buckets() {
+        let policy = Policy::new();
+        let settings = one_a_minute();
+        assert!(matches!(
+            policy.check(&settings, CLIENT, strike(), None),
+            Ok(Verdict::Play(..))
+        ));
+        let Err(denied) = policy.check(&settings, CLIENT, strike(), None) else {
+            panic!("a second sequence was played");
+        };
+        assert_eq!(denied.status, 429);
+        assert_eq!(denied.retry_after_s(), Some(60));
+    }
+
+    #[test]
+    fn refund_gives_back_the_charge() {
+        let policy = Policy::new();
+        let settings = PolicySettings {
+            min_pause_ms: 1000,
+            ..one_a_minute()
+        };
+        let Ok(Verdict::Play(_, charge)) = policy.check(&settings, CLIENT, strike(), None) else {
+            panic!("the sequence was not played");
+        };
+        policy.refund(charge);
+        assert!(matches!(
+            policy.check(&settings, CLIENT, strike(), None),
+            Ok(Verdict::Play(..))
+        ));
+    }
+
+    #[test]
+    fn requeued_sequences_are_released_first() {
+        let policy = Policy::new();
+        let settings = PolicySettings {
+            quiet_hours: Some(QuietHours {
+                action: QuietAction::Queue,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        // 23:00 UTC on 2023-11-14
+        let night = || {
+            Some(LocalClock {
+                utc: 1_700_002_800,
+                zone: TimeZone::UTC,
+            })
+        };
+        let other = sequence::parse("45,100,10").unwrap();
+        for sequence in [strike(), other.clone()] {
+            assert!(matches!(
+                policy.check(&settings, CLIENT, sequence, night()),
+                Ok(Verdict::Deferred(_))
+            ));
+        }
+        assert!(policy.release(&settings, night()).is_empty());
+
+        let released = policy.release(&settings, None);
+        assert_eq!(released, vec![strike(), other.clone()]);
+        assert_eq!(policy.deferred(), 0);
+        policy.requeue(vec![other.clone()]);
+        assert_eq!(policy.deferred(), 1);
+        assert_eq!(policy.release(&settings, None), vec![other]);
+    }
+}
@@ -0,0 +1,501 @@
+// The setup page served in access point mode, when the gong has no WiFi it
+// can connect to. Every GET shows the page, so phones and laptops open it as
+// a captive portal, and the form posts the credentials to `/setup`.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use log::*;
+
+use crate::api::{Method, Request, Response};
+use crate::ip::{self, IpConfig, IpError, StaticIp};
+use crate::storage::{self, Store};
+use crate::wifi::{self, AuthMethod, WifiError, WifiSettings};
+
+// Name of the open network the gong creates for the setup
+pub const SETUP_SSID: &str = "gong-setup";
+
+// Paths ending in `/*` match any path
+pub const ROUTES: &[(&str, Method)] = &[("/setup", Method::Post), ("/*", Method::Get)];
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Gong setup</title>
+<style>
+body { font-family: sans-serif; max-width: 24em; margin: 2em auto; padding: 0 1em; }
+label { display: block; margin-top: 1em; }
+input, select { width: 100%; box-sizing: border-box; padding: 0.4em; }
+button { margin-top: 1.5em; padding: 0.6em 1.2em; }
+.error { color: #b00; }
+</style>
+</head>
+<body>
+<h1>Gong setup</h1>
+{message}
+{networks}
+<form method="post" action="/setup">
+<label>Network (SSID)<input name="ssid" value="{ssid}" maxlength="32" required></label>
+<label>Password<input name="password" type="password" maxlength="64"></label>
+<label>Security
+<select name="auth_method">
+<option value="auto"{auto}>Automatic</option>
+<option value="open"{open}>Open</option>
+<option value="wpa2"{wpa2}>WPA2</option>
+<option value="wpa3"{wpa3}>WPA3</option>
+<option value="wpa2_wpa3"{wpa2_wpa3}>WPA2/WPA3</option>
+</select></label>
+<label>IP address
+<select name="ip_mode">
+<option value="dhcp"{dhcp}>Automatic (DHCP)</option>
+<option value="static"{static}>Static</option>
+</select></label>
+<label>Static address<input name="address" value="{address}" placeholder="192.168.1.20/24"></label>
+<label>Gateway<input name="gateway" value="{gateway}" placeholder="192.168.1.1"></label>
+<label>DNS server<input name="dns" value="{dns}" placeholder="optional"></label>
+<label>Second DNS server<input name="secondary_dns" value="{secondary_dns}" placeholder="optional"></label>
+<button type="submit">Save and restart</button>
+</form>
+</body>
+</html>
+"#;
+
+const SAVED_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Gong setup</title></head>
+<body style="font-family: sans-serif; max-width: 24em; margin: 2em auto;">
+<h1>Saved</h1>
+<p>The gong restarts and connects to <b>{ssid}</b> or another saved network in range. If it cannot, the <b>gong-setup</b> network comes back.</p>
+</body>
+</html>
+"#;
+
+pub struct Portal {
+    store: Arc<dyn Store>,
+    // Called after new credentials are saved, the firmware restarts
+    on_saved: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Portal {
+    pub fn new(store: Arc<dyn Store>, on_saved: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            store,
+            on_saved: Box::new(on_saved),
+        }
+    }
+
+    pub fn handle(&self, req: &Request) -> Response {
+        match (req.method, req.path()) {
+            (Method::Post, "/setup") => self.post_setup(req.body),
+            (Method::Get, _) => {
+                let ip = ip::load(&*self.store);
+                Response::html(200, self.page(None, ip.as_ref(), None))
+            }
+            _ => Response::error(405, "method not allowed"),
+        }
+    }
+
+    fn post_setup(&self, body: &[u8]) -> Response {
+        let (settings, ip) = match parse_form(body) {
+            Ok(form) => form,
+            Err(e) => return Response::html(400, self.page(None, None, Some(&e.to_string()))),
+        };
+        // The network is added to the saved ones, keeping its priority if it
+        // was already known
+        let mut networks = wifi::load(&*self.store);
+        let settings = WifiSettings {
+            priority: networks
+                .iter()
+                .find(|network| network.ssid == settings.ssid)
+                .map_or(settings.priority, |network| network.priority),
+            ..settings
+        };
+        wifi::add(&mut networks, settings.clone());
+        let saved = storage::save(&*self.store, ip::STORE_KEY, &ip)
+            .and_then(|()| wifi::save(&*self.store, &networks));
+        if let Err(e) = saved {
+            error!("Could not save WiFi settings: {:?}", e);
+            return Response::html(
+                500,
+                self.page(
+                    Some(&settings),
+                    Some(&ip),
+                    Some("could not save the settings"),
+                ),
+            );
+        }
+        info!("WiFi settings for {} with {} saved", settings.ssid, ip);
+        (self.on_saved)();
+        Response::html(200, SAVED_PAGE.replace("{ssid}", &escape(&settings.ssid)))
+    }
+
+    // The form, filled in with `settings` and `ip` if given, after a list of
+    // the saved networks
+    fn page(
+        &self,
+        settings: Option<&WifiSettings>,
+        ip: Option<&IpConfig>,
+        error: Option<&str>,
+    ) -> String {
+        let saved: Vec<String> = wifi::load(&*self.store)
+            .iter()
+            .map(|network| format!("<b>{}</b>", escape(&network.ssid)))
+            .collect();
+        let networks = if saved.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<p>Saved networks: {}. Entering one of them again replaces it.</p>",
+                saved.join(", ")
+            )
+        };
+        let auth_method = settings
+            .map(|settings| settings.auth_method)
+            .unwrap_or_default();
+        let selected = |value: AuthMethod| {
+            if auth_method == value {
+                " selected"
+            } else {
+                ""
+            }
+        };
+        let static_ip = match ip {
+            Some(IpConfig::Static(static_ip)) => Some(static_ip),
+            _ => None,
+        };
+        let show = |address: Option<Ipv4Addr>| {
+            address
+                .map(|address| address.to_string())
+                .unwrap_or_default()
+        };
+        PAGE.replace("{networks}", &networks)
+            .replace("{auto}", selected(AuthMethod::Auto))
+            .replace("{open}", selected(AuthMethod::Open))
+            .replace("{wpa2}", selected(AuthMethod::Wpa2))
+            .replace("{wpa3}", selected(AuthMethod::Wpa3))
+            .replace("{wpa2_wpa3}", selected(AuthMethod::Wpa2Wpa3))
+            .replace(
+                "{message}",
+                &error
+                    .map(|e| format!("<p class=\"error\">{}</p>", escape(e)))
+                    .unwrap_or_default(),
+            )
+            .replace(
+                "{ssid}",
+                &escape(
+                    settings
+                        .map(|settings| settings.ssid.as_str())
+                        .unwrap_or(""),
+                ),
+            )
+            .replace("{dhcp}", if static_ip.is_some() { "" } else { " selected" })
+            .replace(
+                "{static}",
+                if static_ip.is_some() { " selected" } else { "" },
+            )
+            .replace(
+                "{address}",
+                &static_ip
+                    .map(|ip| format!("{}/{}", ip.address, ip.prefix))
+                    .unwrap_or_default(),
+            )
+            .replace("{gateway}", &show(static_ip.map(|ip| ip.gateway)))
+            .replace("{dns}", &show(static_ip.and_then(|ip| ip.dns)))
+            .replace(
+                "{secondary_dns}",
+                &show(static_ip.and_then(|ip| ip.secondary_dns)),
+            )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormError {
+    Wifi(WifiError),
+    // The field and what is wrong with it
+    Ip(&'static str, IpError),
+    UnknownIpMode(String),
+    UnknownAuthMethod(String),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::Wifi(e) => e.fmt(f),
+            FormError::Ip(field, e) => write!(f, "{}: {}", field, e),
+            FormError::UnknownIpMode(mode) => {
+                write!(f, "unknown IP mode `{}`, expected dhcp or static", mode)
+            }
+            FormError::UnknownAuthMethod(method) => {
+                write!(f, "unknown security `{}`", method)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+// Reads the submitted setup form
+pub fn parse_form(body: &[u8]) -> Result<(WifiSettings, IpConfig), FormError> {
+    let mut ssid = String::new();
+    let mut password = String::new();
+    let mut auth_method = String::from("auto");
+    let mut ip_mode = String::from("dhcp");
+    let mut address = String::new();
+    let mut gateway = String::new();
+    let mut dns = String::new();
+    let mut secondary_dns = String::new();
+    for pair in String::from_utf8_lossy(body).split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = form_decode(value);
+        match key {
+            "ssid" => ssid = value,
+            "password" => password = value,
+            "auth_method" => auth_method = value,
+            "ip_mode" => ip_mode = value,
+            "address" => address = value,
+            "gateway" => gateway = value,
+            "dns" => dns = value,
+            "secondary_dns" => secondary_dns = value,
+            _ => {}
+        }
+    }
+
+    let auth_method = match auth_method.as_str() {
+        "auto" => AuthMethod::Auto,
+        "open" => AuthMethod::Open,
+        "wpa2" => AuthMethod::Wpa2,
+        "wpa3" => AuthMethod::Wpa3,
+        "wpa2_wpa3" => AuthMethod::Wpa2Wpa3,
+        _ => return Err(FormError::UnknownAuthMethod(auth_method)),
+    };
+    let settings = WifiSettings {
+        ssid,
+        password,
+        auth_method,
+        priority: 0,
+    };
+    settings.validate().map_err(FormError::Wifi)?;
+
+    let ip = match ip_mode.as_str() {
+        "dhcp" => IpConfig::Dhcp,
+        "static" => {
+            let (address, prefix) =
+                ip::parse_cidr(&address).map_err(|e| FormError::Ip("Static address", e))?;
+            let optional = |value: &str, field| match value.trim() {
+                "" => Ok(None),
+                value => ip::parse_address(value)
+                    .map(Some)
+                    .map_err(|e| FormError::Ip(field, e)),
+            };
+            let static_ip = StaticIp {
+                address,
+                prefix: prefix.unwrap_or(24),
+                gateway: ip::parse_address(&gateway).map_err(|e| FormError::Ip("Gateway", e))?,
+                dns: optional(&dns, "DNS server")?,
+                secondary_dns: optional(&secondary_dns, "Second DNS server")?,
+            };
+            static_ip
+                .validate()
+                .map_err(|e| FormError::Ip("Static address", e))?;
+            IpConfig::Static(static_ip)
+        }
+        _ => return Err(FormError::UnknownIpMode(ip_mode)),
+    };
+    Ok((settings, ip))
+}
+
+// Undoes the `application/x-www-form-urlencoded` encoding of a value
+fn form_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next(), input.next()];
+                let decoded = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                match decoded {
+                    Some(decoded) => bytes.push(decoded),
+                    // Malformed escapes are kept as they are
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_form_values() {
+        assert_eq!(form_decode("my+home"), "my home");
+        assert_eq!(form_decode("caf%C3%A9%21"), "café!");
+        assert_eq!(form_decode("%2B%25%26%3D"), "+%&=");
+        assert_eq!(form_decode("%2b"), "+");
+        assert_eq!(form_decode(""), "");
+    }
+
+    #[test]
+    fn keeps_malformed_escapes() {
+        assert_eq!(form_decode("100%"), "100%");
+        assert_eq!(form_decode("%4"), "%4");
+        assert_eq!(form_decode("%zz"), "%zz");
+        assert_eq!(form_decode("a%g1b"), "a%g1b");
+        // A multi-byte character after the `%` is kept whole
+        assert_eq!(form_decode("%é"), "%é");
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        assert_eq!(form_decode("%FF%FEok"), "\u{FFFD}\u{FFFD}ok");
+        let (settings, _) = parse_form(b"ssid=home\xff&password=password").unwrap();
+        assert_eq!(settings.ssid, "home\u{FFFD}");
+    }
+
+    #[test]
+    fn parses_dhcp_form() {
+        let (settings, ip) =
+            parse_form(b"ssid=My+Home%21&password=p%40ss+word&auth_method=wpa3&ip_mode=dhcp")
+                .unwrap();
+        assert_eq!(
+            settings,
+            WifiSettings {
+                ssid: "My Home!".into(),
+                password: "p@ss word".into(),
+                auth_method: AuthMethod::Wpa3,
+                priority: 0,
+            }
+        );
+        assert_eq!(ip, IpConfig::Dhcp);
+
+        // Defaults to automatic security and DHCP, ignoring unknown fields
+        let (settings, ip) = parse_form(b"ssid=home&password=password&submit&x=1").unwrap();
+        assert_eq!(settings.auth_method, AuthMethod::Auto);
+        assert_eq!(ip, IpConfig::Dhcp);
+    }
+
+    #[test]
+    fn parses_static_form() {
+        let form = b"ssid=home&password=password&ip_mode=static&address=192.168.1.50%2F24\
+            &gateway=192.168.1.1&dns=1.1.1.1&secondary_dns=";
+        let (_, ip) = parse_form(form).unwrap();
+        assert_eq!(
+            ip,
+            IpConfig::Static(StaticIp {
+                address: [192, 168, 1, 50].into(),
+                prefix: 24,
+                gateway: [192, 168, 1, 1].into(),
+                dns: Some([1, 1, 1, 1].into()),
+                secondary_dns: None,
+            })
+        );
+
+        // Without a prefix the network is a /24
+        let form = b"ssid=home&password=password&ip_mode=static&address=10.0.0.5&gateway=10.0.0.1";
+        match parse_form(form).unwrap().1 {
+            IpConfig::Static(static_ip) => assert_eq!(static_ip.prefix, 24),
+            ip => panic!("expected a static address, got {:?}", ip),
+        }
+    }
+
+    #[test]
+    fn rejects_missing_ssid() {
+        assert_eq!(
+            parse_form(b"password=password").unwrap_err(),
+            FormError::Wifi(WifiError::SsidLength)
+        );
+        assert_eq!(
+            parse_form(b"ssid=&password=password").unwrap_err(),
+            FormError::Wifi(WifiError::SsidLength)
+        );
+        assert_eq!(
+            parse_form(b"").unwrap_err(),
+            FormError::Wifi(WifiError::SsidLength)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_auth_fields() {
+        assert_eq!(
+            parse_form(b"ssid=home&password=password&auth_method=wep").unwrap_err(),
+            FormError::UnknownAuthMethod("wep".into())
+        );
+        assert_eq!(
+            parse_form(b"ssid=home&password=short").unwrap_err(),
+            FormError::Wifi(WifiError::PasswordLength)
+        );
+        assert_eq!(
+            parse_form(b"ssid=home&password=password&auth_method=open").unwrap_err(),
+            FormError::Wifi(WifiError::PasswordForOpen)
+        );
+        assert_eq!(
+            parse_form(b"ssid=home&auth_method=wpa2").unwrap_err(),
+            FormError::Wifi(WifiError::PasswordMissing)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_static_fields() {
+        let form = |fields: &str| format!("ssid=home&password=password&ip_mode=static&{}", fields);
+        let error = |fields: &str| parse_form(form(fields).as_bytes()).unwrap_err();
+
+        assert_eq!(
+            error("address=192.168.1.300&gateway=192.168.1.1"),
+            FormError::Ip("Static address", IpError::InvalidOctet(4, "300".into()))
+        );
+        assert_eq!(
+            error("address=192.168.1.50%2F33&gateway=192.168.1.1"),
+            FormError::Ip("Static address", IpError::PrefixRange(33))
+        );
+        assert_eq!(
+            error("gateway=192.168.1.1"),
+            FormError::Ip("Static address", IpError::Empty)
+        );
+        assert_eq!(
+            error("address=192.168.1.50"),
+            FormError::Ip("Gateway", IpError::Empty)
+        );
+        assert_eq!(
+            error("address=192.168.1.50&gateway=10.0.0.1"),
+            FormError::Ip("Static address", IpError::GatewayOutsideNetwork)
+        );
+        assert_eq!(
+            error("address=192.168.1.0&gateway=192.168.1.1"),
+            FormError::Ip("Static address", IpError::HostAddress)
+        );
+        assert_eq!(
+            error("address=192.168.1.50&gateway=192.168.1.1&dns=1.1.1"),
+            FormError::Ip("DNS server", IpError::OctetCount(3))
+        );
+        assert_eq!(
+            error("address=192.168.1.50&gateway=192.168.1.1&secondary_dns=dns"),
+            FormError::Ip("Second DNS server", IpError::OctetCount(1))
+        );
+        assert_eq!(
+            parse_form(b"ssid=home&password=password&ip_mode=manual").unwrap_err(),
+            FormError::UnknownIpMode("manual".into())
+        );
+    }
+}
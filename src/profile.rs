@@ -0,0 +1,240 @@
+// Motion profiles for servo moves. Instead of jumping to the target the
+// player steps the duty every `TICK` along the trajectory of the profile.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// One period of the 50 Hz servo signal, updating faster has no effect
+pub const TICK: Duration = Duration::from_millis(20);
+
+// Longest a single move may take
+pub const MAX_MOVE_MS: u32 = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MotionProfile {
+    // Jump to the target at the servo's full speed
+    #[default]
+    Instant,
+    // Constant speed in degrees per second
+    Linear {
+        deg_per_s: u32,
+    },
+    // Sinusoidal acceleration and deceleration over the given time
+    EaseInOut {
+        duration_ms: u32,
+    },
+    // Smoother start and stop than `EaseInOut`, with zero acceleration at
+    // both ends
+    SCurve {
+        duration_ms: u32,
+    },
+}
+
+impl MotionProfile {
+    pub fn duration(&self, from_angle: u32, to_angle: u32) -> Duration {
+        match *self {
+            MotionProfile::Instant => Duration::ZERO,
+            MotionProfile::Linear { deg_per_s } => {
+                let degrees = from_angle.abs_diff(to_angle) as u64;
+                Duration::from_millis(degrees * 1000 / deg_per_s.max(1) as u64)
+            }
+            MotionProfile::EaseInOut { duration_ms } | MotionProfile::SCurve { duration_ms } => {
+                Duration::from_millis(duration_ms as u64)
+            }
+        }
+    }
+
+    // Fraction of the way covered after the fraction `t` of the move's time
+    pub fn progress(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            MotionProfile::Instant => 1.0,
+            MotionProfile::Linear { .. } => t,
+            MotionProfile::EaseInOut { .. } => (1.0 - (std::f32::consts::PI * t).cos()) / 2.0,
+            MotionProfile::SCurve { .. } => t * t * t * (t * (t * 6.0 - 15.0) + 10.0),
+        }
+    }
+}
+
+// Duty values to apply once per tick to move from `from_duty` (the position
+// at `from_angle`) to `to_duty`. The last value is always `to_duty`.
+pub fn duty_trajectory(
+    profile: MotionProfile,
+    from_angle: u32,
+    to_angle: u32,
+    from_duty: u32,
+    to_duty: u32,
+    tick: Duration,
+) -> Vec<u32> {
+    let duration = profile.duration(from_angle, to_angle);
+    let ticks = (duration.as_micros() / tick.as_micros().max(1)).max(1) as u32;
+    let span = to_duty as f32 - from_duty as f32;
+    (1..=ticks)
+        .map(|tick| {
+            if tick == ticks {
+                to_duty
+            } else {
+                let progress = profile.progress(tick as f32 / ticks as f32);
+                (from_duty as f32 + span * progress).round() as u32
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProfileError(pub String);
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+// Parses `instant`, `linear:{deg/s}`, `ease:{ms}` or `scurve:{ms}`
+impl FromStr for MotionProfile {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim())),
+            None => (s.trim(), None),
+        };
+        let number = |what: &str, max: u32| -> Result<u32, ProfileError> {
+            let arg = arg.ok_or_else(|| ProfileError(format!("{} needs {}", name, what)))?;
+            match arg.parse::<u32>() {
+                Ok(value)
+                    if value > 0 && value <= max && arg.bytes().all(|b| b.is_ascii_digit()) =>
+                {
+                    Ok(value)
+                }
+                _ => Err(ProfileError(format!(
+                    "{} of {} must be a number from 1 to {}",
+                    what, name, max
+                ))),
+            }
+        };
+        match name {
+            "instant" if arg.is_none() => Ok(MotionProfile::Instant),
+            "linear" => Ok(MotionProfile::Linear {
+                deg_per_s: number("a speed in degrees per second", 10_000)?,
+            }),
+            "ease" => Ok(MotionProfile::EaseInOut {
+                duration_ms: number("a duration in milliseconds", MAX_MOVE_MS)?,
+            }),
+            "scurve" => Ok(MotionProfile::SCurve {
+                duration_ms: number("a duration in milliseconds", MAX_MOVE_MS)?,
+            }),
+            _ => Err(ProfileError(format!(
+                "unknown motion profile `{}`, expected instant, linear:{{deg/s}}, ease:{{ms}} or scurve:{{ms}}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for MotionProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotionProfile::Instant => write!(f, "instant"),
+            MotionProfile::Linear { deg_per_s } => write!(f, "linear:{}", deg_per_s),
+            MotionProfile::EaseInOut { duration_ms } => write!(f, "ease:{}", duration_ms),
+            MotionProfile::SCurve { duration_ms } => write!(f, "scurve:{}", duration_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trajectory(profile: &str, from: u32, to: u32) -> Vec<u32> {
+        let profile: MotionProfile = profile.parse().unwrap();
+        // A degree is 10 duty
+        duty_trajectory(profile, from, to, from * 10, to * 10, TICK)
+    }
+
+    #[test]
+    fn instant_jumps_to_the_target() {
+        assert_eq!(trajectory("instant", 0, 180), [1800]);
+    }
+
+    #[test]
+    fn linear_steps_evenly() {
+        // 100 degrees at 1000 per second take five ticks
+        assert_eq!(
+            trajectory("linear:1000", 0, 100),
+            [200, 400, 600, 800, 1000]
+        );
+        assert_eq!(trajectory("linear:1000", 100, 0), [800, 600, 400, 200, 0]);
+        // Shorter than a tick
+        assert_eq!(trajectory("linear:10000", 0, 100), [1000]);
+    }
+
+    #[test]
+    fn eased_moves_start_and_end_slowly() {
+        for profile in ["ease:400", "scurve:400"] {
+            let duties = trajectory(profile, 0, 90);
+            assert_eq!(duties.len(), 20, "{}", profile);
+            assert_eq!(duties.last(), Some(&900));
+            assert!(duties.windows(2).all(|pair| pair[0] <= pair[1]));
+            let steps: Vec<u32> = std::iter::once(duties[0])
+                .chain(duties.windows(2).map(|pair| pair[1] - pair[0]))
+                .collect();
+            let fastest = *steps.iter().max().unwrap();
+            assert!(steps[0] < fastest / 4, "{}: {:?}", profile, steps);
+            assert!(steps[19] < fastest / 4, "{}: {:?}", profile, steps);
+            // Symmetric around the middle
+            assert_eq!(duties[9], 450, "{}", profile);
+        }
+    }
+
+    #[test]
+    fn scurve_is_gentler_at_the_ends_than_ease() {
+        let ease = trajectory("ease:400", 0, 90);
+        let scurve = trajectory("scurve:400", 0, 90);
+        assert!(scurve[0] < ease[0]);
+        assert!(900 - scurve[18] < 900 - ease[18]);
+    }
+
+    #[test]
+    fn progress_stays_within_the_move() {
+        for profile in ["instant", "linear:100", "ease:100", "scurve:100"] {
+            let profile: MotionProfile = profile.parse().unwrap();
+            assert_eq!(profile.progress(1.0), 1.0);
+            assert_eq!(profile.progress(2.0), 1.0);
+            for t in 0..=100 {
+                let progress = profile.progress(t as f32 / 100.0);
+                assert!((0.0..=1.0).contains(&progress), "{}", profile);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_profiles() {
+        assert_eq!("instant".parse(), Ok(MotionProfile::Instant));
+        assert_eq!(
+            " linear : 300".parse(),
+            Ok(MotionProfile::Linear { deg_per_s: 300 })
+        );
+        assert_eq!(
+            "scurve:10000".parse(),
+            Ok(MotionProfile::SCurve {
+                duration_ms: 10_000
+            })
+        );
+        for profile in [
+            "",
+            "instant:1",
+            "linear",
+            "linear:0",
+            "ease:10001",
+            "ease:+5",
+            "warp:1",
+        ] {
+            assert!(profile.parse::<MotionProfile>().is_err(), "{}", profile);
+        }
+    }
+}
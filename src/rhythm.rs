@@ -0,0 +1,949 @@
+// Compiler for the rhythm notation accepted by `/pattern`, e.g.
+//
+//     bpm=120; x . x x | X - - -
+//     (x o x o)x4
+//
+// Every symbol takes one step, a beat at the current tempo divided by `div`:
+//
+//   x     strike at `velocity`
+//   X     accented strike at `accent`
+//   o     ghost strike at `ghost`
+//   .     rest
+//   -     holds the previous note or rest for one more step
+//   |     bar line, only for readability
+//   (..)xN  repeats the group N times
+//   name=value  changes a setting for everything after it
+//   #     comment until the end of the line
+//
+// Whitespace, newlines and `;` separate symbols but are otherwise ignored.
+// The pattern compiles to the same steps as `/servo`, timed so that every
+// strike hits the gong on its beat.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::calibration::ServoCalibration;
+use crate::profile::MotionProfile;
+use crate::sequence::{self, Step, StrikeSequence, MAX_PAUSE_MS, MAX_STEPS};
+use crate::strike::{self, Strike};
+
+pub const MAX_REPEAT: u32 = 64;
+pub const MAX_STRIKES: usize = 256;
+// Groups nested deeper are refused, the parser recurses for each
+pub const MAX_DEPTH: usize = 8;
+// Symbols after expanding the repeats, rests and holds included
+pub const MAX_SYMBOLS: usize = 4096;
+
+// Shortest backswing a strike is squeezed to when notes follow closely
+pub const MIN_BACKSWING_MS: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RhythmErrorKind {
+    UnexpectedChar(char),
+    UnclosedGroup,
+    UnopenedGroup,
+    TooDeep,
+    InvalidRepeat,
+    UnknownSetting(String),
+    InvalidSetting(String),
+    DanglingHold,
+    NoStrikes,
+    TooManyStrikes,
+    TooManySymbols,
+    // The compiled sequence is longer than `/servo` accepts
+    TooManySteps,
+    // Strikes are closer together than the servo can manage
+    TooFast {
+        time_ms: u64,
+        gap_ms: u64,
+        needed_ms: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RhythmError {
+    pub kind: RhythmErrorKind,
+    // Where in the notation the error is, if the timeline came from text
+    pub location: Option<Location>,
+}
+
+impl RhythmError {
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            RhythmErrorKind::UnexpectedChar(_) => "unexpected_char",
+            RhythmErrorKind::UnclosedGroup => "unclosed_group",
+            RhythmErrorKind::UnopenedGroup => "unopened_group",
+            RhythmErrorKind::TooDeep => "too_deep",
+            RhythmErrorKind::InvalidRepeat => "invalid_repeat",
+            RhythmErrorKind::UnknownSetting(_) => "unknown_setting",
+            RhythmErrorKind::InvalidSetting(_) => "invalid_setting",
+            RhythmErrorKind::DanglingHold => "dangling_hold",
+            RhythmErrorKind::NoStrikes => "no_strikes",
+            RhythmErrorKind::TooManyStrikes => "too_many_strikes",
+            RhythmErrorKind::TooManySymbols => "too_many_symbols",
+            RhythmErrorKind::TooManySteps => "too_many_steps",
+            RhythmErrorKind::TooFast { .. } => "too_fast",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(location) = self.location {
+            json["line"] = location.line.into();
+            json["column"] = location.column.into();
+        }
+        json.to_string()
+    }
+}
+
+impl fmt::Display for RhythmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = self.location {
+            write!(f, "{}: ", location)?;
+        }
+        match &self.kind {
+            RhythmErrorKind::UnexpectedChar(c) => write!(f, "unexpected character `{}`", c),
+            RhythmErrorKind::UnclosedGroup => write!(f, "`(` is never closed"),
+            RhythmErrorKind::UnopenedGroup => write!(f, "`)` without a matching `(`"),
+            RhythmErrorKind::TooDeep => {
+                write!(f, "groups can be nested at most {} deep", MAX_DEPTH)
+            }
+            RhythmErrorKind::InvalidRepeat => {
+                write!(f, "repeat count must be from 1 to {}", MAX_REPEAT)
+            }
+            RhythmErrorKind::UnknownSetting(name) => write!(
+                f,
+                "unknown setting `{}`, expected bpm, div, velocity, accent, ghost or hold",
+                name
+            ),
+            RhythmErrorKind::InvalidSetting(reason) => f.write_str(reason),
+            RhythmErrorKind::DanglingHold => write!(f, "`-` has no note or rest to hold"),
+            RhythmErrorKind::NoStrikes => write!(f, "there is nothing to strike"),
+            RhythmErrorKind::TooManyStrikes => {
+                write!(f, "the pattern has more than {} strikes", MAX_STRIKES)
+            }
+            RhythmErrorKind::TooManySymbols => write!(
+                f,
+                "the pattern has more than {} notes, rests and holds with its repeats",
+                MAX_SYMBOLS
+            ),
+            RhythmErrorKind::TooManySteps => {
+                write!(f, "the pattern compiles to more than {} steps", MAX_STEPS)
+            }
+            RhythmErrorKind::TooFast {
+                time_ms,
+                gap_ms,
+                needed_ms,
+            } => write!(
+                f,
+                "the strike at {} ms comes {} ms after the previous one, but the servo needs {} ms",
+                time_ms, gap_ms, needed_ms
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RhythmError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Symbol {
+    Strike(Dynamic),
+    Rest,
+    Hold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dynamic {
+    Normal,
+    Accent,
+    Ghost,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    Symbol(Symbol, Location),
+    Set(String, String, Location),
+    Group(Vec<Item>, u32),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    location: Location,
+    // Groups open at the current position
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            chars: source.chars().peekable(),
+            location: Location { line: 1, column: 1 },
+            depth: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.location.line += 1;
+            self.location.column = 1;
+        } else {
+            self.location.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, kind: RhythmErrorKind, location: Location) -> RhythmError {
+        RhythmError {
+            kind,
+            location: Some(location),
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                word.push(c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        word
+    }
+
+    // Items until the end of the input or a `)` closing the group at `open`
+    fn items(&mut self, open: Option<Location>) -> Result<Vec<Item>, RhythmError> {
+        let mut items = Vec::new();
+        loop {
+            let location = self.location;
+            let Some(&c) = self.chars.peek() else {
+                return match open {
+                    Some(open) => Err(self.error(RhythmErrorKind::UnclosedGroup, open)),
+                    None => Ok(items),
+                };
+            };
+
+            match c {
+                c if c.is_whitespace() || c == ';' || c == '|' => {
+                    self.next();
+                }
+                '#' => while !matches!(self.next(), Some('\n') | None) {},
+                '(' => {
+                    if self.depth == MAX_DEPTH {
+                        return Err(self.error(RhythmErrorKind::TooDeep, location));
+                    }
+                    self.next();
+                    self.depth += 1;
+                    let group = self.items(Some(location))?;
+                    self.depth -= 1;
+                    let count = self.repeat()?;
+                    items.push(Item::Group(group, count));
+                }
+                ')' => {
+                    return match open {
+                        Some(_) => {
+                            self.next();
+                            Ok(items)
+                        }
+                        None => Err(self.error(RhythmErrorKind::UnopenedGroup, location)),
+                    };
+                }
+                '.' => {
+                    self.next();
+                    items.push(Item::Symbol(Symbol::Rest, location));
+                }
+                '-' => {
+                    self.next();
+                    items.push(Item::Symbol(Symbol::Hold, location));
+                }
+                c if c.is_ascii_alphabetic() => {
+                    let word = self.word();
+                    if self.chars.peek() == Some(&'=') {
+                        self.next();
+                        let value = self.word();
+                        items.push(Item::Set(word, value, location));
+                        continue;
+                    }
+                    // Consecutive symbols may be written without spaces
+                    let mut column = location.column;
+                    for c in word.chars() {
+                        let dynamic = match c {
+                            'x' => Dynamic::Normal,
+                            'X' => Dynamic::Accent,
+                            'o' => Dynamic::Ghost,
+                            '.' => {
+                                items.push(Item::Symbol(
+                                    Symbol::Rest,
+                                    Location { column, ..location },
+                                ));
+                                column += 1;
+                                continue;
+                            }
+                            c => {
+                                return Err(self.error(
+                                    RhythmErrorKind::UnexpectedChar(c),
+                                    Location { column, ..location },
+                                ))
+                            }
+                        };
+                        items.push(Item::Symbol(
+                            Symbol::Strike(dynamic),
+                            Location { column, ..location },
+                        ));
+                        column += 1;
+                    }
+                }
+                c => return Err(self.error(RhythmErrorKind::UnexpectedChar(c), location)),
+            }
+        }
+    }
+
+    // The optional `xN` after a group
+    fn repeat(&mut self) -> Result<u32, RhythmError> {
+        let location = self.location;
+        let mut lookahead = self.chars.clone();
+        if lookahead.next() != Some('x') || !lookahead.peek().is_some_and(char::is_ascii_digit) {
+            return Ok(1);
+        }
+        self.next();
+        let mut digits = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            digits.push(c);
+            self.next();
+        }
+        match digits.parse::<u32>() {
+            Ok(count) if (1..=MAX_REPEAT).contains(&count) => Ok(count),
+            _ => Err(self.error(RhythmErrorKind::InvalidRepeat, location)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    bpm: f64,
+    div: u32,
+    velocity: f64,
+    accent: f64,
+    ghost: f64,
+    hold: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bpm: 120.0,
+            div: 1,
+            velocity: 0.5,
+            accent: 1.0,
+            ghost: 0.2,
+            hold: Duration::ZERO,
+        }
+    }
+}
+
+impl Settings {
+    fn set(&mut self, name: &str, value: &str, location: Location) -> Result<(), RhythmError> {
+        let invalid = |reason: String| RhythmError {
+            kind: RhythmErrorKind::InvalidSetting(reason),
+            location: Some(location),
+        };
+        let fraction = |value: &str| match value.parse::<f64>() {
+            Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+            _ => Err(invalid(format!("{} must be from 0.0 to 1.0", name))),
+        };
+        match name {
+            "bpm" => match value.parse::<f64>() {
+                Ok(bpm) if (1.0..=600.0).contains(&bpm) => self.bpm = bpm,
+                _ => return Err(invalid("bpm must be from 1 to 600".into())),
+            },
+            "div" => match value.parse::<u32>() {
+                Ok(div) if (1..=16).contains(&div) => self.div = div,
+                _ => return Err(invalid("div must be from 1 to 16".into())),
+            },
+            "velocity" => self.velocity = fraction(value)?,
+            "accent" => self.accent = fraction(value)?,
+            "ghost" => self.ghost = fraction(value)?,
+            "hold" => match value.parse::<u32>() {
+                Ok(hold) if hold <= MAX_PAUSE_MS => self.hold = Duration::from_millis(hold as u64),
+                _ => {
+                    return Err(invalid(format!(
+                        "hold must be a number of milliseconds up to {}",
+                        MAX_PAUSE_MS
+                    )))
+                }
+            },
+            _ => {
+                return Err(RhythmError {
+                    kind: RhythmErrorKind::UnknownSetting(name.to_owned()),
+                    location: Some(location),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.bpm / self.div as f64)
+    }
+}
+
+// A strike in the compiled timeline
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Hit {
+    // Time of the impact from the start of the pattern
+    pub time_ms: u64,
+    pub velocity: f64,
+    pub damping_hold_ms: u64,
+    #[serde(flatten)]
+    pub location: Option<Location>,
+}
+
+// The strikes of a pattern in time order
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Timeline {
+    pub hits: Vec<Hit>,
+    // Length of the pattern including trailing rests
+    pub length_ms: u64,
+}
+
+impl Hit {
+    fn strike(&self) -> Strike {
+        Strike {
+            velocity: self.velocity as f32,
+            damping_hold: Duration::from_millis(self.damping_hold_ms),
+        }
+    }
+}
+
+impl Timeline {
+    // Builds a timeline from impact times, e.g. from a MIDI file
+    pub fn new(hits: Vec<Hit>, length_ms: u64) -> Self {
+        Self { hits, length_ms }
+    }
+
+    // Steps that hit the gong at the times of the timeline, which are shifted
+    // by the lead time of the first strike. When notes follow too closely
+    // for the calibrated backswing it is shortened down to
+    // `MIN_BACKSWING_MS`.
+    pub fn compile(&self, calibration: &ServoCalibration) -> Result<StrikeSequence, RhythmError> {
+        let Some(first) = self.hits.first() else {
+            return Err(RhythmError {
+                kind: RhythmErrorKind::NoStrikes,
+                location: None,
+            });
+        };
+
+        let mut steps = Vec::new();
+        if first.time_ms > 0 {
+            // A sequence starts with a move, the mallet waits at rest
+            steps.push(Step::Move {
+                angle: calibration.rest_angle,
+                profile: MotionProfile::Instant,
+            });
+            push_wait(&mut steps, Duration::from_millis(first.time_ms), first)?;
+        }
+        push_steps(&mut steps, first.strike().steps(calibration), first)?;
+        for pair in self.hits.windows(2) {
+            let (previous, hit) = (&pair[0], &pair[1]);
+            let strike = hit.strike();
+            let gap = Duration::from_millis(hit.time_ms - previous.time_ms);
+            let available = gap.saturating_sub(previous.strike().contact_time(calibration));
+            let Some(fitted) = fit(&strike, calibration, available) else {
+                let mut shortest = calibration.clone();
+                shortest.strike.backswing_ms = MIN_BACKSWING_MS;
+                shortest.strike.settle_ms = 0;
+                let needed =
+                    previous.strike().contact_time(calibration) + strike.lead_time(&shortest);
+                return Err(RhythmError {
+                    kind: RhythmErrorKind::TooFast {
+                        time_ms: hit.time_ms,
+                        gap_ms: gap.as_millis() as u64,
+                        needed_ms: needed.as_millis() as u64,
+                    },
+                    location: hit.location,
+                });
+            };
+            push_wait(&mut steps, available - strike.lead_time(&fitted), hit)?;
+            push_steps(&mut steps, strike.steps(&fitted), hit)?;
+        }
+        let last = self.hits.last().unwrap_or(first);
+        push_steps(&mut steps, [strike::rest(calibration)], last)?;
+        Ok(StrikeSequence::new(steps))
+    }
+}
+
+// The calibration to use for a strike that has to hit the gong within
+// `available`, with the settling dropped and the backswing shortened if the
+// calibrated ones take too long
+fn fit(
+    strike: &Strike,
+    calibration: &ServoCalibration,
+    available: Duration,
+) -> Option<ServoCalibration> {
+    if strike.lead_time(calibration) <= available {
+        return Some(calibration.clone());
+    }
+    let mut fitted = calibration.clone();
+    fitted.strike.backswing_ms = 0;
+    fitted.strike.settle_ms = 0;
+    let backswing = available.checked_sub(strike.lead_time(&fitted))?;
+    fitted.strike.backswing_ms = backswing.as_millis() as u32;
+    (fitted.strike.backswing_ms >= MIN_BACKSWING_MS).then_some(fitted)
+}
+
+// Waits longer than a sequence allows are split up. The count is checked
+// first, so that a wait of years does not fill the memory.
+fn push_wait(steps: &mut Vec<Step>, wait: Duration, hit: &Hit) -> Result<(), RhythmError> {
+    // Each split adds a wait and a move
+    let splits = wait.as_millis() / MAX_PAUSE_MS as u128;
+    if steps.len() as u128 + 2 * splits > MAX_STEPS as u128 {
+        return Err(too_many_steps(hit));
+    }
+    sequence::push_pause(steps, wait);
+    if steps.len() > MAX_STEPS {
+        return Err(too_many_steps(hit));
+    }
+    Ok(())
+}
+
+// Adds the steps of `hit` if the sequence stays within `MAX_STEPS`
+fn push_steps(
+    steps: &mut Vec<Step>,
+    new: impl IntoIterator<Item = Step>,
+    hit: &Hit,
+) -> Result<(), RhythmError> {
+    steps.extend(new);
+    if steps.len() > MAX_STEPS {
+        return Err(too_many_steps(hit));
+    }
+    Ok(())
+}
+
+fn too_many_steps(hit: &Hit) -> RhythmError {
+    RhythmError {
+        kind: RhythmErrorKind::TooManySteps,
+        location: hit.location,
+    }
+}
+
+struct Expander {
+    settings: Settings,
+    time: Duration,
+    hits: Vec<Hit>,
+    // Symbols expanded so far
+    symbols: usize,
+    // Whether anything (a note or a rest) can be held with `-`
+    holdable: bool,
+}
+
+impl Expander {
+    fn expand(&mut self, items: &[Item]) -> Result<(), RhythmError> {
+        for item in items {
+            match item {
+                Item::Set(name, value, location) => self.settings.set(name, value, *location)?,
+                // Repeating only settings changes nothing, but nested deep
+                // enough would take forever
+                Item::Group(items, _) if !has_symbols(items) => self.expand(items)?,
+                Item::Group(items, count) => {
+                    for _ in 0..*count {
+                        self.expand(items)?;
+                    }
+                }
+                Item::Symbol(symbol, location) => {
+                    if self.symbols == MAX_SYMBOLS {
+                        return Err(RhythmError {
+                            kind: RhythmErrorKind::TooManySymbols,
+                            location: Some(*location),
+                        });
+                    }
+                    self.symbols += 1;
+                    match symbol {
+                        Symbol::Strike(dynamic) => {
+                            if self.hits.len() == MAX_STRIKES {
+                                return Err(RhythmError {
+                                    kind: RhythmErrorKind::TooManyStrikes,
+                                    location: Some(*location),
+                                });
+                            }
+                            let velocity = match dynamic {
+                                Dynamic::Normal => self.settings.velocity,
+                                Dynamic::Accent => self.settings.accent,
+                                Dynamic::Ghost => self.settings.ghost,
+                            };
+                            self.hits.push(Hit {
+                                time_ms: self.time.as_millis() as u64,
+                                velocity,
+                                damping_hold_ms: self.settings.hold.as_millis() as u64,
+                                location: Some(*location),
+                            });
+                        }
+                        Symbol::Rest => {}
+                        Symbol::Hold if !self.holdable => {
+                            return Err(RhythmError {
+                                kind: RhythmErrorKind::DanglingHold,
+                                location: Some(*location),
+                            })
+                        }
+                        Symbol::Hold => {}
+                    }
+                    self.holdable = true;
+                    self.time += self.settings.step();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn has_symbols(items: &[Item]) -> bool {
+    items.iter().any(|item| match item {
+        Item::Symbol(..) => true,
+        Item::Set(..) => false,
+        Item::Group(items, _) => has_symbols(items),
+    })
+}
+
+pub fn parse(source: &str) -> Result<Timeline, RhythmError> {
+    let items = Parser::new(source).items(None)?;
+    let mut expander = Expander {
+        settings: Settings::default(),
+        time: Duration::ZERO,
+        hits: Vec::new(),
+        symbols: 0,
+        holdable: false,
+    };
+    expander.expand(&items)?;
+
+    Ok(Timeline {
+        hits: expander.hits,
+        length_ms: expander.time.as_millis() as u64,
+    })
+}
+
+pub fn compile(
+    source: &str,
+    calibration: &ServoCalibration,
+) -> Result<StrikeSequence, RhythmError> {
+    parse(source)?.compile(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (RhythmErrorKind, Option<(usize, usize)>) {
+        let e = parse(source).unwrap_err();
+        (e.kind, e.location.map(|l| (l.line, l.column)))
+    }
+
+    fn times(source: &str) -> Vec<u64> {
+        parse(source)
+            .unwrap()
+            .hits
+            .iter()
+            .map(|hit| hit.time_ms)
+            .collect()
+    }
+
+    // When the mallet reaches the gong, from the end of the first move
+    fn impacts(sequence: &StrikeSequence, calibration: &ServoCalibration) -> Vec<Duration> {
+        let mut impacts = Vec::new();
+        let mut time = Duration::ZERO;
+        let mut position = None;
+        for step in sequence.steps() {
+            match *step {
+                Step::Wait(pause) => time += pause,
+                Step::Move { angle, profile } => {
+                    if let Some(from) = position.replace(angle) {
+                        time += profile.duration(from, angle);
+                    }
+                    if angle == calibration.strike.impact_angle {
+                        impacts.push(time);
+                    }
+                }
+            }
+        }
+        impacts
+    }
+
+    #[test]
+    fn symbols_take_a_step_each() {
+        let timeline = parse("x . X o - |\n# comment\nx").unwrap();
+        let hits: Vec<_> = timeline
+            .hits
+            .iter()
+            .map(|hit| (hit.time_ms, hit.velocity))
+            .collect();
+        assert_eq!(hits, [(0, 0.5), (1000, 1.0), (1500, 0.2), (2500, 0.5)]);
+        assert_eq!(timeline.length_ms, 3000);
+        assert_eq!(
+            timeline.hits[3].location,
+            Some(Location { line: 3, column: 1 })
+        );
+    }
+
+    #[test]
+    fn symbols_can_be_written_together() {
+        assert_eq!(times("xx.x"), [0, 500, 1500]);
+    }
+
+    #[test]
+    fn settings_apply_to_what_follows() {
+        assert_eq!(times("bpm=60; x div=4 x x"), [0, 1000, 1250]);
+        let timeline = parse("velocity=0.8 hold=100 x").unwrap();
+        assert_eq!(timeline.hits[0].velocity, 0.8);
+        assert_eq!(timeline.hits[0].damping_hold_ms, 100);
+    }
+
+    #[test]
+    fn groups_repeat() {
+        assert_eq!(times("bpm=60 (x .)x3"), [0, 2000, 4000]);
+        assert_eq!(times("((x)x2 .)x2"), [0, 500, 1500, 2000]);
+        assert_eq!(times("(x) x"), [0, 500]);
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        assert_eq!(
+            error("x y"),
+            (RhythmErrorKind::UnexpectedChar('y'), Some((1, 3)))
+        );
+        assert_eq!(
+            error("x\n  xq"),
+            (RhythmErrorKind::UnexpectedChar('q'), Some((2, 4)))
+        );
+        assert_eq!(
+            error("x (x"),
+            (RhythmErrorKind::UnclosedGroup, Some((1, 3)))
+        );
+        assert_eq!(error("x )"), (RhythmErrorKind::UnopenedGroup, Some((1, 3))));
+        assert_eq!(
+            error("(x)x0"),
+            (RhythmErrorKind::InvalidRepeat, Some((1, 4)))
+        );
+        assert_eq!(
+            error("(x)x65"),
+            (RhythmErrorKind::InvalidRepeat, Some((1, 4)))
+        );
+        assert_eq!(
+            error("x tempo=1"),
+            (
+                RhythmErrorKind::UnknownSetting("tempo".into()),
+                Some((1, 3))
+            )
+        );
+        assert_eq!(
+            error("bpm=0 x"),
+            (
+                RhythmErrorKind::InvalidSetting("bpm must be from 1 to 600".into()),
+                Some((1, 1))
+            )
+        );
+        assert_eq!(error(" - x"), (RhythmErrorKind::DanglingHold, Some((1, 2))));
+        assert!(parse(". - x").is_ok());
+    }
+
+    #[test]
+    fn error_json_has_the_location() {
+        let json: serde_json::Value =
+            serde_json::from_str(&parse("x\n)").unwrap_err().to_json()).unwrap();
+        assert_eq!(json["error"], "unopened_group");
+        assert_eq!(json["line"], 2);
+        assert_eq!(json["column"], 1);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            error(&nested(MAX_DEPTH + 1)),
+            (RhythmErrorKind::TooDeep, Some((1, MAX_DEPTH + 1)))
+        );
+        // Would overflow the stack without the limit
+        assert_eq!(error(&nested(100_000)).0, RhythmErrorKind::TooDeep);
+    }
+
+    #[test]
+    fn expansion_is_limited() {
+        assert_eq!(
+            error("((((.)x64)x64)x64)x16").0,
+            RhythmErrorKind::TooManySymbols
+        );
+        assert_eq!(
+            error("(x)x64 (x)x64 (x)x64 (x)x64 x"),
+            (RhythmErrorKind::TooManyStrikes, Some((1, 29)))
+        );
+        // Groups of only settings are expanded once
+        let settings = format!("{}bpm=60{} x", "(".repeat(8), ")x64".repeat(8));
+        assert_eq!(times(&settings), [0]);
+    }
+
+    #[test]
+    fn a_single_strike_compiles_like_a_strike() {
+        let calibration = ServoCalibration::default();
+        let strike = Strike {
+            velocity: 0.5,
+            damping_hold: Duration::ZERO,
+        };
+        assert_eq!(
+            compile("x", &calibration).unwrap(),
+            strike.compile(&calibration)
+        );
+        assert_eq!(
+            compile("x", &calibration).unwrap().steps().last(),
+            Some(&Step::Move {
+                angle: calibration.rest_angle,
+                profile: MotionProfile::EaseInOut {
+                    duration_ms: calibration.strike.return_ms
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn compiled_sequences_are_servo_input() {
+        let calibration = ServoCalibration::default();
+        for source in [
+            "x",
+            ". x",
+            "bpm=1 . . x",
+            "bpm=60; x x . X",
+            "bpm=1 hold=60000 x . x",
+            "bpm=1 hold=60000 x . . x",
+            "bpm=90 (X x o .)x4",
+        ] {
+            let sequence = compile(source, &calibration).unwrap();
+            assert_eq!(
+                sequence::parse(&sequence.to_string()).as_ref(),
+                Ok(&sequence),
+                "{}",
+                source
+            );
+        }
+
+        // Leading rests keep the mallet at rest
+        let sequence = compile("bpm=1 . . x", &calibration).unwrap();
+        assert_eq!(
+            sequence.steps()[..4],
+            [
+                Step::Move {
+                    angle: calibration.rest_angle,
+                    profile: MotionProfile::Instant,
+                },
+                Step::Wait(Duration::from_millis(MAX_PAUSE_MS as u64)),
+                Step::Move {
+                    angle: calibration.rest_angle,
+                    profile: MotionProfile::Instant,
+                },
+                Step::Wait(Duration::from_millis(60_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn strikes_hit_on_the_beat() {
+        let calibration = ServoCalibration::default();
+        let sequence = compile("bpm=60; x x . X", &calibration).unwrap();
+        let impacts = impacts(&sequence, &calibration);
+        assert_eq!(impacts.len(), 3);
+        assert_eq!(impacts[1] - impacts[0], Duration::from_secs(1));
+        assert_eq!(impacts[2] - impacts[1], Duration::from_secs(2));
+    }
+
+    #[test]
+    fn close_strikes_get_a_shorter_backswing() {
+        let calibration = ServoCalibration::default();
+        let sequence = compile("bpm=150 x x", &calibration).unwrap();
+        let impacts = impacts(&sequence, &calibration);
+        assert_eq!(impacts[1] - impacts[0], Duration::from_millis(400));
+    }
+
+    #[test]
+    fn too_fast_strikes_are_refused() {
+        let e = compile("bpm=600 div=16 x x", &ServoCalibration::default()).unwrap_err();
+        assert!(matches!(
+            e.kind,
+            RhythmErrorKind::TooFast {
+                time_ms: 6,
+                gap_ms: 6,
+                ..
+            }
+        ));
+        assert_eq!(
+            e.location,
+            Some(Location {
+                line: 1,
+                column: 18
+            })
+        );
+    }
+
+    #[test]
+    fn nothing_to_strike() {
+        let e = compile(". . -", &ServoCalibration::default()).unwrap_err();
+        assert_eq!(e.kind, RhythmErrorKind::NoStrikes);
+    }
+
+    #[test]
+    fn compiled_steps_are_limited() {
+        let calibration = ServoCalibration::default();
+        // 320 minutes of rests, a wait of at most a minute each
+        let e = compile("bpm=1 (.)x64 (.)x64 (.)x64 (.)x64 (.)x64 x", &calibration).unwrap_err();
+        assert_eq!(e.kind, RhythmErrorKind::TooManySteps);
+        assert_eq!(
+            e.location,
+            Some(Location {
+                line: 1,
+                column: 42
+            })
+        );
+        let e = compile("bpm=30 (x)x64 (x)x64", &calibration).unwrap_err();
+        assert_eq!(e.kind, RhythmErrorKind::TooManySteps);
+        assert!(compile("bpm=30 (x)x32", &calibration).is_ok());
+
+        // Checked before the waits are made
+        let far = Timeline::new(
+            vec![Hit {
+                time_ms: 562_949_903_089,
+                velocity: 0.5,
+                damping_hold_ms: 0,
+                location: None,
+            }],
+            562_949_903_089,
+        );
+        assert_eq!(
+            far.compile(&calibration).unwrap_err().kind,
+            RhythmErrorKind::TooManySteps
+        );
+    }
+
+    #[test]
+    fn the_step_limit_leaves_room_for_a_song() {
+        let calibration = ServoCalibration::default();
+        let sequence = compile("bpm=90 (X x o .)x16", &calibration).unwrap();
+        assert!(sequence.steps().len() <= MAX_STEPS);
+    }
+}
@@ -0,0 +1,174 @@
+// Saved patterns played at set times, e.g. stand-up at `0 10 * * 1-5`. The
+// schedules are kept in NVS and run by a thread in the configured time zone
+// once the clock has been synchronised.
+
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Api, Method, Request};
+use crate::cron::{Cron, CronError};
+use crate::patterns::{self, PatternError};
+use crate::storage::{self, Store};
+use crate::tz::{self, TimeZone};
+
+pub const STORE_KEY: &str = "schedules";
+
+pub const MAX_SCHEDULES: usize = 16;
+
+// Times missed by less than this, e.g. while a pattern was being loaded,
+// still fire. After longer gaps, like the first sync of the clock, they are
+// skipped.
+const MAX_CATCH_UP: i64 = 5 * 60;
+
+const STACK_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    // Assigned when the schedule is added
+    #[serde(default)]
+    pub id: u32,
+    pub cron: String,
+    // Name of a saved pattern
+    pub pattern: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleError {
+    Cron(CronError),
+    Pattern(PatternError),
+    TooMany,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::Cron(e) => write!(f, "{}", e),
+            ScheduleError::Pattern(e) => write!(f, "{}", e),
+            ScheduleError::TooMany => {
+                write!(f, "at most {} schedules can be saved", MAX_SCHEDULES)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<Cron, ScheduleError> {
+        patterns::validate_name(&self.pattern).map_err(ScheduleError::Pattern)?;
+        Cron::parse(&self.cron).map_err(ScheduleError::Cron)
+    }
+}
+
+// Adds a schedule with the next free id
+pub fn add(schedules: &mut Vec<Schedule>, mut schedule: Schedule) -> Result<u32, ScheduleError> {
+    schedule.validate()?;
+    if schedules.len() >= MAX_SCHEDULES {
+        return Err(ScheduleError::TooMany);
+    }
+    schedule.id = schedules.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+    schedules.push(schedule);
+    Ok(schedules.last().unwrap().id)
+}
+
+// Seconds since the epoch of the system clock, set by SNTP on the board
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+pub fn synced(now: i64) -> bool {
+    now >= tz::VALID_AFTER
+}
+
+// Runs the saved schedules. Changes to them and to the time zone apply from
+// the next minute.
+pub fn spawn(api: Arc<Api>) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("schedule".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(&api))?;
+    Ok(())
+}
+
+fn run(api: &Api) {
+    let mut last: Option<i64> = None;
+    loop {
+        let now = now();
+        if !synced(now) {
+            thread::sleep(Duration::from_secs(10));
+            continue;
+        }
+        let zone = tz::load(api.store()).validate().unwrap_or(TimeZone::UTC);
+        // Looks for times in (since, now]
+        let since = match last {
+            Some(last) if last <= now && now - last <= MAX_CATCH_UP => last,
+            _ => now,
+        };
+        // Waking at every full minute also catches schedules added meanwhile
+        let mut wake = (now / 60 + 1) * 60;
+        for schedule in load(api.store()) {
+            let next = schedule
+                .validate()
+                .ok()
+                .and_then(|cron| cron.next_after(since, &zone));
+            match next {
+                Some(at) if at <= now => fire(api, &schedule),
+                Some(at) => wake = wake.min(at),
+                None => {}
+            }
+        }
+        // Quiet hours end on the minute as well
+        api.release_deferred();
+        last = Some(now);
+        thread::sleep(Duration::from_secs((wake - now).clamp(1, 60) as u64));
+    }
+}
+
+fn fire(api: &Api, schedule: &Schedule) {
+    let response = api.handle(&Request {
+        method: Method::Post,
+        uri: &format!("/patterns/{}", schedule.pattern),
+        headers: &[],
+        client: None,
+        body: &[],
+    });
+    if response.status == 202 {
+        info!(
+            "Schedule {} ({}) played {}",
+            schedule.id, schedule.cron, schedule.pattern
+        );
+    } else {
+        warn!(
+            "Schedule {} could not play {}: {}",
+            schedule.id, schedule.pattern, response.body
+        );
+    }
+}
+
+// The saved schedules that are usable
+pub fn load(store: &dyn Store) -> Vec<Schedule> {
+    let schedules: Vec<Schedule> = storage::load(store, STORE_KEY).unwrap_or_default();
+    schedules
+        .into_iter()
+        .filter(|schedule| match schedule.validate() {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Ignoring saved schedule {}: {}", schedule.id, e);
+                false
+            }
+        })
+        .take(MAX_SCHEDULES)
+        .collect()
+}
+
+pub fn save(store: &dyn Store, schedules: &[Schedule]) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, &schedules)
+}
@@ -0,0 +1,449 @@
+// Parser for the strike sequence format accepted by `/servo`.
+//
+// A sequence alternates servo angles (in degrees) and pauses (in milliseconds)
+// and always starts and ends with an angle: `({angle},{pause},)*{angle}`,
+// e.g. `90,200,30,500,90`. Whitespace around the numbers is ignored, so a
+// trailing newline from `curl -d` is fine, but empty fields are not.
+//
+// An angle can be followed by the motion profile of the move to it, e.g.
+// `90,200,30@linear:300,500,90@ease:400`, see `MotionProfile`.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::profile::{MotionProfile, MAX_MOVE_MS};
+
+pub const MAX_ANGLE: u32 = 180;
+pub const MAX_PAUSE_MS: u32 = 60_000;
+pub const MAX_STEPS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    // Move the mallet to the given angle in degrees
+    Move { angle: u32, profile: MotionProfile },
+    // Hold the current position
+    Wait(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StrikeSequence {
+    steps: Vec<Step>,
+}
+
+impl StrikeSequence {
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    // Total time of the pauses and moves, assuming the first move is instant
+    pub fn duration(&self) -> Duration {
+        let mut position = None;
+        self.steps
+            .iter()
+            .map(|step| match *step {
+                Step::Wait(pause) => pause,
+                Step::Move { angle, profile } => {
+                    let from = position.replace(angle);
+                    from.map_or(Duration::ZERO, |from| profile.duration(from, angle))
+                }
+            })
+            .sum()
+    }
+}
+
+// Adds a pause after `steps`, merged with a pause they already end with. A
+// pause longer than `MAX_PAUSE_MS` is split by moves to the angle the mallet
+// holds, so that built sequences stay valid `/servo` input. `steps` must
+// contain a move.
+pub fn push_pause(steps: &mut Vec<Step>, mut pause: Duration) {
+    if let Some(&Step::Wait(previous)) = steps.last() {
+        steps.pop();
+        pause += previous;
+    }
+    let angle = steps.iter().rev().find_map(|step| match *step {
+        Step::Move { angle, .. } => Some(angle),
+        Step::Wait(_) => None,
+    });
+    let max = Duration::from_millis(MAX_PAUSE_MS as u64);
+    if let Some(angle) = angle {
+        while pause > max {
+            steps.push(Step::Wait(max));
+            steps.push(Step::Move {
+                angle,
+                profile: MotionProfile::Instant,
+            });
+            pause -= max;
+        }
+    }
+    steps.push(Step::Wait(Duration::from_millis(pause.as_millis() as u64)));
+}
+
+impl std::str::FromStr for StrikeSequence {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+impl fmt::Display for StrikeSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, step) in self.steps.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            match step {
+                Step::Move {
+                    angle,
+                    profile: MotionProfile::Instant,
+                } => write!(f, "{}", angle)?,
+                Step::Move { angle, profile } => write!(f, "{}@{}", angle, profile)?,
+                Step::Wait(pause) => write!(f, "{}", pause.as_millis())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    InvalidUtf8,
+    Empty,
+    EmptyField,
+    InvalidNumber,
+    InvalidProfile(String),
+    MoveTooLong,
+    AngleOutOfRange(u32),
+    PauseTooLong(u32),
+    EndsWithPause,
+    TooManySteps,
+}
+
+// `position` is the byte offset into the request body of the offending field
+// and `field` its zero-based index in the comma separated list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub position: usize,
+    pub field: usize,
+}
+
+impl ParseError {
+    // Stable identifier used in the JSON error body
+    pub fn code(&self) -> &'static str {
+        match &self.kind {
+            ParseErrorKind::InvalidUtf8 => "invalid_utf8",
+            ParseErrorKind::Empty => "empty",
+            ParseErrorKind::EmptyField => "empty_field",
+            ParseErrorKind::InvalidNumber => "invalid_number",
+            ParseErrorKind::InvalidProfile(_) => "invalid_profile",
+            ParseErrorKind::MoveTooLong => "move_too_long",
+            ParseErrorKind::AngleOutOfRange(_) => "angle_out_of_range",
+            ParseErrorKind::PauseTooLong(_) => "pause_too_long",
+            ParseErrorKind::EndsWithPause => "ends_with_pause",
+            ParseErrorKind::TooManySteps => "too_many_steps",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "error": self.code(),
+            "message": self.to_string(),
+            "position": self.position,
+            "field": self.field,
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8 at byte {}", self.position),
+            ParseErrorKind::Empty => write!(f, "sequence is empty"),
+            ParseErrorKind::EmptyField => {
+                write!(f, "field {} at byte {} is empty", self.field, self.position)
+            }
+            ParseErrorKind::InvalidNumber => write!(
+                f,
+                "field {} at byte {} is not a non-negative integer",
+                self.field, self.position
+            ),
+            ParseErrorKind::InvalidProfile(reason) => {
+                write!(
+                    f,
+                    "field {} at byte {}: {}",
+                    self.field, self.position, reason
+                )
+            }
+            ParseErrorKind::MoveTooLong => write!(
+                f,
+                "move at byte {} takes longer than {} ms",
+                self.position, MAX_MOVE_MS
+            ),
+            ParseErrorKind::AngleOutOfRange(angle) => write!(
+                f,
+                "angle {} at byte {} is larger than {}",
+                angle, self.position, MAX_ANGLE
+            ),
+            ParseErrorKind::PauseTooLong(pause) => write!(
+                f,
+                "pause {} ms at byte {} is longer than {} ms",
+                pause, self.position, MAX_PAUSE_MS
+            ),
+            ParseErrorKind::EndsWithPause => write!(
+                f,
+                "sequence ends with a pause at byte {}, it must end with an angle",
+                self.position
+            ),
+            ParseErrorKind::TooManySteps => write!(
+                f,
+                "sequence has more than {} steps (at byte {})",
+                MAX_STEPS, self.position
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_bytes(input: &[u8]) -> Result<StrikeSequence, ParseError> {
+    let input = std::str::from_utf8(input).map_err(|e| ParseError {
+        kind: ParseErrorKind::InvalidUtf8,
+        position: e.valid_up_to(),
+        field: input[..e.valid_up_to()]
+            .iter()
+            .filter(|&&b| b == b',')
+            .count(),
+    })?;
+    parse(input)
+}
+
+pub fn parse(input: &str) -> Result<StrikeSequence, ParseError> {
+    if input.trim().is_empty() {
+        return Err(ParseError {
+            kind: ParseErrorKind::Empty,
+            position: 0,
+            field: 0,
+        });
+    }
+
+    let mut steps = Vec::new();
+    let mut position = 0;
+    let mut angle = None;
+    for (field, raw) in input.split(',').enumerate() {
+        let error = |kind| ParseError {
+            kind,
+            position: position + (raw.len() - raw.trim_start().len()),
+            field,
+        };
+
+        if field >= MAX_STEPS {
+            return Err(error(ParseErrorKind::TooManySteps));
+        }
+
+        let token = raw.trim();
+        if token.is_empty() {
+            return Err(error(ParseErrorKind::EmptyField));
+        }
+
+        let (number, profile) = match token.split_once('@') {
+            Some((number, profile)) if field % 2 == 0 => (number.trim_end(), Some(profile)),
+            _ => (token, None),
+        };
+        // `u32::from_str` accepts a leading `+`, which is not part of the format
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(error(ParseErrorKind::InvalidNumber));
+        }
+        let value = number
+            .parse::<u32>()
+            .map_err(|_| error(ParseErrorKind::InvalidNumber))?;
+
+        if field % 2 == 0 {
+            if value > MAX_ANGLE {
+                return Err(error(ParseErrorKind::AngleOutOfRange(value)));
+            }
+            let profile = match profile {
+                Some(profile) => profile
+                    .parse::<MotionProfile>()
+                    .map_err(|e| error(ParseErrorKind::InvalidProfile(e.0)))?,
+                None => MotionProfile::default(),
+            };
+            if let Some(from) = angle {
+                if profile.duration(from, value) > Duration::from_millis(MAX_MOVE_MS as u64) {
+                    return Err(error(ParseErrorKind::MoveTooLong));
+                }
+            }
+            angle = Some(value);
+            steps.push(Step::Move {
+                angle: value,
+                profile,
+            });
+        } else {
+            if value > MAX_PAUSE_MS {
+                return Err(error(ParseErrorKind::PauseTooLong(value)));
+            }
+            steps.push(Step::Wait(Duration::from_millis(value as u64)));
+        }
+
+        position += raw.len() + 1;
+    }
+
+    if let Some(Step::Wait(_)) = steps.last() {
+        let start = input.rfind(',').map_or(0, |idx| idx + 1);
+        let trimmed = &input[start..];
+        return Err(ParseError {
+            kind: ParseErrorKind::EndsWithPause,
+            position: start + (trimmed.len() - trimmed.trim_start().len()),
+            field: steps.len() - 1,
+        });
+    }
+
+    Ok(StrikeSequence { steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> (ParseErrorKind, usize, usize) {
+        let e = parse(input).unwrap_err();
+        (e.kind, e.position, e.field)
+    }
+
+    // xorshift, so the fuzz tests see the same inputs on every run
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    #[test]
+    fn parses_angles_pauses_and_profiles() {
+        let sequence = parse(" 90, 200,30@linear:300 ,500,90@ease:400\n").unwrap();
+        assert_eq!(
+            sequence.steps(),
+            [
+                Step::Move {
+                    angle: 90,
+                    profile: MotionProfile::Instant
+                },
+                Step::Wait(Duration::from_millis(200)),
+                Step::Move {
+                    angle: 30,
+                    profile: MotionProfile::Linear { deg_per_s: 300 }
+                },
+                Step::Wait(Duration::from_millis(500)),
+                Step::Move {
+                    angle: 90,
+                    profile: MotionProfile::EaseInOut { duration_ms: 400 }
+                },
+            ]
+        );
+        // 60 degrees at 300 per second and the ease
+        assert_eq!(
+            sequence.duration(),
+            Duration::from_millis(200 + 200 + 500 + 400)
+        );
+        assert_eq!(sequence.to_string(), "90,200,30@linear:300,500,90@ease:400");
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        use ParseErrorKind::*;
+        assert_eq!(error(" \n"), (Empty, 0, 0));
+        assert_eq!(error("90,,30"), (EmptyField, 3, 1));
+        assert_eq!(error("90, 200, x"), (InvalidNumber, 9, 2));
+        assert_eq!(error("+90"), (InvalidNumber, 0, 0));
+        assert_eq!(error("90,4294967296,30"), (InvalidNumber, 3, 1));
+        assert_eq!(error("90,200,181"), (AngleOutOfRange(181), 7, 2));
+        assert_eq!(error("90,60001,30"), (PauseTooLong(60001), 3, 1));
+        assert_eq!(error("90,200,30,500"), (EndsWithPause, 10, 3));
+        assert_eq!(error("90,200,30 ,\n500\n"), (EndsWithPause, 12, 3));
+        assert_eq!(error("0,0,180@linear:1"), (MoveTooLong, 4, 2));
+        assert!(matches!(error("90,200,30@warp"), (InvalidProfile(_), 7, 2)));
+        // A profile on a pause is not one
+        assert_eq!(error("90,200@ease:10,30"), (InvalidNumber, 3, 1));
+        let long = vec!["90"; MAX_STEPS + 1].join(",");
+        assert_eq!(error(&long), (TooManySteps, MAX_STEPS * 3, MAX_STEPS));
+
+        let e = parse_bytes(b"90,2\xff0,30").unwrap_err();
+        assert_eq!((e.kind, e.position, e.field), (InvalidUtf8, 4, 1));
+    }
+
+    #[test]
+    fn error_json_has_the_position() {
+        let json: serde_json::Value =
+            serde_json::from_str(&parse("90,,30").unwrap_err().to_json()).unwrap();
+        assert_eq!(json["error"], "empty_field");
+        assert_eq!(json["position"], 3);
+        assert_eq!(json["field"], 1);
+    }
+
+    #[test]
+    fn fuzz_errors_point_into_the_input() {
+        const ALPHABET: &[u8] = b"0123456789,, @:lineartsczu+-\n\xc3\xa9";
+        let mut random = Random(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let len = random.below(24) as usize;
+            let input: Vec<u8> = (0..len)
+                .map(|_| ALPHABET[random.below(ALPHABET.len() as u64) as usize])
+                .collect();
+            match parse_bytes(&input) {
+                Ok(sequence) => {
+                    assert_eq!(parse(&sequence.to_string()), Ok(sequence));
+                }
+                Err(e) => {
+                    assert!(e.position <= input.len(), "{:?} in {:?}", e, input);
+                    let commas = input.iter().filter(|&&b| b == b',').count();
+                    assert!(e.field <= commas, "{:?} in {:?}", e, input);
+                    if let Ok(input) = std::str::from_utf8(&input) {
+                        assert!(input.is_char_boundary(e.position));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_valid_sequences_round_trip() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2_000 {
+            let moves = 1 + random.below(20) as usize;
+            let mut steps = Vec::new();
+            for idx in 0..moves {
+                if idx > 0 {
+                    let pause = random.below(MAX_PAUSE_MS as u64 + 1);
+                    steps.push(Step::Wait(Duration::from_millis(pause)));
+                }
+                let profile = match random.below(4) {
+                    0 => MotionProfile::Instant,
+                    1 => MotionProfile::Linear {
+                        deg_per_s: 18 + random.below(1000) as u32,
+                    },
+                    2 => MotionProfile::EaseInOut {
+                        duration_ms: 1 + random.below(MAX_MOVE_MS as u64) as u32,
+                    },
+                    _ => MotionProfile::SCurve {
+                        duration_ms: 1 + random.below(MAX_MOVE_MS as u64) as u32,
+                    },
+                };
+                steps.push(Step::Move {
+                    angle: random.below(MAX_ANGLE as u64 + 1) as u32,
+                    profile,
+                });
+            }
+            let sequence = StrikeSequence::new(steps);
+            assert_eq!(parse(&sequence.to_string()), Ok(sequence));
+        }
+    }
+}
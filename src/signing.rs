@@ -0,0 +1,389 @@
+// Signed requests, for senders that cannot keep a token, e.g. a curl in a CI
+// job with the secret in its environment. A request carries
+//
+//   X-Gong-Timestamp: seconds since the epoch
+//   X-Gong-Nonce: 8 to 64 letters, digits, `-` or `_`, new for every request
+//   X-Gong-Signature: hex HMAC-SHA256 with the shared secret of
+//                     "{method}\n{path and query}\n{timestamp}\n{nonce}\n{body}"
+//
+// and may then do what a token with the `strike` scope may. Timestamps more
+// than `max_skew_s` away from the gong's clock are refused, and so are
+// nonces seen within that time.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{self, Digest};
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "signing";
+
+pub const TIMESTAMP: &str = "X-Gong-Timestamp";
+pub const NONCE: &str = "X-Gong-Nonce";
+pub const SIGNATURE: &str = "X-Gong-Signature";
+
+// Nonces remembered, more signed requests than this within `max_skew_s` on
+// either side are refused until the oldest have aged out
+pub const NONCE_CACHE: usize = 64;
+
+const MIN_NONCE_LEN: usize = 8;
+const MAX_NONCE_LEN: usize = 64;
+const MIN_SECRET_LEN: usize = 16;
+const MAX_SKEW_S: u32 = 3600;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningSettings {
+    // Empty until signed requests are configured
+    pub secret: String,
+    pub max_skew_s: u32,
+}
+
+impl Default for SigningSettings {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            max_skew_s: 300,
+        }
+    }
+}
+
+impl SigningSettings {
+    pub fn validate(&self) -> Result<(), SignatureError> {
+        if !self.secret.is_empty() && self.secret.len() < MIN_SECRET_LEN {
+            return Err(SignatureError::ShortSecret);
+        }
+        if self.max_skew_s == 0 || self.max_skew_s > MAX_SKEW_S {
+            return Err(SignatureError::InvalidSkew);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    // Settings
+    ShortSecret,
+    InvalidSkew,
+    // Answered 401
+    NotConfigured,
+    Missing(&'static str),
+    InvalidNonce,
+    ClockNotSynced,
+    Stale,
+    Invalid,
+    Replayed,
+    // Answered 429
+    CacheFull,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::ShortSecret => write!(
+                f,
+                "the secret must be at least {} characters long",
+                MIN_SECRET_LEN
+            ),
+            SignatureError::InvalidSkew => {
+                write!(f, "max_skew_s must be from 1 to {}", MAX_SKEW_S)
+            }
+            SignatureError::NotConfigured => write!(f, "signed requests are not configured"),
+            SignatureError::Missing(header) => write!(f, "missing or malformed {}", header),
+            SignatureError::InvalidNonce => write!(
+                f,
+                "{} must be {} to {} letters, digits, `-` or `_`",
+                NONCE, MIN_NONCE_LEN, MAX_NONCE_LEN
+            ),
+            SignatureError::ClockNotSynced => {
+                write!(f, "signed requests need the clock to be synchronised")
+            }
+            SignatureError::Stale => write!(f, "the timestamp is too far from the gong's clock"),
+            SignatureError::Invalid => write!(f, "invalid signature"),
+            SignatureError::Replayed => write!(f, "the nonce was already used"),
+            SignatureError::CacheFull => write!(f, "too many signed requests, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+impl SignatureError {
+    pub fn status(&self) -> u16 {
+        match self {
+            SignatureError::ShortSecret | SignatureError::InvalidSkew => 400,
+            SignatureError::CacheFull => 429,
+            _ => 401,
+        }
+    }
+}
+
+// The signature of a request, also for clients written in Rust
+pub fn sign(
+    secret: &[u8],
+    method: &str,
+    uri: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> Digest {
+    let timestamp = timestamp.to_string();
+    crypto::hmac_sha256(
+        secret,
+        &[
+            method.as_bytes(),
+            b"\n",
+            uri.as_bytes(),
+            b"\n",
+            timestamp.as_bytes(),
+            b"\n",
+            nonce.as_bytes(),
+            b"\n",
+            body,
+        ],
+    )
+}
+
+// The signing headers of a request
+pub struct Signed<'a> {
+    pub timestamp: Option<&'a str>,
+    pub nonce: Option<&'a str>,
+    pub signature: Option<&'a str>,
+}
+
+// Recently used nonces with their timestamps, kept as hashes so every entry
+// has the same size
+pub struct NonceCache {
+    entries: Vec<([u8; 16], i64)>,
+}
+
+impl Default for NonceCache {
+    fn default() -> Self {
+        Self {
+            entries: Vec::with_capacity(NONCE_CACHE),
+        }
+    }
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Checks a request sent to `method` and `uri` at `now`, `None` until the
+    // clock is synchronised, and remembers its nonce
+    pub fn verify(
+        &mut self,
+        settings: &SigningSettings,
+        method: &str,
+        uri: &str,
+        signed: &Signed,
+        body: &[u8],
+        now: Option<i64>,
+    ) -> Result<(), SignatureError> {
+        if settings.secret.is_empty() {
+            return Err(SignatureError::NotConfigured);
+        }
+        let timestamp: i64 = signed
+            .timestamp
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or(SignatureError::Missing(TIMESTAMP))?;
+        let nonce = signed.nonce.ok_or(SignatureError::Missing(NONCE))?;
+        if !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len())
+            || !nonce
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(SignatureError::InvalidNonce);
+        }
+        let signature = signed
+            .signature
+            .and_then(crypto::from_hex)
+            .ok_or(SignatureError::Missing(SIGNATURE))?;
+
+        // The signature first, so that unsigned requests cannot fill the
+        // cache
+        let expected = sign(
+            settings.secret.as_bytes(),
+            method,
+            uri,
+            timestamp,
+            nonce,
+            body,
+        );
+        if !crypto::constant_time_eq(&signature, &expected) {
+            return Err(SignatureError::Invalid);
+        }
+        let now = now.ok_or(SignatureError::ClockNotSynced)?;
+        // Any timestamp parses, so the difference must not overflow
+        let skew = settings.max_skew_s as u64;
+        if now.abs_diff(timestamp) > skew {
+            return Err(SignatureError::Stale);
+        }
+
+        let mut key = [0; 16];
+        key.copy_from_slice(&crypto::sha256(nonce.as_bytes())[..16]);
+        if self.entries.iter().any(|(seen, _)| *seen == key) {
+            return Err(SignatureError::Replayed);
+        }
+        // Entries whose timestamps would be stale by now cannot be replayed
+        // and make room
+        self.entries
+            .retain(|&(_, timestamp)| now.abs_diff(timestamp) <= skew);
+        if self.entries.len() >= NONCE_CACHE {
+            return Err(SignatureError::CacheFull);
+        }
+        self.entries.push((key, timestamp));
+        Ok(())
+    }
+}
+
+// The saved settings, or none if they are not usable
+pub fn load(store: &dyn Store) -> SigningSettings {
+    let settings: SigningSettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved signing settings: {}", e);
+            SigningSettings::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef-ci";
+    const NOW: i64 = 1_700_000_000;
+    const BODY: &[u8] = b"90,200,30";
+
+    fn settings() -> SigningSettings {
+        SigningSettings {
+            secret: SECRET.into(),
+            ..Default::default()
+        }
+    }
+
+    // Verifies a request signed at `timestamp` with `nonce`, received at
+    // `now`
+    fn verify(
+        cache: &mut NonceCache,
+        timestamp: i64,
+        nonce: &str,
+        now: Option<i64>,
+    ) -> Result<(), SignatureError> {
+        let signature = crypto::to_hex(&sign(
+            SECRET.as_bytes(),
+            "POST",
+            "/servo",
+            timestamp,
+            nonce,
+            BODY,
+        ));
+        let timestamp = timestamp.to_string();
+        let signed = Signed {
+            timestamp: Some(&timestamp),
+            nonce: Some(nonce),
+            signature: Some(&signature),
+        };
+        cache.verify(&settings(), "POST", "/servo", &signed, BODY, now)
+    }
+
+    #[test]
+    fn signs_the_readme_example() {
+        assert_eq!(
+            crypto::to_hex(&sign(
+                SECRET.as_bytes(),
+                "POST",
+                "/servo",
+                NOW,
+                "nonce-0001",
+                BODY
+            )),
+            "09bef30ae7f3437a8a9af3b4d09c00beb585a8ec9ef564dad415299f16d74d10"
+        );
+    }
+
+    #[test]
+    fn accepts_a_nonce_once() {
+        let mut cache = NonceCache::new();
+        assert_eq!(verify(&mut cache, NOW, "nonce-0001", Some(NOW)), Ok(()));
+        assert_eq!(
+            verify(&mut cache, NOW, "nonce-0001", Some(NOW + 1)),
+            Err(SignatureError::Replayed)
+        );
+        assert_eq!(verify(&mut cache, NOW, "nonce-0002", Some(NOW)), Ok(()));
+    }
+
+    #[test]
+    fn refuses_bad_requests() {
+        let mut cache = NonceCache::new();
+        assert_eq!(
+            verify(&mut cache, NOW, "short", Some(NOW)),
+            Err(SignatureError::InvalidNonce)
+        );
+        assert_eq!(
+            verify(&mut cache, NOW, "nonce-0001", None),
+            Err(SignatureError::ClockNotSynced)
+        );
+        let signed = Signed {
+            timestamp: Some("1700000000"),
+            nonce: Some("nonce-0001"),
+            signature: Some("09bef30ae7f3437a8a9af3b4d09c00beb585a8ec9ef564dad415299f16d74d11"),
+        };
+        assert_eq!(
+            cache.verify(&settings(), "POST", "/servo", &signed, BODY, Some(NOW)),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            cache.verify(
+                &SigningSettings::default(),
+                "POST",
+                "/servo",
+                &signed,
+                BODY,
+                Some(NOW)
+            ),
+            Err(SignatureError::NotConfigured)
+        );
+    }
+
+    #[test]
+    fn refuses_stale_timestamps() {
+        let mut cache = NonceCache::new();
+        assert_eq!(
+            verify(&mut cache, NOW - 300, "nonce-0001", Some(NOW)),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&mut cache, NOW + 301, "nonce-0002", Some(NOW)),
+            Err(SignatureError::Stale)
+        );
+        // Far enough apart to overflow a subtraction
+        for timestamp in [i64::MIN, i64::MAX] {
+            assert_eq!(
+                verify(&mut cache, timestamp, "nonce-0003", Some(NOW)),
+                Err(SignatureError::Stale)
+            );
+        }
+    }
+
+    #[test]
+    fn stale_nonces_make_room() {
+        let mut cache = NonceCache::new();
+        for n in 0..NONCE_CACHE {
+            let nonce = format!("nonce-{:04}", n);
+            assert_eq!(verify(&mut cache, NOW, &nonce, Some(NOW)), Ok(()));
+        }
+        assert_eq!(
+            verify(&mut cache, NOW, "nonce-full", Some(NOW)),
+            Err(SignatureError::CacheFull)
+        );
+        assert_eq!(
+            verify(&mut cache, NOW + 301, "nonce-full", Some(NOW + 301)),
+            Ok(())
+        );
+    }
+}
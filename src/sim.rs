@@ -0,0 +1,192 @@
+// Host implementations of the hardware traits that record what the firmware
+// would have done, with a timestamp from the given clock.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+
+use crate::hal::{Actuator, Color, HeapInfo, StatusIndicator, SystemInfo};
+use crate::player::Clock;
+use crate::storage::Store;
+
+// The board runs the LEDC timer with a 14 bit resolution
+pub const MAX_DUTY: u32 = 1 << 14;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event<T> {
+    pub at: Duration,
+    pub value: T,
+}
+
+pub type Recording<T> = Arc<Mutex<Vec<Event<T>>>>;
+
+// A clock that only advances when slept on, so simulated sequences finish
+// instantly and their timing is deterministic
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+pub struct SimActuator<C> {
+    clock: C,
+    duties: Recording<u32>,
+}
+
+impl<C> SimActuator<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            duties: Default::default(),
+        }
+    }
+
+    // Shared handle to the recorded duty values, still readable after the
+    // actuator has been moved into the player
+    pub fn duties(&self) -> Recording<u32> {
+        self.duties.clone()
+    }
+}
+
+impl<C> Actuator for SimActuator<C>
+where
+    C: Clock,
+{
+    fn max_duty(&self) -> u32 {
+        MAX_DUTY
+    }
+
+    fn set_duty(&mut self, duty: u32) -> anyhow::Result<()> {
+        let at = self.clock.now();
+        debug!("{:>8} ms: duty {}", at.as_millis(), duty);
+        self.duties.lock().unwrap().push(Event { at, value: duty });
+        Ok(())
+    }
+}
+
+pub struct SimIndicator<C> {
+    clock: C,
+    colors: Recording<Color>,
+}
+
+impl<C> SimIndicator<C>
+where
+    C: Clock,
+{
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            colors: Default::default(),
+        }
+    }
+
+    pub fn colors(&self) -> Recording<Color> {
+        self.colors.clone()
+    }
+}
+
+impl<C> StatusIndicator for SimIndicator<C>
+where
+    C: Clock,
+{
+    fn set_color(&mut self, color: Color) -> anyhow::Result<()> {
+        let at = self.clock.now();
+        info!("{:>8} ms: LED {:?}", at.as_millis(), color);
+        self.colors.lock().unwrap().push(Event { at, value: color });
+        Ok(())
+    }
+}
+
+// The host has no heap limit or access point to report
+pub struct SimSystem<C> {
+    clock: C,
+}
+
+impl<C> SimSystem<C> {
+    pub fn new(clock: C) -> Self {
+        Self { clock }
+    }
+}
+
+impl<C> SystemInfo for SimSystem<C>
+where
+    C: Clock + Send + Sync,
+{
+    fn uptime(&self) -> Duration {
+        self.clock.now()
+    }
+
+    fn heap(&self) -> Option<HeapInfo> {
+        None
+    }
+
+    fn reset_reason(&self) -> &'static str {
+        "power_on"
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+
+    fn fill_random(&self, buf: &mut [u8]) {
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(buf))
+            .expect("reading /dev/urandom");
+    }
+}
+
+// Settings kept in memory for as long as the simulation runs
+#[derive(Default)]
+pub struct MemoryStore {
+    values: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+// Persistent key-value settings, backed by NVS on the board.
+//
+// Values are stored as JSON so records can gain fields without breaking what
+// is already saved. NVS limits keys to 15 characters.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub trait Store: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+    fn remove(&self, key: &str) -> anyhow::Result<()>;
+}
+
+// Loads a record, falling back to `None` (and logging) if it is missing or
+// can no longer be decoded
+pub fn load<T: DeserializeOwned>(store: &dyn Store, key: &str) -> Option<T> {
+    match store.get(key) {
+        Ok(Some(bytes)) => match serde_json::from_slice(&bytes) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("Ignoring unreadable setting {}: {}", key, e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Could not read setting {}: {:?}", key, e);
+            None
+        }
+    }
+}
+
+pub fn save<T: Serialize>(store: &dyn Store, key: &str, value: &T) -> anyhow::Result<()> {
+    store.set(key, &serde_json::to_vec(value)?)
+}
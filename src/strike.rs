@@ -0,0 +1,279 @@
+// Strikes described by how hard they hit instead of by angles and pauses,
+// compiled into servo steps with the strike calibration.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::calibration::ServoCalibration;
+use crate::profile::MotionProfile;
+use crate::sequence::{self, Step, StrikeSequence, MAX_PAUSE_MS};
+
+pub const DEFAULT_VELOCITY: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Strike {
+    // From 0.0 (softest) to 1.0 (hardest)
+    pub velocity: f32,
+    // How long the mallet stays on the gong to damp it, zero lets it ring
+    pub damping_hold: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StrikeError {
+    Velocity,
+    DampingHold,
+}
+
+impl fmt::Display for StrikeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StrikeError::Velocity => write!(f, "velocity must be a number from 0.0 to 1.0"),
+            StrikeError::DampingHold => write!(
+                f,
+                "damping_hold must be a number of milliseconds up to {}",
+                MAX_PAUSE_MS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StrikeError {}
+
+impl Strike {
+    pub fn new(velocity: f32, damping_hold: Duration) -> Result<Self, StrikeError> {
+        if !(0.0..=1.0).contains(&velocity) {
+            return Err(StrikeError::Velocity);
+        }
+        if damping_hold > Duration::from_millis(MAX_PAUSE_MS as u64) {
+            return Err(StrikeError::DampingHold);
+        }
+        Ok(Self {
+            velocity,
+            damping_hold,
+        })
+    }
+
+    // Steps of the strike without returning to rest, so strikes can follow
+    // each other directly
+    pub fn steps(&self, calibration: &ServoCalibration) -> Vec<Step> {
+        let strike = &calibration.strike;
+        let (backswing, speed) = self.swing(calibration);
+
+        let mut steps = vec![
+            Step::Move {
+                angle: backswing,
+                profile: eased(strike.backswing_ms),
+            },
+            Step::Wait(Duration::from_millis(strike.settle_ms as u64)),
+            Step::Move {
+                angle: strike.impact_angle,
+                profile: MotionProfile::Linear { deg_per_s: speed },
+            },
+        ];
+        // With a long damping hold the contact can exceed `MAX_PAUSE_MS`
+        sequence::push_pause(&mut steps, self.contact_time(calibration));
+        steps
+    }
+
+    // Time from the start of the strike until the mallet hits the gong
+    pub fn lead_time(&self, calibration: &ServoCalibration) -> Duration {
+        let strike = &calibration.strike;
+        let (backswing, speed) = self.swing(calibration);
+        let swing = MotionProfile::Linear { deg_per_s: speed };
+        Duration::from_millis((strike.backswing_ms + strike.settle_ms) as u64)
+            + swing.duration(backswing, strike.impact_angle)
+    }
+
+    // Time the mallet stays on the gong after hitting it
+    pub fn contact_time(&self, calibration: &ServoCalibration) -> Duration {
+        Duration::from_millis(calibration.strike.contact_ms as u64) + self.damping_hold
+    }
+
+    // Backswing angle and swing speed for the velocity
+    fn swing(&self, calibration: &ServoCalibration) -> (u32, u32) {
+        let strike = &calibration.strike;
+        let lerp = |soft: u32, hard: u32| {
+            (soft as f32 + (hard as f32 - soft as f32) * self.velocity).round() as u32
+        };
+        (
+            lerp(strike.soft_backswing_angle, strike.hard_backswing_angle),
+            lerp(strike.soft_speed_deg_per_s, strike.hard_speed_deg_per_s).max(1),
+        )
+    }
+
+    // The full strike, ending with the mallet back at rest
+    pub fn compile(&self, calibration: &ServoCalibration) -> StrikeSequence {
+        let mut steps = self.steps(calibration);
+        steps.push(rest(calibration));
+        StrikeSequence::new(steps)
+    }
+}
+
+// Move back to the rest angle after strikes
+pub fn rest(calibration: &ServoCalibration) -> Step {
+    Step::Move {
+        angle: calibration.rest_angle,
+        profile: eased(calibration.strike.return_ms),
+    }
+}
+
+fn eased(duration_ms: u32) -> MotionProfile {
+    match duration_ms {
+        0 => MotionProfile::Instant,
+        duration_ms => MotionProfile::EaseInOut { duration_ms },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strike(velocity: f32) -> Strike {
+        Strike::new(velocity, Duration::ZERO).unwrap()
+    }
+
+    fn steps(backswing: u32, deg_per_s: u32, contact_ms: u64) -> Vec<Step> {
+        vec![
+            Step::Move {
+                angle: backswing,
+                profile: MotionProfile::EaseInOut { duration_ms: 300 },
+            },
+            Step::Wait(Duration::from_millis(50)),
+            Step::Move {
+                angle: 30,
+                profile: MotionProfile::Linear { deg_per_s },
+            },
+            Step::Wait(Duration::from_millis(contact_ms)),
+        ]
+    }
+
+    #[test]
+    fn velocity_sets_the_swing() {
+        let calibration = ServoCalibration::default();
+        assert_eq!(strike(0.0).steps(&calibration), steps(110, 150, 40));
+        assert_eq!(strike(0.5).steps(&calibration), steps(135, 575, 40));
+        assert_eq!(strike(1.0).steps(&calibration), steps(160, 1000, 40));
+        // Rounded to whole degrees
+        assert_eq!(strike(0.01).swing(&calibration), (111, 159));
+        assert_eq!(strike(0.99).swing(&calibration), (160, 992));
+    }
+
+    #[test]
+    fn swing_can_go_either_way() {
+        let mut calibration = ServoCalibration::default();
+        calibration.strike.impact_angle = 150;
+        calibration.strike.soft_backswing_angle = 80;
+        calibration.strike.hard_backswing_angle = 20;
+        calibration.strike.soft_speed_deg_per_s = 400;
+        calibration.strike.hard_speed_deg_per_s = 100;
+        assert_eq!(strike(0.0).swing(&calibration), (80, 400));
+        assert_eq!(strike(0.5).swing(&calibration), (50, 250));
+        assert_eq!(strike(1.0).swing(&calibration), (20, 100));
+    }
+
+    #[test]
+    fn lead_time_reaches_the_gong() {
+        let calibration = ServoCalibration::default();
+        // 300 ms backswing, 50 ms settling and the swing
+        assert_eq!(
+            strike(0.0).lead_time(&calibration),
+            Duration::from_millis(350 + 533)
+        );
+        assert_eq!(
+            strike(0.5).lead_time(&calibration),
+            Duration::from_millis(350 + 182)
+        );
+        assert_eq!(
+            strike(1.0).lead_time(&calibration),
+            Duration::from_millis(350 + 130)
+        );
+    }
+
+    #[test]
+    fn compiled_strike_returns_to_rest() {
+        let mut calibration = ServoCalibration::default();
+        let mut expected = steps(135, 575, 40);
+        expected.push(Step::Move {
+            angle: 90,
+            profile: MotionProfile::EaseInOut { duration_ms: 300 },
+        });
+        assert_eq!(
+            strike(0.5).compile(&calibration),
+            StrikeSequence::new(expected)
+        );
+
+        // Without durations the moves are instant
+        calibration.strike.backswing_ms = 0;
+        calibration.strike.return_ms = 0;
+        let compiled = strike(0.5).compile(&calibration);
+        assert_eq!(
+            compiled.steps()[0],
+            Step::Move {
+                angle: 135,
+                profile: MotionProfile::Instant,
+            }
+        );
+        assert_eq!(
+            compiled.steps().last(),
+            Some(&Step::Move {
+                angle: 90,
+                profile: MotionProfile::Instant,
+            })
+        );
+    }
+
+    #[test]
+    fn damping_hold_is_limited() {
+        let max = Duration::from_millis(MAX_PAUSE_MS as u64);
+        assert_eq!(
+            Strike::new(-0.1, Duration::ZERO),
+            Err(StrikeError::Velocity)
+        );
+        assert_eq!(Strike::new(1.1, Duration::ZERO), Err(StrikeError::Velocity));
+        assert_eq!(
+            Strike::new(f32::NAN, Duration::ZERO),
+            Err(StrikeError::Velocity)
+        );
+        assert_eq!(
+            Strike::new(0.5, max + Duration::from_millis(1)),
+            Err(StrikeError::DampingHold)
+        );
+
+        let calibration = ServoCalibration::default();
+        let held = Strike::new(0.5, Duration::from_millis(200)).unwrap();
+        assert_eq!(held.steps(&calibration), steps(135, 575, 240));
+        assert_eq!(held.contact_time(&calibration), Duration::from_millis(240));
+    }
+
+    #[test]
+    fn long_contact_is_split_into_valid_pauses() {
+        let calibration = ServoCalibration::default();
+        let held = Strike::new(0.5, Duration::from_millis(MAX_PAUSE_MS as u64)).unwrap();
+        assert_eq!(
+            held.contact_time(&calibration),
+            Duration::from_millis(MAX_PAUSE_MS as u64 + 40)
+        );
+        let steps = held.steps(&calibration);
+        assert_eq!(
+            steps[3..],
+            [
+                Step::Wait(Duration::from_millis(MAX_PAUSE_MS as u64)),
+                Step::Move {
+                    angle: 30,
+                    profile: MotionProfile::Instant,
+                },
+                Step::Wait(Duration::from_millis(40)),
+            ]
+        );
+
+        let compiled = held.compile(&calibration);
+        assert_eq!(
+            crate::sequence::parse(&compiled.to_string()),
+            Ok(compiled.clone())
+        );
+        assert_eq!(
+            compiled.duration(),
+            Duration::from_millis(182 + 50 + MAX_PAUSE_MS as u64 + 40 + 300)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// HTTPS for the API, so tokens and passwords do not cross the network in
+// clear text. The certificate and its key are PEM and kept in NVS, either
+// uploaded or generated self-signed on the first boot with HTTPS enabled.
+// While HTTPS is on, plain HTTP requests are redirected to it.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "tls";
+
+pub const HTTPS_PORT: u16 = 443;
+
+// An RSA 4096 certificate with a short chain fits
+pub const MAX_PEM_LEN: usize = 8 * 1024;
+
+const CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+// `PRIVATE KEY`, `EC PRIVATE KEY` or `RSA PRIVATE KEY`
+const KEY_END: &str = "PRIVATE KEY-----";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    // Answer plain HTTP with a redirect to HTTPS, otherwise nothing listens
+    // on port 80
+    pub redirect: bool,
+    // Empty ones are generated on the next boot
+    pub certificate: String,
+    pub private_key: String,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redirect: true,
+            certificate: String::new(),
+            private_key: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TlsError {
+    TooLong,
+    // Only one of the two was given
+    Incomplete,
+    NotACertificate,
+    NotAKey,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::TooLong => write!(
+                f,
+                "the certificate and key must be at most {} bytes each",
+                MAX_PEM_LEN
+            ),
+            TlsError::Incomplete => write!(f, "the certificate and private key go together"),
+            TlsError::NotACertificate => write!(f, "the certificate must be PEM"),
+            TlsError::NotAKey => write!(f, "the private key must be PEM"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl TlsSettings {
+    // Checks the form only, whether the key belongs to the certificate is
+    // found out when HTTPS starts with them
+    pub fn validate(&self) -> Result<(), TlsError> {
+        if self.certificate.len() > MAX_PEM_LEN || self.private_key.len() > MAX_PEM_LEN {
+            return Err(TlsError::TooLong);
+        }
+        if self.certificate.is_empty() != self.private_key.is_empty() {
+            return Err(TlsError::Incomplete);
+        }
+        if self.certificate.is_empty() {
+            return Ok(());
+        }
+        if !self.certificate.trim_start().starts_with(CERTIFICATE_BEGIN) {
+            return Err(TlsError::NotACertificate);
+        }
+        if !self.private_key.trim_start().starts_with("-----BEGIN ")
+            || !self.private_key.trim_end().ends_with(KEY_END)
+        {
+            return Err(TlsError::NotAKey);
+        }
+        Ok(())
+    }
+
+    // Whether a certificate has to be made before HTTPS can start
+    pub fn needs_certificate(&self) -> bool {
+        self.enabled && self.certificate.is_empty()
+    }
+}
+
+// Where `Location` of a redirect to HTTPS points, from the request's
+// `target` and `Host` header or else the address it was sent to
+pub fn https_url(host: Option<&str>, local_address: &str, target: &str) -> String {
+    let host = host
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .unwrap_or(local_address);
+    // The port of plain HTTP does not carry over, `[::1]:80` keeps its
+    // brackets
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    };
+    let target = if target.starts_with('/') { target } else { "/" };
+    // `HTTPS_PORT` is the default one, so the URL needs none
+    format!("https://{}{}", host, target)
+}
+
+// The saved settings, or HTTP only if they are not usable
+pub fn load(store: &dyn Store) -> TlsSettings {
+    let settings: TlsSettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved TLS settings: {}", e);
+            TlsSettings::default()
+        }
+    }
+}
+
+pub fn save(store: &dyn Store, settings: &TlsSettings) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, settings)
+}
@@ -0,0 +1,467 @@
+// Local time without the C library: POSIX TZ strings like
+// `CET-1CEST,M3.5.0,M10.5.0/3`, the form esp-idf uses as well, and the
+// calendar arithmetic the scheduler needs. Times are seconds since the Unix
+// epoch, local times are the same count with the offset added.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "time";
+
+pub const SECS_PER_DAY: i64 = 86_400;
+
+// Clocks before this have not been synchronised yet
+pub const VALID_AFTER: i64 = 1_672_531_200; // 2023-01-01
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeSettings {
+    // POSIX TZ string, e.g. `CET-1CEST,M3.5.0,M10.5.0/3` for central Europe
+    pub timezone: String,
+    pub ntp_server: String,
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC0".into(),
+            ntp_server: "pool.ntp.org".into(),
+        }
+    }
+}
+
+impl TimeSettings {
+    pub fn validate(&self) -> Result<TimeZone, TzError> {
+        if self.ntp_server.is_empty() || self.ntp_server.contains(char::is_whitespace) {
+            return Err(TzError::NtpServer);
+        }
+        TimeZone::parse(&self.timezone)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TzError {
+    Invalid(String),
+    NtpServer,
+}
+
+impl fmt::Display for TzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TzError::Invalid(tz) => write!(
+                f,
+                "`{}` is not a POSIX TZ string like CET-1CEST,M3.5.0,M10.5.0/3",
+                tz
+            ),
+            TzError::NtpServer => write!(f, "the NTP server must be a host name"),
+        }
+    }
+}
+
+impl std::error::Error for TzError {}
+
+// `Mm.w.d/time`: day `d` (0 is Sunday) of week `w` (5 is the last) of month
+// `m`, at `time` seconds after local midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rule {
+    month: u32,
+    week: u32,
+    weekday: u32,
+    time: i64,
+}
+
+impl Rule {
+    // Local midnight of the day the rule picks in `year`, plus its time
+    fn local(&self, year: i64) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let offset = (self.weekday as i64 - weekday(first) as i64).rem_euclid(7);
+        let mut day = first + offset + 7 * (self.week as i64 - 1);
+        if self.week == 5 {
+            let next_month = if self.month == 12 {
+                days_from_civil(year + 1, 1, 1)
+            } else {
+                days_from_civil(year, self.month + 1, 1)
+            };
+            while day >= next_month {
+                day -= 7;
+            }
+        }
+        day * SECS_PER_DAY + self.time
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Dst {
+    // Seconds east of UTC
+    offset: i64,
+    start: Rule,
+    end: Rule,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeZone {
+    // Seconds east of UTC
+    offset: i64,
+    dst: Option<Dst>,
+}
+
+// What a local time corresponds to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalTime {
+    Single(i64),
+    // Passed twice when the clocks go back, earlier first
+    Ambiguous(i64, i64),
+    // Skipped when the clocks go forward, with the moment they did
+    Gap(i64),
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone {
+        offset: 0,
+        dst: None,
+    };
+
+    pub fn parse(tz: &str) -> Result<Self, TzError> {
+        let invalid = || TzError::Invalid(tz.to_owned());
+        let mut parser = Parser { rest: tz };
+        parser.name().ok_or_else(invalid)?;
+        let offset = -parser.offset().ok_or_else(invalid)?;
+        if parser.rest.is_empty() {
+            return Ok(Self { offset, dst: None });
+        }
+        parser.name().ok_or_else(invalid)?;
+        // Summer time is an hour ahead unless given
+        let dst_offset = if parser.rest.starts_with(',') {
+            offset + 3600
+        } else {
+            -parser.offset().ok_or_else(invalid)?
+        };
+        let start = parser.rule().ok_or_else(invalid)?;
+        let end = parser.rule().ok_or_else(invalid)?;
+        if !parser.rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            offset,
+            dst: Some(Dst {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    // Offset east of UTC in effect at `utc`
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return self.offset,
+        };
+        let (year, _, _) = civil_from_days((utc + self.offset).div_euclid(SECS_PER_DAY));
+        // The start is given in standard time and the end in summer time
+        let start = dst.start.local(year) - self.offset;
+        let end = dst.end.local(year) - dst.offset;
+        let in_dst = if start < end {
+            start <= utc && utc < end
+        } else {
+            // Southern hemisphere, summer time spans the new year
+            utc < end || start <= utc
+        };
+        if in_dst {
+            dst.offset
+        } else {
+            self.offset
+        }
+    }
+
+    pub fn to_local(&self, utc: i64) -> i64 {
+        utc + self.offset_at(utc)
+    }
+
+    pub fn to_utc(&self, local: i64) -> LocalTime {
+        let offsets = match &self.dst {
+            Some(dst) => [self.offset.max(dst.offset), self.offset.min(dst.offset)],
+            None => return LocalTime::Single(local - self.offset),
+        };
+        // The larger offset gives the earlier instant
+        let valid: Vec<i64> = offsets
+            .iter()
+            .map(|offset| local - offset)
+            .filter(|&utc| self.to_local(utc) == local)
+            .collect();
+        match valid[..] {
+            [utc] => LocalTime::Single(utc),
+            [first, second] if first != second => LocalTime::Ambiguous(first, second),
+            [utc, _] => LocalTime::Single(utc),
+            _ => {
+                // The change lies between the two readings, found by halving
+                let (mut before, mut after) = (local - offsets[0], local - offsets[1]);
+                let offset = self.offset_at(before);
+                while after - before > 1 {
+                    let middle = before + (after - before) / 2;
+                    if self.offset_at(middle) == offset {
+                        before = middle;
+                    } else {
+                        after = middle;
+                    }
+                }
+                LocalTime::Gap(after)
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    // `CET`, or `<+03>` for names that are not letters
+    fn name(&mut self) -> Option<()> {
+        let len = if let Some(rest) = self.rest.strip_prefix('<') {
+            rest.find('>')? + 2
+        } else {
+            self.rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len())
+        };
+        if len < 3 {
+            return None;
+        }
+        self.rest = &self.rest[len..];
+        Some(())
+    }
+
+    fn number(&mut self) -> Option<i64> {
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let number = self.rest[..len].parse().ok()?;
+        self.rest = &self.rest[len..];
+        Some(number)
+    }
+
+    // `[+-]h[:mm[:ss]]` in seconds
+    fn time(&mut self) -> Option<i64> {
+        let sign = match self.rest.as_bytes().first() {
+            Some(b'-') => -1,
+            Some(b'+') => 1,
+            _ => 0,
+        };
+        if sign != 0 {
+            self.rest = &self.rest[1..];
+        }
+        let mut seconds = self.number()? * 3600;
+        for scale in [60, 1] {
+            match self.rest.strip_prefix(':') {
+                Some(rest) => {
+                    self.rest = rest;
+                    let value = self.number()?;
+                    if value >= 60 {
+                        return None;
+                    }
+                    seconds += value * scale;
+                }
+                None => break,
+            }
+        }
+        Some(if sign < 0 { -seconds } else { seconds })
+    }
+
+    // Hours west of UTC as POSIX writes them, at most a day
+    fn offset(&mut self) -> Option<i64> {
+        let offset = self.time()?;
+        (offset.abs() <= 24 * 3600).then_some(offset)
+    }
+
+    // `,Mm.w.d[/time]`
+    fn rule(&mut self) -> Option<Rule> {
+        self.rest = self.rest.strip_prefix(",M")?;
+        let month = self.number()?;
+        self.rest = self.rest.strip_prefix('.')?;
+        let week = self.number()?;
+        self.rest = self.rest.strip_prefix('.')?;
+        let weekday = self.number()?;
+        let time = match self.rest.strip_prefix('/') {
+            Some(rest) => {
+                self.rest = rest;
+                self.time()?
+            }
+            None => 2 * 3600,
+        };
+        let valid = (1..=12).contains(&month)
+            && (1..=5).contains(&week)
+            && weekday <= 6
+            && time.abs() <= 167 * 3600;
+        valid.then_some(Rule {
+            month: month as u32,
+            week: week as u32,
+            weekday: weekday as u32,
+            time,
+        })
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, see
+// http://howardhinnant.github.io/date_algorithms.html
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Year, month and day of a day since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// 0 is Sunday
+pub fn weekday(days: i64) -> u32 {
+    (days + 4).rem_euclid(7) as u32
+}
+
+// `2024-03-31T02:30:00` for logs and the API
+pub fn format(local: i64) -> String {
+    let days = local.div_euclid(SECS_PER_DAY);
+    let seconds = local.rem_euclid(SECS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// The saved settings, or the defaults (UTC) if there are none that are
+// usable
+pub fn load(store: &dyn Store) -> TimeSettings {
+    let settings: TimeSettings = storage::load(store, STORE_KEY).unwrap_or_default();
+    match settings.validate() {
+        Ok(_) => settings,
+        Err(e) => {
+            log::warn!("Ignoring saved time settings: {}", e);
+            TimeSettings::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EUROPE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    // 2024-03-31 01:00 and 2024-10-27 01:00 UTC
+    const SPRING: i64 = 1_711_846_800;
+    const AUTUMN: i64 = 1_729_990_800;
+
+    #[test]
+    fn parses_posix_strings() {
+        assert_eq!(TimeZone::parse("UTC0"), Ok(TimeZone::UTC));
+        assert_eq!(
+            TimeZone::parse("<+0330>-3:30").unwrap().offset_at(0),
+            12_600
+        );
+        assert_eq!(
+            TimeZone::parse("EST5EDT,M3.2.0,M11.1.0")
+                .unwrap()
+                .offset_at(0),
+            -18_000
+        );
+        for tz in [
+            "",
+            "UTC",
+            "CE-1",
+            "CET-25",
+            "CET-1CEST",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+            "CET-1CEST,M3.6.0,M10.5.0",
+            "CET-1CEST,M3.5.7,M10.5.0",
+            "CET-1CEST,M3.5.0,M10.5.0/3x",
+        ] {
+            assert!(TimeZone::parse(tz).is_err(), "{}", tz);
+        }
+    }
+
+    #[test]
+    fn offsets_change_at_the_transitions() {
+        let zone = TimeZone::parse(EUROPE).unwrap();
+        assert_eq!(zone.offset_at(SPRING - 1), 3600);
+        assert_eq!(zone.offset_at(SPRING), 7200);
+        assert_eq!(zone.offset_at(AUTUMN - 1), 7200);
+        assert_eq!(zone.offset_at(AUTUMN), 3600);
+        assert_eq!(format(zone.to_local(SPRING)), "2024-03-31T03:00:00");
+        assert_eq!(format(zone.to_local(AUTUMN)), "2024-10-27T02:00:00");
+    }
+
+    #[test]
+    fn southern_summer_spans_the_new_year() {
+        let zone = TimeZone::parse(SYDNEY).unwrap();
+        // 2024-04-07 03:00 AEDT
+        let end = 1_712_419_200;
+        assert_eq!(zone.offset_at(end - 1), 39_600);
+        assert_eq!(zone.offset_at(end), 36_000);
+        // 2024-01-01 and 2024-07-01 00:00 UTC
+        assert_eq!(zone.offset_at(1_704_067_200), 39_600);
+        assert_eq!(zone.offset_at(1_719_792_000), 36_000);
+    }
+
+    #[test]
+    fn local_times_around_the_transitions() {
+        let zone = TimeZone::parse(EUROPE).unwrap();
+        let local =
+            |days: i64, hour: i64, minute: i64| days * SECS_PER_DAY + hour * 3600 + minute * 60;
+        let march_31 = days_from_civil(2024, 3, 31);
+        let october_27 = days_from_civil(2024, 10, 27);
+        assert_eq!(zone.to_utc(local(march_31, 2, 30)), LocalTime::Gap(SPRING));
+        assert_eq!(
+            zone.to_utc(local(march_31, 3, 30)),
+            LocalTime::Single(SPRING + 1800)
+        );
+        assert_eq!(
+            zone.to_utc(local(october_27, 2, 30)),
+            LocalTime::Ambiguous(AUTUMN - 1800, AUTUMN + 1800)
+        );
+        assert_eq!(
+            zone.to_utc(local(october_27, 3, 0)),
+            LocalTime::Single(AUTUMN + 3600)
+        );
+    }
+
+    #[test]
+    fn calendar_round_trips() {
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        // 1970-01-01 was a Thursday
+        assert_eq!(weekday(0), 4);
+        assert_eq!(format(-1), "1969-12-31T23:59:59");
+    }
+}
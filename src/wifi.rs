@@ -0,0 +1,410 @@
+// The WiFi networks the gong knows, entered in the setup portal or over HTTP
+// and kept in NVS. The values compiled into the firmware are only used until
+// some are saved.
+//
+// A gong that moves between places keeps one entry per place. Before
+// connecting it scans and tries the known networks in range, the ones with
+// the highest priority first and among those the strongest.
+
+use std::cmp::Reverse;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Store};
+
+pub const STORE_KEY: &str = "wifi";
+
+// Limits of the 802.11 SSID and the WPA passphrase
+pub const MAX_SSID_LEN: usize = 32;
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 64;
+
+// Known networks kept at most, the whole list is one NVS entry
+pub const MAX_NETWORKS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    // Open without a password, WPA2 otherwise, or whatever the scan found
+    #[default]
+    Auto,
+    Open,
+    Wpa2,
+    Wpa3,
+    // Either of them, for networks in WPA2/WPA3 transition mode
+    Wpa2Wpa3,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiSettings {
+    pub ssid: String,
+    // Empty for open networks
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub auth_method: AuthMethod,
+    // Higher is tried first
+    #[serde(default)]
+    pub priority: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WifiError {
+    SsidLength,
+    PasswordLength,
+    // Open networks have no password, the others need one
+    PasswordForOpen,
+    PasswordMissing,
+    TooManyNetworks,
+    DuplicateSsid(String),
+}
+
+impl fmt::Display for WifiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiError::SsidLength => {
+                write!(f, "the SSID must be 1 to {} bytes long", MAX_SSID_LEN)
+            }
+            WifiError::PasswordLength => write!(
+                f,
+                "the password must be empty or {} to {} characters long",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            ),
+            WifiError::PasswordForOpen => write!(f, "open networks have no password"),
+            WifiError::PasswordMissing => {
+                write!(f, "WPA2 and WPA3 networks need a password")
+            }
+            WifiError::TooManyNetworks => {
+                write!(f, "at most {} networks can be saved", MAX_NETWORKS)
+            }
+            WifiError::DuplicateSsid(ssid) => write!(f, "{} is listed twice", ssid),
+        }
+    }
+}
+
+impl std::error::Error for WifiError {}
+
+impl WifiSettings {
+    pub fn validate(&self) -> Result<(), WifiError> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LEN {
+            return Err(WifiError::SsidLength);
+        }
+        if !self.password.is_empty()
+            && !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&self.password.len())
+        {
+            return Err(WifiError::PasswordLength);
+        }
+        match self.auth_method {
+            AuthMethod::Auto => {}
+            AuthMethod::Open if !self.password.is_empty() => {
+                return Err(WifiError::PasswordForOpen)
+            }
+            AuthMethod::Open => {}
+            _ if self.password.is_empty() => return Err(WifiError::PasswordMissing),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The method to connect with, `seen` is what the scan reported for the
+    // network if it was found
+    pub fn resolve_auth_method(&self, seen: Option<AuthMethod>) -> AuthMethod {
+        match (self.auth_method, seen) {
+            (AuthMethod::Auto, _) if self.password.is_empty() => AuthMethod::Open,
+            (AuthMethod::Auto, Some(AuthMethod::Open) | Some(AuthMethod::Auto) | None) => {
+                AuthMethod::Wpa2
+            }
+            (AuthMethod::Auto, Some(seen)) => seen,
+            (auth_method, _) => auth_method,
+        }
+    }
+}
+
+pub fn validate(networks: &[WifiSettings]) -> Result<(), WifiError> {
+    if networks.len() > MAX_NETWORKS {
+        return Err(WifiError::TooManyNetworks);
+    }
+    for (idx, network) in networks.iter().enumerate() {
+        network.validate()?;
+        if networks[..idx].iter().any(|n| n.ssid == network.ssid) {
+            return Err(WifiError::DuplicateSsid(network.ssid.clone()));
+        }
+    }
+    Ok(())
+}
+
+// Adds a network, replacing a saved one with the same SSID. A new network
+// takes the place of the one with the lowest priority when the list is full.
+pub fn add(networks: &mut Vec<WifiSettings>, network: WifiSettings) {
+    if let Some(existing) = networks.iter_mut().find(|n| n.ssid == network.ssid) {
+        *existing = network;
+        return;
+    }
+    if networks.len() >= MAX_NETWORKS {
+        if let Some(idx) = (0..networks.len()).min_by_key(|&idx| networks[idx].priority) {
+            networks.remove(idx);
+        }
+    }
+    networks.push(network);
+}
+
+// An access point found by a scan
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedNetwork {
+    pub ssid: String,
+    pub signal_strength: i8,
+    pub auth_method: AuthMethod,
+}
+
+// A known network in range, with the method to connect to it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate<'a> {
+    pub network: &'a WifiSettings,
+    pub auth_method: AuthMethod,
+    pub signal_strength: i8,
+}
+
+// The known networks found by a scan in the order to try them: by priority
+// and then by signal strength. Each network is listed once with its
+// strongest access point.
+pub fn candidates<'a>(
+    networks: &'a [WifiSettings],
+    scanned: &[ScannedNetwork],
+) -> Vec<Candidate<'a>> {
+    let mut candidates: Vec<Candidate> = networks
+        .iter()
+        .filter_map(|network| {
+            let strongest = scanned
+                .iter()
+                .filter(|ap| ap.ssid == network.ssid)
+                .max_by_key(|ap| ap.signal_strength)?;
+            Some(Candidate {
+                network,
+                auth_method: network.resolve_auth_method(Some(strongest.auth_method)),
+                signal_strength: strongest.signal_strength,
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|c| (Reverse(c.network.priority), Reverse(c.signal_strength)));
+    candidates
+}
+
+// How the list is saved, older firmware kept a single network
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Saved {
+    Networks(Vec<WifiSettings>),
+    Network(WifiSettings),
+}
+
+// The saved networks that are usable, empty if there are none
+pub fn load(store: &dyn Store) -> Vec<WifiSettings> {
+    let networks = match storage::load(store, STORE_KEY) {
+        Some(Saved::Networks(networks)) => networks,
+        Some(Saved::Network(network)) => vec![network],
+        None => Vec::new(),
+    };
+    networks
+        .into_iter()
+        .filter(|network| match network.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Ignoring saved WiFi network {}: {}", network.ssid, e);
+                false
+            }
+        })
+        .take(MAX_NETWORKS)
+        .collect()
+}
+
+pub fn save(store: &dyn Store, networks: &[WifiSettings]) -> anyhow::Result<()> {
+    storage::save(store, STORE_KEY, &networks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str, priority: u8) -> WifiSettings {
+        WifiSettings {
+            ssid: ssid.into(),
+            password: "password".into(),
+            auth_method: AuthMethod::Auto,
+            priority,
+        }
+    }
+
+    fn scanned(ssid: &str, signal_strength: i8, auth_method: AuthMethod) -> ScannedNetwork {
+        ScannedNetwork {
+            ssid: ssid.into(),
+            signal_strength,
+            auth_method,
+        }
+    }
+
+    fn ssids<'a>(candidates: &[Candidate<'a>]) -> Vec<&'a str> {
+        candidates
+            .iter()
+            .map(|candidate| candidate.network.ssid.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn candidates_by_priority_then_signal() {
+        let networks = [
+            network("home", 1),
+            network("office", 1),
+            network("phone", 5),
+            network("cabin", 0),
+        ];
+        let scan = [
+            scanned("office", -50, AuthMethod::Wpa2),
+            scanned("home", -70, AuthMethod::Wpa2),
+            // The strongest access point of a network counts
+            scanned("home", -40, AuthMethod::Wpa2),
+            scanned("phone", -85, AuthMethod::Wpa2),
+            scanned("neighbour", -30, AuthMethod::Wpa2),
+        ];
+        let candidates = candidates(&networks, &scan);
+        assert_eq!(ssids(&candidates), ["phone", "home", "office"]);
+        assert_eq!(candidates[1].signal_strength, -40);
+    }
+
+    #[test]
+    fn no_candidates_out_of_range() {
+        let networks = [network("home", 1)];
+        assert!(candidates(&networks, &[]).is_empty());
+        assert!(candidates(&networks, &[scanned("Home", -40, AuthMethod::Wpa2)]).is_empty());
+        assert!(candidates(&[], &[scanned("home", -40, AuthMethod::Wpa2)]).is_empty());
+    }
+
+    #[test]
+    fn auto_resolves_from_the_scan() {
+        let open = WifiSettings {
+            password: String::new(),
+            ..network("cafe", 0)
+        };
+        let secured = network("home", 0);
+        assert_eq!(open.resolve_auth_method(None), AuthMethod::Open);
+        assert_eq!(
+            open.resolve_auth_method(Some(AuthMethod::Wpa2)),
+            AuthMethod::Open
+        );
+        assert_eq!(secured.resolve_auth_method(None), AuthMethod::Wpa2);
+        assert_eq!(
+            secured.resolve_auth_method(Some(AuthMethod::Open)),
+            AuthMethod::Wpa2
+        );
+        for seen in [AuthMethod::Wpa2, AuthMethod::Wpa3, AuthMethod::Wpa2Wpa3] {
+            assert_eq!(secured.resolve_auth_method(Some(seen)), seen);
+        }
+        let wpa3 = WifiSettings {
+            auth_method: AuthMethod::Wpa3,
+            ..network("home", 0)
+        };
+        assert_eq!(
+            wpa3.resolve_auth_method(Some(AuthMethod::Wpa2)),
+            AuthMethod::Wpa3
+        );
+
+        let scan = [scanned("home", -60, AuthMethod::Wpa3)];
+        let networks = [secured];
+        assert_eq!(
+            candidates(&networks, &scan)[0].auth_method,
+            AuthMethod::Wpa3
+        );
+    }
+
+    #[test]
+    fn add_replaces_the_same_ssid() {
+        let mut networks = vec![network("home", 1), network("office", 2)];
+        add(
+            &mut networks,
+            WifiSettings {
+                password: "new password".into(),
+                ..network("home", 3)
+            },
+        );
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].password, "new password");
+        assert_eq!(networks[0].priority, 3);
+    }
+
+    #[test]
+    fn full_list_drops_the_lowest_priority() {
+        let mut networks: Vec<WifiSettings> = (0..MAX_NETWORKS)
+            .map(|idx| network(&format!("net{}", idx), 5 - (idx % 3) as u8))
+            .collect();
+        assert_eq!(validate(&networks), Ok(()));
+        add(&mut networks, network("new", 9));
+        assert_eq!(networks.len(), MAX_NETWORKS);
+        // `net2` was the first with priority 3
+        assert!(!networks.iter().any(|network| network.ssid == "net2"));
+        assert_eq!(networks.last().unwrap().ssid, "new");
+
+        networks.push(network("extra", 0));
+        assert_eq!(validate(&networks), Err(WifiError::TooManyNetworks));
+    }
+
+    #[test]
+    fn validates_networks() {
+        assert_eq!(validate(&[network("home", 0)]), Ok(()));
+        assert_eq!(
+            validate(&[network("home", 0), network("home", 1)]),
+            Err(WifiError::DuplicateSsid("home".into()))
+        );
+        assert_eq!(network("", 0).validate(), Err(WifiError::SsidLength));
+        assert_eq!(
+            network(&"x".repeat(MAX_SSID_LEN + 1), 0).validate(),
+            Err(WifiError::SsidLength)
+        );
+        let short = WifiSettings {
+            password: "short".into(),
+            ..network("home", 0)
+        };
+        assert_eq!(short.validate(), Err(WifiError::PasswordLength));
+        let open = WifiSettings {
+            auth_method: AuthMethod::Open,
+            ..network("home", 0)
+        };
+        assert_eq!(open.validate(), Err(WifiError::PasswordForOpen));
+        let wpa2 = WifiSettings {
+            auth_method: AuthMethod::Wpa2,
+            password: String::new(),
+            ..network("home", 0)
+        };
+        assert_eq!(wpa2.validate(), Err(WifiError::PasswordMissing));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn loads_the_single_network_of_older_firmware() {
+        use crate::sim::MemoryStore;
+
+        let store = MemoryStore::new();
+        store
+            .set(STORE_KEY, br#"{"ssid": "home", "password": "password"}"#)
+            .unwrap();
+        assert_eq!(load(&store), [network("home", 0)]);
+
+        save(&store, &[network("home", 0), network("office", 2)]).unwrap();
+        assert_eq!(load(&store), [network("home", 0), network("office", 2)]);
+
+        // Unusable entries are skipped
+        store
+            .set(
+                STORE_KEY,
+                br#"[{"ssid": "", "password": ""}, {"ssid": "office"}]"#,
+            )
+            .unwrap();
+        assert_eq!(
+            load(&store),
+            [WifiSettings {
+                password: String::new(),
+                ..network("office", 0)
+            }]
+        );
+    }
+}